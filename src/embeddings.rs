@@ -1,28 +1,66 @@
 use anyhow::{Error as E, Result};
-use candle_core::{Device, Tensor};
-use candle_nn::VarBuilder;
+use candle_core::{Device, IndexOp, Tensor};
+use candle_nn::{Module, VarBuilder};
 use candle_transformers::models::bert::{BertModel, Config};
-use hf_hub::{api::sync::Api, Repo, RepoType};
-use tokenizers::{PaddingParams, Tokenizer};
+use hf_hub::{api::sync::{Api, ApiRepo}, Repo, RepoType};
+use tokenizers::{Encoding, PaddingParams, Tokenizer, TruncationParams};
 
+/// HuggingFace model used for embeddings. Recorded in the index so an index
+/// built with a different model is detected instead of silently queried.
+pub const MODEL_ID: &str = "sentence-transformers/all-MiniLM-L6-v2";
+
+/// Width of [`MODEL_ID`]'s vectors.
+pub const MODEL_DIM: usize = 384;
+
+/// Turns texts into L2-normalized vectors; one vector per input, in order.
+/// `Searcher` only talks to this trait, so backends other than the bundled
+/// candle model (ONNX, remote APIs) can be plugged in.
+pub trait Embedder: Send + Sync {
+    fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>>;
+
+    /// Recorded in the index metadata; vectors from different models can't be compared.
+    fn model_id(&self) -> &str;
+
+    /// Width of the vectors, which sizes the index's vector column.
+    fn dim(&self) -> Result<usize>;
+
+    /// Texts per `embed_batch` call while indexing.
+    fn batch_size(&self) -> usize {
+        32
+    }
+
+    /// Pays one-time costs (allocations, page-ins of mmapped weights) ahead of
+    /// the first real query. No-op unless the backend has such costs.
+    fn warm_up(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Local BERT sentence embedder ([`MODEL_ID`] by default) running on the CPU with candle.
 pub struct EmbeddingModel {
     model: BertModel,
     tokenizer: Tokenizer,
     device: Device,
+    model_id: String,
+    dim: usize,
 }
 
 impl EmbeddingModel {
     pub fn new() -> Result<Self> {
+        Self::load(MODEL_ID)
+    }
+
+    /// Loads another BERT-architecture sentence-transformers model from the HuggingFace Hub.
+    pub fn load(model_id: &str) -> Result<Self> {
         let device = Device::Cpu; // Use CPU for portability and simplicity
         let api = Api::new()?;
         let repo = api.repo(Repo::new(
-            "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+            model_id.to_string(),
             RepoType::Model,
         ));
 
         let config_filename = repo.get("config.json")?;
         let tokenizer_filename = repo.get("tokenizer.json")?;
-        let weights_filename = repo.get("model.safetensors")?;
 
         let config = std::fs::read_to_string(config_filename)?;
         let config: Config = serde_json::from_str(&config)?;
@@ -34,17 +72,33 @@ impl EmbeddingModel {
         };
         tokenizer.with_padding(Some(pp));
 
-        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights_filename], verify_dtype(&device), &device)? };
+        let vb = load_weights(&repo, &device)?;
         let model = BertModel::load(vb, &config)?;
 
         Ok(Self {
             model,
             tokenizer,
             device,
+            model_id: model_id.to_string(),
+            dim: config.hidden_size,
         })
     }
+}
 
-    pub fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+impl Embedder for EmbeddingModel {
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn dim(&self) -> Result<usize> {
+        Ok(self.dim)
+    }
+
+    fn warm_up(&self) -> Result<()> {
+        self.embed_batch(&["fn warm_up() {}".to_string()]).map(|_| ())
+    }
+
+    fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
         let tokens = self.tokenizer.encode_batch(texts.to_vec(), true).map_err(E::msg)?;
         let token_ids = tokens
             .iter()
@@ -94,6 +148,274 @@ impl EmbeddingModel {
     }
 }
 
+/// Cross-encoder used by `--rerank` unless CODE_SEARCH_RERANK_MODEL names another.
+pub const RERANK_MODEL_ID: &str = "cross-encoder/ms-marco-MiniLM-L-6-v2";
+
+/// Pairs scored per forward pass.
+const RERANK_BATCH: usize = 16;
+
+/// BERT sequence-classification model that scores how well a passage answers a
+/// query by reading both together: slower than comparing embeddings, but sharper.
+pub struct CrossEncoder {
+    model: BertModel,
+    pooler: candle_nn::Linear,
+    classifier: candle_nn::Linear,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl CrossEncoder {
+    /// Loads a BERT cross-encoder (`bert.*` encoder and pooler plus a one-logit
+    /// `classifier`, as published by sentence-transformers) from the HuggingFace Hub.
+    pub fn load(model_id: &str) -> Result<Self> {
+        let device = Device::Cpu;
+        let api = Api::new()?;
+        let repo = api.repo(Repo::new(model_id.to_string(), RepoType::Model));
+
+        let config = std::fs::read_to_string(repo.get("config.json")?)?;
+        let config: Config = serde_json::from_str(&config)?;
+
+        let mut tokenizer = Tokenizer::from_file(repo.get("tokenizer.json")?).map_err(E::msg)?;
+        tokenizer.with_padding(Some(PaddingParams {
+            strategy: tokenizers::PaddingStrategy::BatchLongest,
+            ..Default::default()
+        }));
+        // Query and chunk together must fit the position embeddings
+        tokenizer.with_truncation(Some(TruncationParams {
+            max_length: config.max_position_embeddings,
+            ..Default::default()
+        })).map_err(E::msg)?;
+
+        let vb = load_weights(&repo, &device)?;
+        let model = BertModel::load(vb.clone(), &config)?;
+        let pooler = candle_nn::linear(config.hidden_size, config.hidden_size, vb.pp("bert.pooler.dense"))?;
+        let classifier = candle_nn::linear(config.hidden_size, 1, vb.pp("classifier"))?;
+
+        Ok(Self { model, pooler, classifier, tokenizer, device })
+    }
+
+    /// Relevance of each passage to `query` in (0, 1), in order; higher is better.
+    pub fn score(&self, query: &str, passages: &[&str]) -> Result<Vec<f32>> {
+        let mut scores = Vec::with_capacity(passages.len());
+        for batch in passages.chunks(RERANK_BATCH) {
+            let pairs: Vec<(String, String)> = batch.iter()
+                .map(|passage| (query.to_string(), passage.to_string()))
+                .collect();
+            let encodings = self.tokenizer.encode_batch(pairs, true).map_err(E::msg)?;
+            let stack = |field: fn(&Encoding) -> &[u32]| -> Result<Tensor> {
+                let rows = encodings.iter()
+                    .map(|encoding| Tensor::new(field(encoding), &self.device))
+                    .collect::<candle_core::Result<Vec<_>>>()?;
+                Ok(Tensor::stack(&rows, 0)?)
+            };
+            let token_ids = stack(Encoding::get_ids)?;
+            let token_type_ids = stack(Encoding::get_type_ids)?;
+            let attention_mask = stack(Encoding::get_attention_mask)?;
+
+            // Classification head: tanh pooler over [CLS], then a single logit
+            let hidden = self.model.forward(&token_ids, &token_type_ids, Some(&attention_mask))?;
+            let pooled = self.pooler.forward(&hidden.i((.., 0))?)?.tanh()?;
+            let logits = self.classifier.forward(&pooled)?.squeeze(1)?;
+            scores.extend(candle_nn::ops::sigmoid(&logits)?.to_vec1::<f32>()?);
+        }
+        Ok(scores)
+    }
+}
+
+/// Cross-encoder for `--rerank` (CODE_SEARCH_RERANK_MODEL, or [`RERANK_MODEL_ID`]).
+pub fn configured_rerank_model() -> String {
+    std::env::var("CODE_SEARCH_RERANK_MODEL")
+        .ok()
+        .filter(|m| !m.is_empty())
+        .unwrap_or_else(|| RERANK_MODEL_ID.to_string())
+}
+
+/// Inputs per request unless CODE_SEARCH_EMBEDDINGS_BATCH says otherwise.
+const DEFAULT_API_BATCH: usize = 64;
+
+/// Retries of a rate-limited (429) or failed (5xx, network) request before giving up.
+const API_MAX_RETRIES: u32 = 5;
+
+/// Embeds through an OpenAI-compatible `POST <base>/embeddings` endpoint.
+pub struct ApiEmbedder {
+    agent: ureq::Agent,
+    url: String,
+    model: String,
+    api_key: Option<String>,
+    batch_size: usize,
+    // Learned from the first response; the API doesn't advertise it
+    dim: std::sync::OnceLock<usize>,
+}
+
+#[derive(serde::Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(serde::Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl ApiEmbedder {
+    /// Reads CODE_SEARCH_EMBEDDINGS_URL (base URL, e.g. `https://api.openai.com/v1`),
+    /// CODE_SEARCH_EMBEDDINGS_MODEL, CODE_SEARCH_EMBEDDINGS_API_KEY and
+    /// CODE_SEARCH_EMBEDDINGS_BATCH. None when no URL is set, i.e. the local model should be used.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(base_url) = std::env::var("CODE_SEARCH_EMBEDDINGS_URL").ok().filter(|u| !u.is_empty()) else {
+            return Ok(None);
+        };
+        let model = std::env::var("CODE_SEARCH_EMBEDDINGS_MODEL")
+            .map_err(|_| E::msg("CODE_SEARCH_EMBEDDINGS_MODEL must be set when CODE_SEARCH_EMBEDDINGS_URL is"))?;
+        Ok(Some(Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout(std::time::Duration::from_secs(60))
+                .build(),
+            url: format!("{}/embeddings", base_url.trim_end_matches('/')),
+            model,
+            api_key: std::env::var("CODE_SEARCH_EMBEDDINGS_API_KEY").ok().filter(|k| !k.is_empty()),
+            batch_size: std::env::var("CODE_SEARCH_EMBEDDINGS_BATCH")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_API_BATCH)
+                .max(1),
+            dim: std::sync::OnceLock::new(),
+        }))
+    }
+
+    /// One request, retried with exponential backoff (or the server's Retry-After)
+    /// on rate limits, server errors and network failures.
+    fn request(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let body = serde_json::json!({ "model": self.model, "input": texts });
+        let mut attempt = 0;
+        let response = loop {
+            let mut request = self.agent.post(&self.url);
+            if let Some(key) = &self.api_key {
+                request = request.set("Authorization", &format!("Bearer {}", key));
+            }
+            let (error, retry_after) = match request.send_json(&body) {
+                Ok(response) => break response,
+                Err(ureq::Error::Status(code, response)) if code == 429 || code >= 500 => {
+                    let retry_after = response.header("Retry-After").and_then(|s| s.trim().parse::<u64>().ok());
+                    (format!("Embeddings API returned {}: {}", code, response.into_string().unwrap_or_default().trim()), retry_after)
+                }
+                Err(ureq::Error::Status(code, response)) => {
+                    let body = response.into_string().unwrap_or_default();
+                    return Err(E::msg(format!("Embeddings API returned {}: {}", code, body.trim())));
+                }
+                Err(e) => (format!("Embeddings API request failed: {}", e), None),
+            };
+            if attempt == API_MAX_RETRIES {
+                return Err(E::msg(format!("{} (gave up after {} retries)", error, API_MAX_RETRIES)));
+            }
+            let delay = retry_after.unwrap_or(1 << attempt);
+            eprintln!("{}; retrying in {}s...", error, delay);
+            std::thread::sleep(std::time::Duration::from_secs(delay));
+            attempt += 1;
+        };
+
+        let mut data = response.into_json::<EmbeddingsResponse>()?.data;
+        if data.len() != texts.len() {
+            return Err(E::msg(format!("Embeddings API returned {} vectors for {} inputs", data.len(), texts.len())));
+        }
+        // Results may come back out of order
+        data.sort_by_key(|d| d.index);
+        Ok(data.into_iter().map(|d| d.embedding).collect())
+    }
+}
+
+impl Embedder for ApiEmbedder {
+    fn model_id(&self) -> &str {
+        &self.model
+    }
+
+    fn dim(&self) -> Result<usize> {
+        if let Some(dim) = self.dim.get() {
+            return Ok(*dim);
+        }
+        // Costs one tiny request, once
+        let probe = self.embed_batch(&["dimension probe".to_string()])?;
+        Ok(probe[0].len())
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let mut vectors = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(self.batch_size) {
+            for embedding in self.request(batch)? {
+                let dim = *self.dim.get_or_init(|| embedding.len());
+                if embedding.len() != dim {
+                    return Err(E::msg(format!(
+                        "Embeddings API returned a {}-dimensional vector after {}-dimensional ones",
+                        embedding.len(), dim
+                    )));
+                }
+                // Scores assume unit-length vectors; not every API normalizes
+                let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt().max(1e-9);
+                vectors.push(embedding.iter().map(|x| x / norm).collect());
+            }
+        }
+        Ok(vectors)
+    }
+}
+
+/// Model id the index is built with under the current configuration, without
+/// loading anything (for commands that read an index but never embed).
+pub fn configured_model_id() -> String {
+    match std::env::var("CODE_SEARCH_EMBEDDINGS_URL") {
+        Ok(url) if !url.is_empty() => std::env::var("CODE_SEARCH_EMBEDDINGS_MODEL").unwrap_or_default(),
+        _ => MODEL_ID.to_string(),
+    }
+}
+
+/// Model for prose files (CODE_SEARCH_DOCS_MODEL), if one is configured. Their
+/// chunks are then embedded by it and kept in their own table.
+pub fn configured_docs_model() -> Option<String> {
+    std::env::var("CODE_SEARCH_DOCS_MODEL").ok().filter(|m| !m.is_empty())
+}
+
+/// Memory-maps the repo's `model.safetensors`, fetching it if it isn't cached. A
+/// download that was interrupted leaves a file that doesn't parse and would fail
+/// every later run the same way, so such a file is deleted and downloaded again, once.
+fn load_weights(repo: &ApiRepo, device: &Device) -> Result<VarBuilder<'static>> {
+    let weights = repo.get("model.safetensors")?;
+    let error = match unsafe { VarBuilder::from_mmaped_safetensors(&[&weights], verify_dtype(device), device) } {
+        Ok(vb) => return Ok(vb),
+        Err(e) if is_corrupt_weights(&e) => e,
+        Err(e) => return Err(e.into()),
+    };
+    eprintln!("Model cache corrupted ({}: {}); re-downloading", weights.display(), error);
+    // The snapshot entry links into blobs/; drop both so nothing of the bad copy is reused
+    if let Ok(blob) = std::fs::canonicalize(&weights) {
+        let _ = std::fs::remove_file(blob);
+    }
+    let _ = std::fs::remove_file(&weights);
+    let weights = repo.download("model.safetensors")
+        .map_err(|e| E::msg(format!("Model cache corrupted and re-downloading the weights failed: {}", e)))?;
+    unsafe { VarBuilder::from_mmaped_safetensors(&[&weights], verify_dtype(device), device) }
+        .map_err(|e| E::msg(format!(
+            "Model weights at {} are unreadable even after re-downloading ({}); check free disk space, \
+             or delete the model from the HuggingFace cache (~/.cache/huggingface/hub)",
+            weights.display(), e
+        )))
+}
+
+/// Whether loading weights failed on the file's contents (a truncated or garbled
+/// safetensors file) rather than on something a fresh download wouldn't fix.
+fn is_corrupt_weights(e: &candle_core::Error) -> bool {
+    match e {
+        candle_core::Error::SafeTensor(_) => true,
+        candle_core::Error::WithPath { inner, .. }
+        | candle_core::Error::WithBacktrace { inner, .. }
+        | candle_core::Error::Context { inner, .. } => is_corrupt_weights(inner),
+        _ => false,
+    }
+}
+
 pub fn normalize_l2(v: &Tensor) -> Result<Tensor> {
     let norm = v.sqr()?.sum_keepdim(1)?.sqrt()?;
     Ok(v.broadcast_div(&norm)?)
@@ -103,3 +425,128 @@ fn verify_dtype(_device: &Device) -> candle_core::DType {
     // Default to F32 for CPU
     candle_core::DType::F32
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    /// Serves `responses` (status, extra headers, body) to consecutive requests on a
+    /// local port and records each request's JSON body.
+    fn mock_server(responses: Vec<(u16, &'static str, String)>) -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        std::thread::spawn(move || {
+            for (status, headers, body) in responses {
+                let Ok((stream, _)) = listener.accept() else { return };
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut request = vec![0; length];
+                reader.read_exact(&mut request).unwrap();
+                seen.lock().unwrap().push(serde_json::from_slice(&request).unwrap());
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+                    status, body.len(), headers, body
+                ).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn embedder(url: &str, batch_size: usize) -> ApiEmbedder {
+        ApiEmbedder {
+            agent: ureq::AgentBuilder::new().timeout(std::time::Duration::from_secs(10)).build(),
+            url: format!("{}/embeddings", url),
+            model: "test-model".to_string(),
+            api_key: Some("secret".to_string()),
+            batch_size,
+            dim: std::sync::OnceLock::new(),
+        }
+    }
+
+    /// A response for `inputs` named `t<k>`: `[1, k]` per input, listed in reverse.
+    fn vectors_for(inputs: &[&str], width: usize) -> String {
+        let data: Vec<_> = inputs.iter().enumerate().rev().map(|(index, input)| {
+            let k: f32 = input.trim_start_matches('t').parse().unwrap();
+            let mut embedding = vec![0.0; width];
+            embedding[0] = 1.0;
+            embedding[1] = k;
+            serde_json::json!({ "index": index, "embedding": embedding })
+        }).collect();
+        serde_json::json!({ "data": data }).to_string()
+    }
+
+    fn texts(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn batches_requests_and_keeps_input_order() {
+        let (url, requests) = mock_server(vec![
+            (200, "", vectors_for(&["t1", "t2"], 2)),
+            (200, "", vectors_for(&["t3", "t4"], 2)),
+            (200, "", vectors_for(&["t5"], 2)),
+        ]);
+        let api = embedder(&url, 2);
+        let vectors = api.embed_batch(&texts(&["t1", "t2", "t3", "t4", "t5"])).unwrap();
+
+        let inputs: Vec<usize> = requests.lock().unwrap().iter().map(|r| r["input"].as_array().unwrap().len()).collect();
+        assert_eq!(inputs, [2, 2, 1]);
+        assert_eq!(requests.lock().unwrap()[0]["model"], "test-model");
+        for (k, vector) in vectors.iter().enumerate() {
+            // Unit length, and the second component over the first gives back the input's number
+            assert!((vector.iter().map(|x| x * x).sum::<f32>() - 1.0).abs() < 1e-5);
+            assert!((vector[1] / vector[0] - (k + 1) as f32).abs() < 1e-4);
+        }
+        assert_eq!(api.dim().unwrap(), 2);
+    }
+
+    #[test]
+    fn rejects_a_change_of_dimension() {
+        let (url, _) = mock_server(vec![
+            (200, "", vectors_for(&["t1"], 2)),
+            (200, "", vectors_for(&["t2"], 3)),
+        ]);
+        let error = embedder(&url, 1).embed_batch(&texts(&["t1", "t2"])).unwrap_err();
+        assert_eq!(error.to_string(), "Embeddings API returned a 3-dimensional vector after 2-dimensional ones");
+    }
+
+    #[test]
+    fn client_errors_fail_without_retrying() {
+        let (url, requests) = mock_server(vec![
+            (400, "", r#"{"error": "unknown model"}"#.to_string()),
+            (200, "", vectors_for(&["t1"], 2)),
+        ]);
+        let error = embedder(&url, 8).embed_batch(&texts(&["t1"])).unwrap_err();
+        assert_eq!(error.to_string(), r#"Embeddings API returned 400: {"error": "unknown model"}"#);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn server_errors_are_retried_after_retry_after() {
+        let (url, requests) = mock_server(vec![
+            (503, "Retry-After: 0\r\n", "busy".to_string()),
+            (200, "", vectors_for(&["t1"], 2)),
+        ]);
+        let vectors = embedder(&url, 8).embed_batch(&texts(&["t1"])).unwrap();
+        assert_eq!(vectors.len(), 1);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+}
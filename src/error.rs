@@ -0,0 +1,50 @@
+/// Failure causes callers may want to handle differently (the MCP layer maps
+/// each to its own error code). Raised inside `anyhow::Error`, recover with
+/// `downcast_ref::<SearchError>()`; anything else is an internal error.
+#[derive(Debug)]
+pub enum SearchError {
+    PathNotFound(String),
+    ModelLoad(String),
+    /// The index can't be read or written
+    IndexCorrupt(String),
+    /// The index was built by a different embedding model, or a newer version of the tool
+    IndexIncompatible(String),
+    /// The scan found nothing to index (wrong path, extensions or ignore rules)
+    NoIndexableFiles(String),
+}
+
+impl SearchError {
+    /// Stable machine-readable name of the variant.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SearchError::PathNotFound(_) => "path_not_found",
+            SearchError::ModelLoad(_) => "model_load",
+            SearchError::IndexCorrupt(_) => "index_corrupt",
+            SearchError::IndexIncompatible(_) => "index_incompatible",
+            SearchError::NoIndexableFiles(_) => "no_indexable_files",
+        }
+    }
+
+    pub(crate) fn index(e: anyhow::Error) -> Self {
+        SearchError::IndexCorrupt(format!("{:#}", e))
+    }
+}
+
+impl std::fmt::Display for SearchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SearchError::PathNotFound(path) => write!(f, "Repository path not found: {}", path),
+            SearchError::ModelLoad(reason) => write!(f, "Failed to load embedding model: {}", reason),
+            SearchError::IndexCorrupt(reason) => write!(f, "Index error: {}", reason),
+            SearchError::IndexIncompatible(reason) => write!(f, "{}", reason),
+            SearchError::NoIndexableFiles(path) => write!(
+                f,
+                "No indexable files found in {}. Check the path, that --ext (or CODE_SEARCH_EXTENSIONS) \
+                 covers the repository's languages, and that .gitignore/.codesearchignore don't exclude everything",
+                path
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SearchError {}
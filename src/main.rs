@@ -1,15 +1,23 @@
 mod embeddings;
+mod error;
 
 mod mcp;
 pub mod scanner;
 pub mod search;
 mod store;
 mod text_index;
+mod watch;
 
 
 use clap::{Parser, Subcommand};
 use mcp::run_mcp_server;
-use search::Searcher;
+use search::{SearchOptions, Searcher};
+use mcp::SearchHit;
+use store::SearchResult;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 
 #[derive(Parser)]
@@ -25,34 +33,555 @@ struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
     
-    /// Direct query argument (fallback if no subcommand)
+    /// Direct query argument (fallback if no subcommand); `-` reads it from stdin
     #[arg(index = 1)]
     direct_query: Option<String>,
+
+    /// Read the query from stdin (same as passing `-` as the query)
+    #[arg(long, conflicts_with = "direct_query")]
+    stdin: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Search the codebase
-    Search {
-        /// Search query
-        query: String,
-        
+    Search(SearchArgs),
+
+    /// Search with a precomputed query embedding instead of text. Uses the
+    /// existing index as-is (no model load, no re-indexing)
+    SearchVector {
+        /// File holding the vector: a JSON array, or comma/whitespace-separated numbers
+        vector_file: PathBuf,
+
         /// Repository path
         #[arg(short, long, default_value = ".")]
         path: String,
 
-        /// Max lines per chunk
-        #[arg(long, default_value_t = 60)]
-        max_lines: usize,
+        /// Limit results count
+        #[arg(long)]
+        limit: Option<usize>,
 
-        /// Glob patterns to exclude
+        /// Index location override (see `search --index-dir`)
         #[arg(long)]
-        exclude: Vec<String>,
+        index_dir: Option<PathBuf>,
 
-        /// Limit results count
+        /// Named index override (see `search --table`)
+        #[arg(long, value_parser = parse_table_name)]
+        table: Option<String>,
+
+        /// Only return chunks of these symbol kinds (comma-separated)
+        #[arg(long, value_delimiter = ',', value_parser = clap::builder::PossibleValuesParser::new(scanner::SYMBOL_KINDS))]
+        kind: Vec<String>,
+    },
+
+    /// Dump every indexed chunk as JSON lines (for inspecting chunking or
+    /// feeding external tools). Reads the existing index as-is
+    Export {
+        /// Repository path
+        #[arg(short, long, default_value = ".")]
+        path: String,
+
+        /// File to write (stdout if omitted)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Include each chunk's embedding vector
         #[arg(long)]
-        limit: Option<usize>,
+        with_vectors: bool,
+
+        /// Index location override (see `search --index-dir`)
+        #[arg(long)]
+        index_dir: Option<PathBuf>,
+
+        /// Named index override (see `search --table`)
+        #[arg(long, value_parser = parse_table_name)]
+        table: Option<String>,
+    },
+
+    /// Load chunks written by `export --with-vectors` into the index,
+    /// skipping the model
+    Import {
+        /// JSONL file to read (`-` for stdin)
+        input: PathBuf,
+
+        /// Repository the chunks belong to
+        #[arg(short, long, default_value = ".")]
+        path: String,
+
+        /// Index location override (see `search --index-dir`)
+        #[arg(long)]
+        index_dir: Option<PathBuf>,
+
+        /// Named index override (see `search --table`)
+        #[arg(long, value_parser = parse_table_name)]
+        table: Option<String>,
+
+        /// Stamp chunks with the mtimes of the local files, so the next search
+        /// doesn't re-embed them. Only use it when the checkout matches the one
+        /// the index was exported from
+        #[arg(long)]
+        restamp: bool,
+    },
+
+    /// Index the files of a `.tar` or `.tar.gz` (e.g. `git archive HEAD`)
+    /// without extracting it
+    IndexArchive {
+        /// Archive to read (`-` for stdin)
+        archive: PathBuf,
+
+        /// Repository the files belong to
+        #[arg(short, long, default_value = ".")]
+        path: String,
+
+        /// Index location override (see `search --index-dir`)
+        #[arg(long)]
+        index_dir: Option<PathBuf>,
+
+        /// Named index override (see `search --table`)
+        #[arg(long, value_parser = parse_table_name)]
+        table: Option<String>,
+
+        /// Leading path components to drop from entry names, like `tar --strip-components`
+        #[arg(long, default_value_t = 0)]
+        strip_components: usize,
+
+        /// Max lines per chunk for a new index [default: 60]; an existing
+        /// index keeps the size it was built with
+        #[arg(long)]
+        max_lines: Option<usize>,
+    },
+
+    /// Load the model and index once, then answer queries read from stdin
+    /// (one per line) until EOF
+    Repl {
+        #[command(flatten)]
+        flags: SearchFlags,
+
+        /// Re-scan for changed files before each query
+        #[arg(long)]
+        refresh: bool,
+    },
+
+    /// Build or update the index, then keep it up to date as files change
+    /// (like the MCP server's watcher), so CLI searches find it current
+    Watch {
+        /// Repository path
+        #[arg(short, long, default_value = ".")]
+        path: String,
+
+        /// Index location override (see `search --index-dir`)
+        #[arg(long)]
+        index_dir: Option<PathBuf>,
+
+        /// Named index override (see `search --table`)
+        #[arg(long, value_parser = parse_table_name)]
+        table: Option<String>,
+
+        /// Max lines per chunk for a new index [default: 60]; an existing
+        /// index keeps the size it was built with
+        #[arg(long)]
+        max_lines: Option<usize>,
+    },
+
+    /// Prune old index versions and compact the index's files now, instead of
+    /// waiting for the automatic cleanup after writes
+    Compact {
+        /// Repository path
+        #[arg(short, long, default_value = ".")]
+        path: String,
+
+        /// Index location override (see `search --index-dir`)
+        #[arg(long)]
+        index_dir: Option<PathBuf>,
+
+        /// Named index override (see `search --table`)
+        #[arg(long, value_parser = parse_table_name)]
+        table: Option<String>,
+    },
+
+    /// Delete the repository's index (every table, the keyword index and the
+    /// manifests) to reclaim disk or start over; the repository is not touched
+    Clean {
+        /// Repository path
+        #[arg(short, long, default_value = ".")]
+        path: String,
+
+        /// Index location override (see `search --index-dir`)
+        #[arg(long)]
+        index_dir: Option<PathBuf>,
+
+        /// Delete without asking for confirmation
+        #[arg(short, long)]
+        force: bool,
     },
+
+    /// Print the MCP tool definitions (name, description and JSON schema of the
+    /// parameters) as served by `--mcp`, without starting the server
+    Schema,
+}
+
+#[derive(Parser)]
+struct SearchArgs {
+    /// Search query (`-` reads it from stdin)
+    query: String,
+
+    /// Another query to search for in the same run (repeatable); the results of
+    /// all the queries are merged into one ranked list
+    #[arg(long = "query", value_name = "QUERY")]
+    queries: Vec<String>,
+
+    #[command(flatten)]
+    flags: SearchFlags,
+
+    /// Write results to this file instead of stdout (JSON if it ends in `.json`)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Rank by keyword (BM25) matches only: no model load and no re-indexing,
+    /// the repository must already be indexed
+    #[arg(long, conflicts_with_all = [
+        "cluster", "with_vectors", "no_text_index", "explain_plan",
+        "kind", "since", "rerank", "whole_symbol", "all_branches",
+    ])]
+    text_only: bool,
+}
+
+/// Options shared by `search` and `repl`.
+#[derive(clap::Args)]
+struct SearchFlags {
+    /// Repository path
+    #[arg(short, long, default_value = ".")]
+    path: String,
+
+    /// Max lines per chunk for a new index [default: 60]. An existing index
+    /// keeps the size it was built with; delete it to rechunk
+    #[arg(long)]
+    max_lines: Option<usize>,
+
+    /// Keep only the first N lines of each chunk in a new index's vector store and
+    /// read results back from the files; 0 keeps none (overrides CODE_SEARCH_STORE_CONTENT_LINES)
+    #[arg(long, value_name = "N")]
+    store_content_lines: Option<usize>,
+
+    /// Glob patterns to exclude
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Limit results count
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Group results by file, listing matched line ranges under each file
+    #[arg(long)]
+    group_by_file: bool,
+
+    /// Group results into topics by the cosine similarity of their embeddings and
+    /// print one representative per group; SIM is the similarity needed to join a
+    /// group (default 0.8)
+    #[arg(long, value_name = "SIM", num_args = 0..=1, default_missing_value = "0.8", conflicts_with = "group_by_file")]
+    cluster: Option<f32>,
+
+    /// Include each result's stored embedding in `.json` output files
+    #[arg(long)]
+    with_vectors: bool,
+
+    /// Don't add `.code-search/` to the repository's .gitignore
+    #[arg(long)]
+    no_gitignore_update: bool,
+
+    /// Store the index under this directory instead of `<path>/.code-search`
+    /// (overrides CODE_SEARCH_INDEX_DIR)
+    #[arg(long)]
+    index_dir: Option<PathBuf>,
+
+    /// Use a separately named index in the same location, e.g. per subsystem
+    /// (overrides CODE_SEARCH_TABLE)
+    #[arg(long, value_parser = parse_table_name)]
+    table: Option<String>,
+
+    /// Rescore the final candidates with a cross-encoder model: slower, but a
+    /// sharper top of the list (or set CODE_SEARCH_RERANK=1)
+    #[arg(long)]
+    rerank: bool,
+
+    /// Tag indexed chunks with their git branch and only search the current
+    /// branch's, so results never mix branches (or set CODE_SEARCH_BRANCH_AWARE=1)
+    #[arg(long)]
+    branch_aware: bool,
+
+    /// Also search the chunks of other branches (see --branch-aware)
+    #[arg(long)]
+    all_branches: bool,
+
+    /// Expand keyword matching with common programming synonyms
+    /// (e.g. remove/delete/drop)
+    #[arg(long)]
+    synonyms: bool,
+
+    /// Whether keyword matches need `all` query terms or `any` of them
+    /// (overrides CODE_SEARCH_MATCH; default any)
+    #[arg(long = "match", value_enum, value_name = "MODE")]
+    match_mode: Option<MatchMode>,
+
+    /// Read the query as tantivy query syntax on the keyword side (`field:term`,
+    /// AND/OR/NOT, "phrases", +required, -excluded) instead of plain words
+    /// (or set CODE_SEARCH_QUERY_SYNTAX=1)
+    #[arg(long)]
+    query_syntax: bool,
+
+    /// Vector candidates fetched per result before reranking
+    /// (higher improves recall, costs latency)
+    #[arg(long, default_value_t = 3)]
+    recall_multiplier: usize,
+
+    /// Score added to results containing every query word, scaled by the share
+    /// of words present (scores are roughly 0-1; 0 disables lexical boosting)
+    #[arg(long, default_value_t = 0.1)]
+    keyword_boost: f32,
+
+    /// Rank-fusion constant k: a result at rank r in the vector or keyword list
+    /// scores 1/(k + r); lower values favor each list's top results more strongly
+    /// (default: 60, or CODE_SEARCH_RRF_K)
+    #[arg(long, value_name = "K")]
+    rrf_k: Option<f32>,
+
+    /// Only keep results whose content matches this regex
+    /// (case-sensitive; prefix with `(?i)` to ignore case)
+    #[arg(long)]
+    grep: Option<String>,
+
+    /// When a match is part of a large symbol that was split into several
+    /// chunks, return the whole symbol
+    #[arg(long)]
+    whole_symbol: bool,
+
+    /// Only return chunks of these symbol kinds (comma-separated, e.g. function,struct)
+    #[arg(long, value_delimiter = ',', value_parser = clap::builder::PossibleValuesParser::new(scanner::SYMBOL_KINDS))]
+    kind: Vec<String>,
+
+    /// Only return chunks of files modified within this long, e.g. 24h, 7d, 2w
+    /// (units: s, m, h, d, w)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    since: Option<std::time::Duration>,
+
+    /// Only search chunks of this file (relative to --path, or to the current directory)
+    #[arg(long, value_name = "FILE")]
+    file: Option<String>,
+
+    /// Worker threads for scanning and indexing (default: all cores, or CODE_SEARCH_THREADS)
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// Always run the query, even if an identical one was answered since the
+    /// index last changed
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Don't build or query the keyword (tantivy) index; rank by vector similarity
+    /// only (or set CODE_SEARCH_DISABLE_TEXT=1)
+    #[arg(long)]
+    no_text_index: bool,
+
+    /// Extra file extensions to index, comma-separated (e.g. `zig,vue`); start
+    /// the list with `=` to replace the defaults (overrides CODE_SEARCH_EXTENSIONS)
+    #[arg(long, value_name = "LIST")]
+    ext: Option<String>,
+
+    /// Skip directories with this name anywhere in the tree, on top of the
+    /// defaults (target, node_modules, dist, build, ...); can be repeated
+    #[arg(long, value_name = "NAME")]
+    skip_dir: Vec<String>,
+
+    /// Also index lockfiles and generated files (Cargo.lock, *.min.js, ...),
+    /// which are skipped by default (or set CODE_SEARCH_INDEX_GENERATED=1)
+    #[arg(long)]
+    index_generated: bool,
+
+    /// Show at most N lines of each result; line numbers still cover the whole
+    /// chunk (overrides CODE_SEARCH_SNIPPET_LINES; 0 shows whole chunks)
+    #[arg(long, value_name = "N")]
+    snippet_lines: Option<usize>,
+
+    /// Prepend each file's imports to its chunks before embedding, so the model
+    /// sees where referenced types come from (or set CODE_SEARCH_INCLUDE_IMPORTS=1)
+    #[arg(long)]
+    include_imports: bool,
+
+    /// Prepend a `// file: <path> symbol: <name>` line to each chunk before
+    /// embedding, so paths and names inform the vectors (or set CODE_SEARCH_EMBED_HEADER=1)
+    #[arg(long)]
+    embed_header: bool,
+
+    /// Embed chunks with common indentation, repeated spaces and blank lines removed;
+    /// results still show the original text (or set CODE_SEARCH_NORMALIZE=1)
+    #[arg(long)]
+    normalize: bool,
+
+    /// Print what each search stage produced (files scanned and reindexed, chunks
+    /// embedded, candidates per ranking stage) and how long it took, to stderr
+    #[arg(long)]
+    explain_plan: bool,
+
+    /// Don't prune and compact the index after this run's writes; see the `compact`
+    /// command (or set CODE_SEARCH_NO_CLEANUP=1)
+    #[arg(long)]
+    no_cleanup: bool,
+
+    /// Order of the printed results; relevance still decides which results are
+    /// included. `path` lists by file, then line; `line` by line number
+    #[arg(long, value_enum, default_value = "score", conflicts_with_all = ["group_by_file", "cluster"])]
+    sort: SortOrder,
+
+    /// Show each result's raw vector distance (lower is closer) instead of its score
+    #[arg(long)]
+    show_distance: bool,
+
+    /// `grep` prints one `path:line:1: first line` entry per result, for editor
+    /// quickfix lists
+    #[arg(long, value_enum, default_value = "text", conflicts_with_all = ["group_by_file", "cluster"])]
+    format: OutputFormat,
+}
+
+impl SearchFlags {
+    /// Builds per-call options, validating flags before the (slow) model load.
+    fn options(&self) -> anyhow::Result<SearchOptions> {
+        let grep = self.grep.as_deref()
+            .map(regex::Regex::new)
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("Invalid --grep pattern: {}", e))?;
+
+        if self.cluster.is_some_and(|sim| !(sim > 0.0 && sim <= 1.0)) {
+            anyhow::bail!("--cluster similarity must be in (0, 1]");
+        }
+        if self.rrf_k.is_some_and(|k| k <= 0.0) {
+            anyhow::bail!("--rrf-k must be positive");
+        }
+
+        let limit = resolve_limit(self.limit);
+
+        let defaults = SearchOptions::default();
+        Ok(SearchOptions {
+            max_lines: self.max_lines,
+            store_content_lines: self.store_content_lines.or(defaults.store_content_lines),
+            exclude: self.exclude.clone(),
+            limit,
+            update_gitignore: defaults.update_gitignore && !self.no_gitignore_update,
+            synonyms: self.synonyms,
+            query_syntax: defaults.query_syntax || self.query_syntax,
+            match_all: self.match_mode.map_or(defaults.match_all, |mode| matches!(mode, MatchMode::All)),
+            recall_multiplier: self.recall_multiplier,
+            keyword_boost: self.keyword_boost,
+            rrf_k: self.rrf_k.unwrap_or(defaults.rrf_k),
+            grep,
+            whole_symbol: self.whole_symbol,
+            kinds: self.kind.clone(),
+            since: self.since,
+            file: self.file.clone(),
+            with_vectors: self.with_vectors || self.cluster.is_some(),
+            threads: self.threads.unwrap_or(defaults.threads),
+            cache: !self.no_cache,
+            text_index: defaults.text_index && !self.no_text_index,
+            index_generated: defaults.index_generated || self.index_generated,
+            include_imports: defaults.include_imports || self.include_imports,
+            embed_header: defaults.embed_header || self.embed_header,
+            normalize: defaults.normalize || self.normalize,
+            cleanup: defaults.cleanup && !self.no_cleanup,
+            plan: self.explain_plan.then(Default::default),
+            branch_aware: defaults.branch_aware || self.branch_aware,
+            all_branches: self.all_branches,
+            rerank: defaults.rerank || self.rerank,
+            skip_dirs: defaults.skip_dirs.iter().cloned()
+                .chain(self.skip_dir.iter().map(|dir| dir.trim_matches('/').to_string()))
+                .collect(),
+            extensions: self.ext.as_deref().map(scanner::extension_set).unwrap_or_else(|| defaults.extensions.clone()),
+            ..defaults
+        })
+    }
+
+    /// Truncates results for display per `--snippet-lines`.
+    fn trim_snippets(&self, results: &mut [SearchResult]) {
+        if let Some(max_lines) = self.snippet_lines.or_else(search::snippet_lines).filter(|&n| n > 0) {
+            for result in results {
+                result.truncate_content(max_lines);
+            }
+        }
+    }
+
+    /// Reorders the final results per `--sort`.
+    fn sort_results(&self, results: &mut [SearchResult]) {
+        match self.sort {
+            SortOrder::Score => {}
+            SortOrder::Path => results.sort_by(|a, b| (&a.file_path, a.line_start).cmp(&(&b.file_path, b.line_start))),
+            SortOrder::Line => results.sort_by(|a, b| (a.line_start, &a.file_path).cmp(&(b.line_start, &b.file_path))),
+        }
+    }
+
+    fn layout(&self) -> Layout {
+        match self.cluster {
+            Some(threshold) => Layout::Clusters(threshold),
+            None if self.group_by_file => Layout::ByFile,
+            None if matches!(self.format, OutputFormat::Grep) => Layout::Grep,
+            None => Layout::List,
+        }
+    }
+}
+
+/// Keyword matching for `--match`.
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum MatchMode {
+    All,
+    Any,
+}
+
+/// Order of printed results for `--sort`.
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum SortOrder {
+    Score,
+    Path,
+    Line,
+}
+
+/// Text output style for `--format`.
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum OutputFormat {
+    Text,
+    Grep,
+}
+
+/// How results are printed as text.
+#[derive(Clone, Copy)]
+enum Layout {
+    List,
+    ByFile,
+    /// `path:line:column: snippet`, one line per result
+    Grep,
+    /// One representative per group of results at least this similar
+    Clusters(f32),
+}
+
+/// Asks a yes/no question on stderr and reads the answer from stdin; anything
+/// but `y`/`yes` is a no. Refuses when stdin isn't a terminal, so scripts have to
+/// pass `--force` rather than delete on a stray line of input.
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    use std::io::IsTerminal;
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!("stdin is not a terminal; pass --force to delete without confirmation");
+    }
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Determine limit: CLI Arg > Env Var > Default (10)
+fn resolve_limit(limit: Option<usize>) -> usize {
+    limit.unwrap_or_else(|| {
+        std::env::var("CODE_SEARCH_LIMIT")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(10)
+    })
 }
 
 #[tokio::main]
@@ -62,48 +591,423 @@ async fn main() -> anyhow::Result<()> {
     if cli.mcp {
         // Run MCP Server
         run_mcp_server().await?;
-    } else {
-        // CLI Mode
-        let (query, path, max_lines, exclude, limit) = match cli.command {
-            Some(Commands::Search { query, path, max_lines, exclude, limit }) => (query, path, max_lines, exclude, limit),
-            None => {
-                if let Some(q) = cli.direct_query {
-                    (q, std::env::current_dir()?.to_string_lossy().to_string(), 60, vec![], None)
-                } else {
-                    // Print help if no args
-                    use clap::CommandFactory;
-                    Cli::command().print_help()?;
-                    return Ok(());
+        return Ok(());
+    }
+
+    // CLI Mode
+    let mut args = match cli.command {
+        Some(Commands::Search(args)) => args,
+        Some(Commands::Repl { flags, refresh }) => return run_repl(flags, refresh).await,
+        Some(Commands::SearchVector { vector_file, path, limit, index_dir, table, kind }) => {
+            let vector = parse_vector(&std::fs::read_to_string(&vector_file)?)
+                .map_err(|e| anyhow::anyhow!("Invalid vector in {}: {}", vector_file.display(), e))?;
+            let results = search::search_by_vector(&path, index_dir, table, &vector, resolve_limit(limit), &kind).await?;
+            print_results(&results, Layout::List, false)?;
+            return Ok(());
+        }
+        Some(Commands::Export { path, output, with_vectors, index_dir, table }) => {
+            let count = match &output {
+                Some(output) => {
+                    if let Some(parent) = output.parent().filter(|p| !p.as_os_str().is_empty()) {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    let mut file = std::io::BufWriter::new(std::fs::File::create(output)?);
+                    search::export_index(&path, index_dir, table, with_vectors, &mut file).await?
+                }
+                None => {
+                    let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+                    search::export_index(&path, index_dir, table, with_vectors, &mut stdout).await?
                 }
+            };
+            eprintln!("Exported {} chunks", count);
+            return Ok(());
+        }
+        Some(Commands::Import { input, path, index_dir, table, restamp }) => {
+            let count = if input.as_os_str() == "-" {
+                search::import_index(&path, index_dir, table, std::io::stdin().lock(), restamp).await?
+            } else {
+                let file = std::fs::File::open(&input)
+                    .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", input.display(), e))?;
+                search::import_index(&path, index_dir, table, std::io::BufReader::new(file), restamp).await?
+            };
+            eprintln!("Imported {} chunks", count);
+            return Ok(());
+        }
+        Some(Commands::Watch { path, index_dir, table, max_lines }) => {
+            let options = SearchOptions { max_lines, ..SearchOptions::default() };
+            search::init_thread_pool(options.threads)?;
+            eprintln!("Initializing searcher (loading model)...");
+            let searcher = Searcher::new()?.with_index_dir(index_dir).with_table(table);
+            searcher.update_index(&path, &options).await?;
+            eprintln!("Watching {} for changes (Ctrl-C to stop)...", path);
+            let searcher = Arc::new(tokio::sync::Mutex::new(Some(searcher)));
+            watch::run(searcher, PathBuf::from(&path), options).await?;
+            return Ok(());
+        }
+        Some(Commands::Compact { path, index_dir, table }) => {
+            search::compact_index(&path, index_dir, table).await?;
+            return Ok(());
+        }
+        Some(Commands::Clean { path, index_dir, force }) => {
+            let index_path = search::index_location(&path, index_dir)?;
+            if !index_path.exists() {
+                eprintln!("No index at {}", index_path.display());
+                return Ok(());
             }
-        };
+            if !force && !confirm(&format!("Delete the index at {}?", index_path.display()))? {
+                eprintln!("Aborted");
+                return Ok(());
+            }
+            search::remove_index(&path, &index_path)?;
+            eprintln!("Removed {}", index_path.display());
+            return Ok(());
+        }
+        Some(Commands::Schema) => {
+            println!("{}", serde_json::to_string_pretty(&mcp::tool_definitions())?);
+            return Ok(());
+        }
+        Some(Commands::IndexArchive { archive, path, index_dir, table, strip_components, max_lines }) => {
+            let options = SearchOptions { max_lines, ..SearchOptions::default() };
+            let searcher = Searcher::new()?.with_index_dir(index_dir).with_table(table);
+            let count = if archive.as_os_str() == "-" {
+                searcher.index_archive(&path, std::io::stdin().lock(), strip_components, &options).await?
+            } else {
+                let file = std::fs::File::open(&archive)
+                    .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", archive.display(), e))?;
+                searcher.index_archive(&path, file, strip_components, &options).await?
+            };
+            eprintln!("Indexed {} chunks", count);
+            return Ok(());
+        }
+        None => {
+            if let Some(q) = cli.direct_query.or(cli.stdin.then(|| "-".to_string())) {
+                // Same defaults as the `search` subcommand
+                let mut args = SearchArgs::parse_from(["search", q.as_str()]);
+                args.flags.path = std::env::current_dir()?.to_string_lossy().to_string();
+                args
+            } else {
+                // Print help if no args
+                use clap::CommandFactory;
+                Cli::command().print_help()?;
+                return Ok(());
+            }
+        }
+    };
+    if args.query == "-" {
+        args.query = read_query_from_stdin()?;
+    }
 
-        // Determine limit: CLI Arg > Env Var > Default (10)
-        let limit = limit.unwrap_or_else(|| {
-            std::env::var("CODE_SEARCH_LIMIT")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(10)
-        });
+    let SearchArgs { query, queries, flags, output, text_only } = args;
+    let mut options = flags.options()?;
+    let label = std::iter::once(&query).chain(&queries)
+        .map(|query| format!("'{}'", query))
+        .collect::<Vec<_>>()
+        .join(", ");
+    options.extra_queries = queries;
+    let mut results = if text_only {
+        eprintln!("Searching the text index for {} in '{}' (limit: {})...", label, flags.path, options.limit);
+        search::search_text(&flags.path, flags.index_dir.clone(), flags.table.clone(), &query, &options)?
+    } else {
+        search::init_thread_pool(options.threads)?;
 
         eprintln!("Initializing searcher (loading model)...");
-        let searcher = Searcher::new()?;
-        
-        eprintln!("Searching for '{}' in '{}' (limit: {})...", query, path, limit);
-        let results = searcher.search(&path, &query, max_lines, exclude, limit).await?;
-        
-        if results.is_empty() {
-            println!("No results found.");
-        } else {
+        let searcher = Searcher::new()?.with_index_dir(flags.index_dir.clone()).with_table(flags.table.clone());
+
+        eprintln!("Searching for {} in '{}' (limit: {})...", label, flags.path, options.limit);
+        options.interrupt = Some(install_interrupt_handler());
+        let results = searcher.search(&flags.path, &query, &options).await?;
+        print_plan(&options);
+        results
+    };
+    flags.trim_snippets(&mut results);
+    flags.sort_results(&mut results);
+    match &output {
+        Some(output) => {
+            write_output_file(output, results, flags.layout(), flags.show_distance)?;
+            eprintln!("Results written to {}", output.display());
+        }
+        None => print_results(&results, flags.layout(), flags.show_distance)?,
+    }
+
+    Ok(())
+}
+
+/// Pays for the model load and index scan once, then runs each stdin line as a query.
+async fn run_repl(flags: SearchFlags, refresh: bool) -> anyhow::Result<()> {
+    use std::io::BufRead;
+
+    let mut options = flags.options()?;
+    search::init_thread_pool(options.threads)?;
+
+    eprintln!("Initializing searcher (loading model)...");
+    let searcher = Searcher::new()?.with_index_dir(flags.index_dir.clone()).with_table(flags.table.clone());
+    searcher.warm_up()?;
+
+    options.interrupt = Some(install_interrupt_handler());
+    searcher.update_index(&flags.path, &options).await?;
+    eprintln!("Ready. Enter a query per line (Ctrl-D to exit).");
+
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        eprint!("> ");
+        std::io::stderr().flush()?;
+        let Some(line) = lines.next() else { break };
+        let query = line?;
+        let query = query.trim();
+        if query.is_empty() {
+            continue;
+        }
+
+        if let Some(plan) = &options.plan {
+            *plan.lock().unwrap_or_else(|e| e.into_inner()) = Default::default();
+        }
+        // A Ctrl-C during an earlier query was handled then; this one starts afresh
+        if let Some(interrupt) = &options.interrupt {
+            interrupt.store(false, Ordering::SeqCst);
+        }
+        if refresh {
+            searcher.update_index(&flags.path, &options).await?;
+        }
+        // A failed query shouldn't end the session
+        match searcher.query_index(&flags.path, query, &options).await {
+            Ok(mut results) => {
+                print_plan(&options);
+                flags.trim_snippets(&mut results);
+                flags.sort_results(&mut results);
+                print_results(&results, flags.layout(), flags.show_distance)?
+            }
+            Err(e) => eprintln!("Search failed: {:#}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Prints the plan recorded under `--explain-plan`.
+fn print_plan(options: &SearchOptions) {
+    if let Some(plan) = &options.plan {
+        eprintln!("{}", plan.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+}
+
+/// First Ctrl-C lets the current indexing batch commit so the next run resumes
+/// from it; a second one while the flag is still set exits immediately. Clearing
+/// the flag (the REPL does before each query) re-arms the first.
+fn install_interrupt_handler() -> Arc<AtomicBool> {
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let interrupt_flag = interrupt.clone();
+    tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if interrupt_flag.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+            eprintln!("Interrupted: finishing the current batch (Ctrl-C again to abort)...");
+        }
+    });
+    interrupt
+}
+
+fn print_results(results: &[SearchResult], layout: Layout, show_distance: bool) -> std::io::Result<()> {
+    write_results(&mut std::io::stdout().lock(), results, layout, show_distance)
+}
+
+/// `score: 0.83`, or `distance: 0.34` under `--show-distance` for results that have one.
+fn score_label(result: &SearchResult, show_distance: bool) -> String {
+    match result.distance {
+        Some(distance) if show_distance => format!("distance: {:.2}", distance),
+        _ => format!("score: {:.2}", result.score),
+    }
+}
+
+fn write_results(out: &mut dyn Write, results: &[SearchResult], layout: Layout, show_distance: bool) -> std::io::Result<()> {
+    if results.is_empty() {
+        // An empty quickfix list is the grep-style answer
+        if !matches!(layout, Layout::Grep) {
+            writeln!(out, "No results found.")?;
+        }
+        return Ok(());
+    }
+    match layout {
+        Layout::Grep => write_grep_lines(out, results)?,
+        Layout::ByFile => write_grouped_by_file(out, results, show_distance)?,
+        Layout::Clusters(threshold) => write_clusters(out, results, threshold, show_distance)?,
+        Layout::List => {
             for (i, result) in results.iter().enumerate() {
-                println!("\n{}. {}:{}:{} (score: {:.2})", 
-                    i + 1, result.file_path, result.line_start, result.line_end, result.score);
-                println!("--------------------------------------------------");
-                println!("{}", result.content);
-                println!("--------------------------------------------------");
+                writeln!(out, "\n{}. {}:{}:{} ({}){}", 
+                    i + 1, result.file_path, result.line_start, result.line_end, score_label(result, show_distance),
+                    result.symbol_label())?;
+                writeln!(out, "--------------------------------------------------")?;
+                writeln!(out, "{}", result.content)?;
+                writeln!(out, "--------------------------------------------------")?;
             }
         }
     }
+    Ok(())
+}
 
+/// Writes results to `path`: a JSON array (same fields as the MCP response)
+/// for `.json` files, the usual text format otherwise.
+fn write_output_file(path: &Path, results: Vec<SearchResult>, layout: Layout, show_distance: bool) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    if path.extension().is_some_and(|ext| ext == "json") {
+        let hits: Vec<SearchHit> = results.into_iter().map(SearchHit::from).collect();
+        serde_json::to_writer_pretty(&mut file, &hits)?;
+        writeln!(file)?;
+    } else {
+        write_results(&mut file, &results, layout, show_distance)?;
+    }
+    file.flush()?;
     Ok(())
 }
+
+/// Parses a JSON array of numbers, or numbers separated by commas/whitespace (CSV).
+fn parse_vector(text: &str) -> anyhow::Result<Vec<f32>> {
+    let text = text.trim();
+    let vector: Vec<f32> = if text.starts_with('[') {
+        serde_json::from_str(text)?
+    } else {
+        text.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<f32>())
+            .collect::<Result<_, _>>()?
+    };
+    if vector.is_empty() {
+        anyhow::bail!("no values");
+    }
+    Ok(vector)
+}
+
+/// clap parser for `--table`.
+fn parse_table_name(text: &str) -> anyhow::Result<String> {
+    search::check_table_name(text)?;
+    Ok(text.to_string())
+}
+
+/// Parses a duration like `90s`, `30m`, `24h`, `7d` or `2w`.
+fn parse_duration(text: &str) -> anyhow::Result<std::time::Duration> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (amount, unit) = text.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| anyhow::anyhow!("expected a number followed by s, m, h, d or w"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => anyhow::bail!("unknown unit '{}' (expected s, m, h, d or w)", unit),
+    };
+    Ok(std::time::Duration::from_secs(amount.saturating_mul(seconds)))
+}
+
+/// Reads the whole of stdin as one query, joining lines with spaces.
+fn read_query_from_stdin() -> anyhow::Result<String> {
+    use std::io::Read;
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input)?;
+    let query = input.split_whitespace().collect::<Vec<_>>().join(" ");
+    if query.is_empty() {
+        anyhow::bail!("No query received on stdin");
+    }
+    Ok(query)
+}
+
+/// Writes each result as `path:line:1: text`, pointing at its first non-blank line.
+/// Notebook cells are left out (`SearchResult::lines_are_cells`).
+fn write_grep_lines(out: &mut dyn Write, results: &[SearchResult]) -> std::io::Result<()> {
+    // A cell number is no line to jump to
+    for result in results.iter().filter(|result| !result.lines_are_cells()) {
+        let (offset, line) = result.content.lines()
+            .enumerate()
+            .find(|(_, line)| !line.trim().is_empty())
+            .unwrap_or((0, ""));
+        writeln!(out, "{}:{}:1: {}", result.file_path, result.line_start + offset, line.trim())?;
+    }
+    Ok(())
+}
+
+/// Prints the best result of each similarity cluster in full, followed by the
+/// locations of the results it stands for.
+fn write_clusters(out: &mut dyn Write, results: &[SearchResult], threshold: f32, show_distance: bool) -> std::io::Result<()> {
+    for (i, cluster) in search::cluster_results(results, threshold).into_iter().enumerate() {
+        let rep = &results[cluster[0]];
+        let similar = match cluster.len() - 1 {
+            0 => String::new(),
+            n => format!(" [+{} similar]", n),
+        };
+        writeln!(out, "\n{}. {}:{}:{} ({}){}{}",
+            i + 1, rep.file_path, rep.line_start, rep.line_end, score_label(rep, show_distance),
+            rep.symbol_label(), similar)?;
+        writeln!(out, "--------------------------------------------------")?;
+        writeln!(out, "{}", rep.content)?;
+        writeln!(out, "--------------------------------------------------")?;
+        for &member in &cluster[1..] {
+            let hit = &results[member];
+            writeln!(out, "   also {}:{}:{} ({}){}",
+                hit.file_path, hit.line_start, hit.line_end, score_label(hit, show_distance), hit.symbol_label())?;
+        }
+    }
+    Ok(())
+}
+
+/// Lists each file once, ordered by its best score, with the matched
+/// line ranges beneath it in file order.
+fn write_grouped_by_file(out: &mut dyn Write, results: &[SearchResult], show_distance: bool) -> std::io::Result<()> {
+    let mut groups: Vec<(&str, Vec<&SearchResult>)> = Vec::new();
+    for result in results {
+        match groups.iter_mut().find(|(path, _)| *path == result.file_path) {
+            Some((_, hits)) => hits.push(result),
+            None => groups.push((&result.file_path, vec![result])),
+        }
+    }
+
+    let best_score = |hits: &[&SearchResult]| hits.iter().map(|r| r.score).fold(f32::MIN, f32::max);
+    groups.sort_by(|a, b| best_score(&b.1).partial_cmp(&best_score(&a.1)).unwrap_or(std::cmp::Ordering::Equal));
+
+    for (i, (path, mut hits)) in groups.into_iter().enumerate() {
+        writeln!(out, "\n{}. {} (best score: {:.2})", i + 1, path, best_score(&hits))?;
+        hits.sort_by_key(|r| r.line_start);
+        for hit in hits {
+            writeln!(out, "   {}-{} ({})", hit.line_start, hit.line_end, score_label(hit, show_distance))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_only_rejects_options_it_would_ignore() {
+        for flag in [&["--kind", "function"][..], &["--since", "2d"], &["--rerank"], &["--whole-symbol"], &["--all-branches"]] {
+            let args = ["code-search", "search", "retry", "--text-only"].iter().chain(flag);
+            assert!(Cli::try_parse_from(args).is_err(), "{:?}", flag);
+        }
+        assert!(Cli::try_parse_from(["code-search", "search", "retry", "--text-only", "--grep", "budget"]).is_ok());
+    }
+
+    #[test]
+    fn grep_lines_skip_notebook_cells() {
+        let hit = |file_path: &str, kind: Option<&str>| SearchResult {
+            file_path: file_path.to_string(),
+            chunk_index: 0,
+            content: "\n  let x = parse();".to_string(),
+            line_start: 3,
+            line_end: 4,
+            symbol_start: 3,
+            symbol_end: 4,
+            symbol_name: None,
+            symbol_kind: kind.map(str::to_string),
+            score: 1.0,
+            distance: None,
+            embedding: None,
+        };
+        let mut out = Vec::new();
+        write_grep_lines(&mut out, &[hit("src/lib.rs", Some("function")), hit("analysis.ipynb", Some("cell"))]).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "src/lib.rs:4:1: let x = parse();\n");
+    }
+}
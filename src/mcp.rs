@@ -1,6 +1,6 @@
 use anyhow::{Context, Result};
 use rmcp::{
-    model::{CallToolResult, Content, ListToolsResult, ErrorData, ErrorCode, CallToolRequestParam, PaginatedRequestParam},
+    model::{CallToolResult, Content, ListToolsResult, ErrorData, ErrorCode, CallToolRequestParam, PaginatedRequestParam, Tool},
     service::{ServiceExt, RequestContext, RoleServer},
     tool, tool_router,
     handler::server::{
@@ -12,16 +12,136 @@ use rmcp::{
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use crate::search::Searcher;
+use crate::scanner::SYMBOL_KINDS;
+use crate::error::SearchError;
+use crate::search::{SearchOptions, Searcher};
+use crate::store::SearchResult;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use notify::{Watcher, RecursiveMode, EventKind};
-use std::path::Path;
+use std::path::PathBuf;
+
+/// Upper bound on the ranked list that paging walks through.
+/// `total` in paged responses is counted against this cap.
+const MAX_PAGED_RESULTS: usize = 100;
+
+/// Default and largest page of `list_files`.
+const DEFAULT_LISTED_FILES: usize = 200;
+const MAX_LISTED_FILES: usize = 1000;
+
+/// Score floor for `search` results when neither `min_score` nor
+/// CODE_SEARCH_MCP_MIN_SCORE is given. Higher than the ranker's own 0.01 cutoff,
+/// since every marginal chunk costs the agent context.
+const DEFAULT_MIN_SCORE: f32 = 0.2;
+
+/// Accepted range for `max_lines`; the chunker never goes below 10 lines.
+const MAX_LINES_RANGE: std::ops::RangeInclusive<usize> = 10..=500;
+
+/// Rejects a tool argument before any work is done.
+fn invalid_params(message: String) -> ErrorData {
+    ErrorData {
+        code: ErrorCode(-32602), // Invalid params
+        message: message.into(),
+        data: None
+    }
+}
+
+/// Maps a searcher failure to an MCP error. Known causes get their own code and a
+/// `kind` in `data` so clients can react (e.g. rebuild the index) without parsing messages.
+fn search_error_data(context: &str, e: anyhow::Error) -> ErrorData {
+    let (code, data) = match e.downcast_ref::<SearchError>() {
+        Some(err @ SearchError::PathNotFound(path)) => (-32002, serde_json::json!({ "kind": err.kind(), "path": path })),
+        Some(err @ SearchError::ModelLoad(_)) => (-32003, serde_json::json!({ "kind": err.kind() })),
+        Some(err @ SearchError::IndexCorrupt(_)) => (-32004, serde_json::json!({ "kind": err.kind() })),
+        Some(err @ SearchError::IndexIncompatible(_)) => (-32005, serde_json::json!({ "kind": err.kind() })),
+        Some(err @ SearchError::NoIndexableFiles(path)) => (-32006, serde_json::json!({ "kind": err.kind(), "path": path })),
+        None => (-32000, serde_json::json!({ "kind": "internal" })),
+    };
+    ErrorData {
+        code: ErrorCode(code),
+        message: format!("{}: {:#}", context, e).into(),
+        data: Some(data),
+    }
+}
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 pub struct SearchArgs {
     pub query: String,
     pub repository_path: Option<String>,
+    /// Number of results to skip (for paging), below 100. Defaults to 0.
+    pub offset: Option<usize>,
+    /// Page size, 1 to 100. Defaults to CODE_SEARCH_LIMIT or 10.
+    pub limit: Option<usize>,
+    /// Maximum lines per chunk, 10 to 500, used when the repository's index is first built.
+    /// An existing index keeps the size it was built with. Defaults to 60.
+    pub max_lines: Option<usize>,
+    /// Only return chunks of these symbol kinds: function, method, class, struct,
+    /// enum, trait, interface, type, module, macro, jsx, cell.
+    pub kinds: Option<Vec<String>>,
+    /// Only search chunks of this file, relative to repository_path (e.g. src/main.rs).
+    pub file_path: Option<String>,
+    /// Include each result's stored embedding vector in the JSON results. Defaults to false.
+    pub include_embeddings: Option<bool>,
+    /// Show at most this many lines of each result's content; line_start/line_end still
+    /// cover the whole chunk. Defaults to CODE_SEARCH_SNIPPET_LINES, or whole chunks.
+    pub snippet_lines: Option<usize>,
+    /// Rescore the candidates with a cross-encoder for a more precise top of the list,
+    /// at the cost of latency (the model is downloaded on first use). Defaults to CODE_SEARCH_RERANK.
+    pub rerank: Option<bool>,
+    /// Glob patterns to leave out of the scan, like the CLI's --exclude (e.g. "tests/**",
+    /// "*.generated.ts"). Excluded files are also dropped from the index until a search
+    /// without them runs.
+    pub exclude: Option<Vec<String>>,
+    /// Drop results scoring below this, 0 to 1 (scores are roughly vector similarity plus a
+    /// keyword bonus, or the cross-encoder's relevance with rerank). Raise it for fewer,
+    /// surer matches; 0 keeps everything. Defaults to CODE_SEARCH_MCP_MIN_SCORE or 0.2.
+    pub min_score: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct IndexStatusArgs {
+    pub repository_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct ListFilesArgs {
+    pub repository_path: Option<String>,
+    /// Only list files matching this glob, relative to repository_path: `src/**/*.rs`
+    /// is anchored at the root, a pattern without a slash like `*.py` matches anywhere.
+    pub glob: Option<String>,
+    /// Number of files to skip (for paging). Defaults to 0.
+    pub offset: Option<usize>,
+    /// Page size, 1 to 1000. Defaults to 200.
+    pub limit: Option<usize>,
+}
+
+/// Machine-readable form of a search result, returned next to the text block
+/// so clients don't have to parse the human-readable output.
+#[derive(Serialize, Debug)]
+pub struct SearchHit {
+    pub file_path: String,
+    pub line_start: usize,
+    pub line_end: usize,
+    pub score: f32,
+    pub content: String,
+    pub symbol_name: Option<String>,
+    pub symbol_kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<Vec<f32>>,
+}
+
+impl From<SearchResult> for SearchHit {
+    fn from(r: SearchResult) -> Self {
+        Self {
+            file_path: r.file_path,
+            line_start: r.line_start,
+            line_end: r.line_end,
+            score: r.score,
+            content: r.content,
+            symbol_name: r.symbol_name,
+            symbol_kind: r.symbol_kind,
+            embedding: r.embedding,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -39,61 +159,251 @@ impl McpServer {
         }
     }
 
-    #[tool(name = "search", description = "Perform a semantic code search. Returns a list of relevant code chunks with their file path, line numbers, and similarity score.")]
+    #[tool(name = "search", description = "Perform a semantic code search. Returns a list of relevant code chunks with their file path, line numbers, and similarity score. `exclude` takes glob patterns (e.g. \"tests/**\") for files to leave out; results scoring below `min_score` (0 to 1, default 0.2) are dropped.")]
     async fn search(&self, args: Parameters<SearchArgs>) -> Result<CallToolResult, ErrorData> {
         let query = &args.0.query;
         let path = args.0.repository_path.as_deref().unwrap_or(".");
         
         eprintln!("Searching for '{}' in '{}'...", query, path);
 
+        // Validate before the (slow) first-time model load
+        let kinds: Vec<String> = args.0.kinds.iter()
+            .flatten()
+            .map(|k| k.to_lowercase())
+            .collect();
+        if let Some(unknown) = kinds.iter().find(|k| !SYMBOL_KINDS.contains(&k.as_str())) {
+            return Err(invalid_params(format!("Unknown symbol kind '{}' (expected one of: {})", unknown, SYMBOL_KINDS.join(", "))));
+        }
+        if let Some(limit) = args.0.limit.filter(|l| !(1..=MAX_PAGED_RESULTS).contains(l)) {
+            return Err(invalid_params(format!("limit must be between 1 and {} (got {})", MAX_PAGED_RESULTS, limit)));
+        }
+        if let Some(offset) = args.0.offset.filter(|&o| o >= MAX_PAGED_RESULTS) {
+            return Err(invalid_params(format!("offset must be below {} (got {})", MAX_PAGED_RESULTS, offset)));
+        }
+        if let Some(min_score) = args.0.min_score.filter(|m| !(0.0..=1.0).contains(m)) {
+            return Err(invalid_params(format!("min_score must be between 0 and 1 (got {})", min_score)));
+        }
+        if let Some(max_lines) = args.0.max_lines.filter(|m| !MAX_LINES_RANGE.contains(m)) {
+            return Err(invalid_params(format!(
+                "max_lines must be between {} and {} (got {})",
+                MAX_LINES_RANGE.start(), MAX_LINES_RANGE.end(), max_lines
+            )));
+        }
+
         let mut searcher_guard = self.searcher.lock().await;
         
         if searcher_guard.is_none() {
              eprintln!("Initializing searcher (loading model)...");
-            let searcher = Searcher::new().map_err(|e| {
-                ErrorData {
-                    code: ErrorCode(-32000),
-                    message: format!("Failed to initialize searcher: {}", e).into(),
-                    data: None
-                }
-            })?;
+            let searcher = Searcher::new()
+                .map_err(|e| search_error_data("Failed to initialize searcher", e))?;
             *searcher_guard = Some(searcher);
         }
         
         let searcher = searcher_guard.as_mut().unwrap();
 
-        let limit = std::env::var("CODE_SEARCH_LIMIT")
+        let limit = args.0.limit.unwrap_or_else(|| {
+            std::env::var("CODE_SEARCH_LIMIT")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(10)
+        });
+        let offset = args.0.offset.unwrap_or(0);
+        let min_score = args.0.min_score.unwrap_or_else(|| {
+            std::env::var("CODE_SEARCH_MCP_MIN_SCORE")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_MIN_SCORE)
+        });
+
+        let timeout_secs = std::env::var("CODE_SEARCH_TIMEOUT_SECS")
             .ok()
             .and_then(|s| s.parse().ok())
-            .unwrap_or(10);
-
-        let results = searcher.search(path, query, 60, vec![], limit).await.map_err(|e| {
-             ErrorData {
-                code: ErrorCode(-32000),
-                message: format!("Search failed: {}", e).into(),
-                data: None
-             }
-        })?;
+            .unwrap_or(60);
+
+        // Rank the same capped list every time so pages are stable across calls
+        // Note: embedding runs synchronously, so the timeout is observed at the next await point
+        let options = SearchOptions {
+            limit: MAX_PAGED_RESULTS,
+            max_lines: args.0.max_lines,
+            kinds,
+            file: args.0.file_path.clone(),
+            exclude: args.0.exclude.clone().unwrap_or_default(),
+            with_vectors: args.0.include_embeddings.unwrap_or(false),
+            rerank: args.0.rerank.unwrap_or_else(|| SearchOptions::default().rerank),
+            ..SearchOptions::default()
+        };
+        let search = searcher.search(path, query, &options);
+        let results = tokio::time::timeout(std::time::Duration::from_secs(timeout_secs), search)
+            .await
+            .map_err(|_| {
+                ErrorData {
+                    code: ErrorCode(-32001), // Request timeout
+                    message: format!(
+                        "Search timed out after {}s (indexing may still be in progress; retry, or raise CODE_SEARCH_TIMEOUT_SECS)",
+                        timeout_secs
+                    ).into(),
+                    data: Some(serde_json::json!({ "kind": "timeout", "timeout_secs": timeout_secs }))
+                }
+            })?
+            .map_err(|e| search_error_data("Search failed", e))?;
+
+        // After reranking, so the floor applies to the scores the agent sees
+        let ranked = results.len();
+        let results: Vec<_> = results.into_iter().filter(|r| r.score >= min_score).collect();
+        let total = results.len();
+        let mut page: Vec<_> = results.into_iter().skip(offset).take(limit).collect();
+        if let Some(max_lines) = args.0.snippet_lines.or_else(crate::search::snippet_lines).filter(|&n| n > 0) {
+            for result in &mut page {
+                result.truncate_content(max_lines);
+            }
+        }
+        let has_more = offset + page.len() < total;
+        let next_offset = if has_more { Some(offset + page.len()) } else { None };
 
         let mut text_output = String::new();
-        if results.is_empty() {
-            text_output.push_str("No results found.");
+        if page.is_empty() {
+            if total == 0 && ranked > 0 {
+                text_output.push_str(&format!(
+                    "No results scored at least {:.2} ({} below; pass a lower min_score to see them).",
+                    min_score, ranked
+                ));
+            } else if total == 0 {
+                text_output.push_str("No results found.");
+            } else {
+                text_output.push_str(&format!("No results at offset {} (total: {}).", offset, total));
+            }
         } else {
-            for result in results {
+            text_output.push_str(&format!(
+                "Showing results {}-{} of {}\n\n",
+                offset + 1, offset + page.len(), total
+            ));
+            for result in &page {
                  text_output.push_str(&format!(
-                    "{}:{}:{} (score: {:.2})\n",
-                    result.file_path, result.line_start, result.line_end, result.score
+                    "{}:{}:{} (score: {:.2}){}\n",
+                    result.file_path, result.line_start, result.line_end, result.score,
+                    result.symbol_label()
                 ));
                 text_output.push_str("--------------------------------------------------\n");
                 text_output.push_str(&result.content);
                  text_output.push_str("\n--------------------------------------------------\n\n");
             }
+            if let Some(next) = next_offset {
+                text_output.push_str(&format!("More results available (use offset: {}).\n", next));
+            }
+        }
+
+        let hits: Vec<SearchHit> = page.into_iter().map(SearchHit::from).collect();
+        let structured = serde_json::json!({
+            "results": hits,
+            "total": total,
+            "offset": offset,
+            "has_more": has_more,
+            "next_offset": next_offset,
+            "min_score": min_score,
+        });
+
+        // Text block first for backward compatibility, then the same data as JSON
+        let mut result = CallToolResult::success(vec![
+            Content::text(text_output),
+            Content::json(&structured)?,
+        ]);
+        result.structured_content = Some(structured);
+        Ok(result)
+    }
+
+    #[tool(name = "index_status", description = "Report the state of a repository's search index without updating it: number of chunks, number of indexed files, and the newest stored file modification time (Unix seconds) as a proxy for freshness. Cheap; does not load the embedding model.")]
+    async fn index_status(&self, args: Parameters<IndexStatusArgs>) -> Result<CallToolResult, ErrorData> {
+        let path = args.0.repository_path.as_deref().unwrap_or(".");
+        let stats = crate::search::index_stats(path, None, None)
+            .await
+            .map_err(|e| search_error_data("Failed to read index status", e))?;
+
+        let text_output = match stats.newest_mtime {
+            None => format!("No index for '{}' yet; the next search builds it.", path),
+            Some(mtime) => format!(
+                "Index for '{}': {} chunks from {} files; newest indexed file modified {}.",
+                path,
+                stats.chunks,
+                stats.files,
+                chrono::DateTime::from_timestamp(mtime as i64, 0)
+                    .map_or_else(|| mtime.to_string(), |t| t.to_rfc3339()),
+            ),
+        };
+        let structured = serde_json::to_value(&stats).map_err(|e| ErrorData {
+            code: ErrorCode(-32000),
+            message: format!("Failed to serialize index status: {}", e).into(),
+            data: None,
+        })?;
+
+        let mut result = CallToolResult::success(vec![
+            Content::text(text_output),
+            Content::json(&structured)?,
+        ]);
+        result.structured_content = Some(structured);
+        Ok(result)
+    }
+
+    #[tool(name = "list_files", description = "List the files in a repository's search index, sorted by path, optionally filtered by a glob (e.g. \"src/**/*.rs\"). Paged with offset/limit. Reads the index as it is; does not re-index or load the embedding model.")]
+    async fn list_files(&self, args: Parameters<ListFilesArgs>) -> Result<CallToolResult, ErrorData> {
+        let path = args.0.repository_path.as_deref().unwrap_or(".");
+        let offset = args.0.offset.unwrap_or(0);
+        let limit = args.0.limit.unwrap_or(DEFAULT_LISTED_FILES);
+        if !(1..=MAX_LISTED_FILES).contains(&limit) {
+            return Err(invalid_params(format!("limit must be between 1 and {} (got {})", MAX_LISTED_FILES, limit)));
         }
+        let glob = args.0.glob.as_deref();
+        if let Some(Err(e)) = glob.map(crate::store::file_glob) {
+            return Err(invalid_params(format!("{:#}", e)));
+        }
+
+        let files = crate::search::list_indexed_files(path, None, None, glob)
+            .await
+            .map_err(|e| search_error_data("Failed to list indexed files", e))?;
+        let total = files.len();
+        let page: Vec<String> = files.into_iter().skip(offset).take(limit).collect();
+        let has_more = offset + page.len() < total;
+        let next_offset = if has_more { Some(offset + page.len()) } else { None };
+
+        let text_output = if page.is_empty() {
+            if total == 0 {
+                format!("No indexed files in '{}'{}.", path, glob.map_or(String::new(), |g| format!(" matching '{}'", g)))
+            } else {
+                format!("No files at offset {} (total: {}).", offset, total)
+            }
+        } else {
+            let mut text = format!("Showing files {}-{} of {}\n\n", offset + 1, offset + page.len(), total);
+            for file in &page {
+                text.push_str(file);
+                text.push('\n');
+            }
+            if let Some(next) = next_offset {
+                text.push_str(&format!("\nMore files available (use offset: {}).\n", next));
+            }
+            text
+        };
 
-        Ok(CallToolResult::success(vec![Content::text(text_output)]))
+        let structured = serde_json::json!({
+            "files": page,
+            "total": total,
+            "offset": offset,
+            "has_more": has_more,
+            "next_offset": next_offset,
+        });
+        let mut result = CallToolResult::success(vec![
+            Content::text(text_output),
+            Content::json(&structured)?,
+        ]);
+        result.structured_content = Some(structured);
+        Ok(result)
     }
 }
 
+/// Every tool the server offers, with the `schemars`-generated schema of its
+/// arguments. Also printed by the `schema` command.
+pub fn tool_definitions() -> Vec<Tool> {
+    McpServer::tool_router().list_all()
+}
+
 impl ServerHandler for McpServer {
     async fn list_tools(
         &self,
@@ -113,6 +423,13 @@ impl ServerHandler for McpServer {
         request: CallToolRequestParam,
         _ctx: RequestContext<RoleServer>,
     ) -> Result<CallToolResult, ErrorData> {
+        self.dispatch(request).await
+    }
+}
+
+impl McpServer {
+    /// Runs the tool `call_tool` names. Every tool in `tool_definitions` needs a branch here.
+    async fn dispatch(&self, request: CallToolRequestParam) -> Result<CallToolResult, ErrorData> {
         // Manual dispatch since ToolRouter delegation is proving difficult with private fields/traits
         if request.name == "search" {
              let args: SearchArgs = if let Some(args_map) = request.arguments {
@@ -134,6 +451,30 @@ impl ServerHandler for McpServer {
              return self.search(Parameters(args)).await;
         }
 
+        if request.name == "index_status" {
+            let args_map = request.arguments.unwrap_or_default();
+            let args: IndexStatusArgs = serde_json::from_value(serde_json::Value::Object(args_map)).map_err(|e| {
+                ErrorData {
+                    code: ErrorCode(-32602), // Invalid params
+                    message: format!("Invalid arguments: {}", e).into(),
+                    data: None
+                }
+            })?;
+            return self.index_status(Parameters(args)).await;
+        }
+
+        if request.name == "list_files" {
+            let args_map = request.arguments.unwrap_or_default();
+            let args: ListFilesArgs = serde_json::from_value(serde_json::Value::Object(args_map)).map_err(|e| {
+                ErrorData {
+                    code: ErrorCode(-32602), // Invalid params
+                    message: format!("Invalid arguments: {}", e).into(),
+                    data: None
+                }
+            })?;
+            return self.list_files(Parameters(args)).await;
+        }
+
         Err(ErrorData {
             code: ErrorCode(-32601), // Method not found
             message: format!("Tool not found: {}", request.name).into(),
@@ -143,84 +484,33 @@ impl ServerHandler for McpServer {
 }
 
 pub async fn run_mcp_server() -> Result<()> {
+    crate::search::init_thread_pool(SearchOptions::default().threads)?;
     let server = McpServer::new();
-    
-    // Start Background Watcher
-    let searcher_clone = server.searcher.clone();
-    
-    // Channel for file events
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-    
-    std::thread::spawn(move || {
-        let (wt_tx, wt_rx) = std::sync::mpsc::channel();
-        let watcher = notify::recommended_watcher(wt_tx);
-        
-        match watcher {
-            Ok(mut w) => {
-                if let Err(e) = w.watch(Path::new("."), RecursiveMode::Recursive) {
-                    eprintln!("Failed to start watcher: {}", e);
-                    return;
-                }
-                
-                // Keep watcher alive
-                for res in wt_rx {
-                    match res {
-                        Ok(event) => {
-                            if let Err(_) = tx.send(event) {
-                                break;
-                            }
-                        },
-                        Err(e) => eprintln!("Watch error: {:?}", e),
+
+    // Load and warm up the model while the client is still connecting, so the first
+    // search is as fast as later ones. On failure the first search retries and reports it
+    let searcher_init = server.searcher.clone();
+    tokio::spawn(async move {
+        let mut searcher_guard = searcher_init.lock().await;
+        if searcher_guard.is_none() {
+            eprintln!("Initializing searcher (loading model)...");
+            match Searcher::new() {
+                Ok(searcher) => {
+                    if let Err(e) = searcher.warm_up() {
+                        eprintln!("Model warm-up failed: {:#}", e);
                     }
+                    *searcher_guard = Some(searcher);
                 }
-            },
-            Err(e) => eprintln!("Failed to create watcher: {}", e),
+                Err(e) => eprintln!("Failed to init searcher: {:#}", e),
+            }
         }
     });
-
-    // Processor loop
+    
+    // Start Background Watcher
+    let watcher_searcher = server.searcher.clone();
     tokio::spawn(async move {
-        // Simple debouncing map: Path -> Instant
-        // Actually for now just process.
-        // Parallel or Serial? Serial is safer for DB.
-        
-        while let Some(event) = rx.recv().await {
-            match event.kind {
-                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {
-                    for path in event.paths {
-                        // Filter ignore dirs partially (simple check)
-                        if path.components().any(|c| c.as_os_str() == "target" || c.as_os_str() == ".git" || c.as_os_str() == "node_modules") {
-                            continue;
-                        }
-                        
-                        // We need to initialize searcher if not exists? 
-                        // If user never searched, maybe we shouldn't index?
-                        // But "Watch Mode" implies active indexing.
-                        // Let's check lock.
-                        let mut searcher_guard = searcher_clone.lock().await;
-                        
-                        // Valid searcher needed.
-                        if searcher_guard.is_none() {
-                             // Initialize default?
-                             // Or skip. If I skip, I miss updates before first search.
-                             // Better to initialize.
-                             eprintln!("Initializing searcher for watch mode...");
-                             match Searcher::new() {
-                                 Ok(s) => *searcher_guard = Some(s),
-                                 Err(e) => {
-                                     eprintln!("Failed to init searcher: {}", e);
-                                     continue;
-                                 }
-                             }
-                        }
-                        
-                        if let Some(searcher) = searcher_guard.as_ref() {
-                            let _ = searcher.index_file(&path, ".", 60).await;
-                        }
-                    }
-                },
-                _ => {}
-            }
+        if let Err(e) = crate::watch::run(watcher_searcher, PathBuf::from("."), SearchOptions::default()).await {
+            eprintln!("Watcher stopped: {:#}", e);
         }
     });
     
@@ -229,3 +519,51 @@ pub async fn run_mcp_server() -> Result<()> {
     
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &'static str, arguments: serde_json::Value) -> Result<CallToolResult, ErrorData> {
+        let request = CallToolRequestParam {
+            name: name.into(),
+            arguments: arguments.as_object().cloned(),
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(McpServer::new().dispatch(request))
+    }
+
+    #[test]
+    fn list_files_is_dispatched() {
+        let repo = std::env::temp_dir().join(format!("code-search-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&repo).unwrap();
+        let path = repo.to_string_lossy().to_string();
+
+        let result = call("list_files", serde_json::json!({ "repository_path": path, "glob": "src/**/*.rs" })).unwrap();
+        assert_eq!(result.structured_content.unwrap()["total"], 0);
+
+        let error = call("list_files", serde_json::json!({ "repository_path": path, "glob": "src/[" })).unwrap_err();
+        assert_eq!(error.code, ErrorCode(-32602));
+        let error = call("list_files", serde_json::json!({ "repository_path": path, "limit": 0 })).unwrap_err();
+        assert_eq!(error.code, ErrorCode(-32602));
+        let error = call("list_files", serde_json::json!({ "limit": "all" })).unwrap_err();
+        assert_eq!(error.code, ErrorCode(-32602));
+
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn search_offsets_stay_within_the_ranked_results() {
+        // Rejected before the model loads
+        let error = call("search", serde_json::json!({ "query": "parse", "offset": MAX_PAGED_RESULTS })).unwrap_err();
+        assert_eq!(error.code, ErrorCode(-32602));
+        let error = call("search", serde_json::json!({ "query": "parse", "offset": usize::MAX })).unwrap_err();
+        assert_eq!(error.code, ErrorCode(-32602));
+    }
+
+    #[test]
+    fn unknown_tools_are_not_found() {
+        let error = call("list_symbols", serde_json::json!({})).unwrap_err();
+        assert_eq!(error.code, ErrorCode(-32601));
+    }
+}
@@ -1,9 +1,12 @@
 use anyhow::Result;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use ignore::WalkBuilder;
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::fs;
 use std::path::Path;
 use std::time::SystemTime;
-use tree_sitter::{Parser, Query, QueryCursor};
+use tree_sitter::{Node, Parser, Query, QueryCursor};
 
 use crossbeam_channel::Sender;
 
@@ -15,6 +18,57 @@ pub struct FileChunk {
     pub line_start: usize,
     pub line_end: usize,
     pub mtime: u64,
+    /// Comment/docstring text attached to this chunk (empty if none).
+    /// Indexed separately in the text index so natural-language queries can match it.
+    pub doc_text: String,
+    /// Line range of the whole tree-sitter node this chunk came from.
+    /// Same as `line_start`/`line_end` unless an oversized node was split.
+    pub symbol_start: usize,
+    pub symbol_end: usize,
+    /// Identifier of the tree-sitter node (e.g. `parse_config`), if recoverable
+    pub symbol_name: Option<String>,
+    /// Kind of the tree-sitter node (`function`, `struct`, ...); None for non-AST chunks
+    pub symbol_kind: Option<String>,
+    /// `content_hash` of the whole file (0 if unknown); lets a renamed file keep its vectors
+    pub content_hash: u64,
+    /// The file's top-level import/use statements (empty for non-AST chunks). Not stored;
+    /// prepended to the content when embedding if `SearchOptions::include_imports` is set
+    pub imports: String,
+    /// First line of the symbol an oversized node was split from, on every piece but
+    /// the first (empty otherwise). Not stored; prepended to the content when embedding
+    pub signature: String,
+}
+
+impl FileChunk {
+    /// The text the embedding model sees for this chunk: the content, preceded by the
+    /// symbol's signature for split pieces and optionally by a `// file: ... symbol: ...`
+    /// header line and the file's imports.
+    pub fn embedding_text(&self, include_imports: bool, include_header: bool) -> Cow<'_, str> {
+        let imports = include_imports && !self.imports.is_empty();
+        if !imports && !include_header && self.signature.is_empty() {
+            return Cow::Borrowed(&self.content);
+        }
+        let mut text = String::new();
+        if include_header {
+            text.push_str("// file: ");
+            text.push_str(&self.file_path);
+            if let Some(name) = &self.symbol_name {
+                text.push_str(" symbol: ");
+                text.push_str(name);
+            }
+            text.push('\n');
+        }
+        if imports {
+            text.push_str(&self.imports);
+            text.push('\n');
+        }
+        if !self.signature.is_empty() {
+            text.push_str(&self.signature);
+            text.push('\n');
+        }
+        text.push_str(&self.content);
+        Cow::Owned(text)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -23,9 +77,54 @@ pub struct FileEntry {
     pub mtime: u64,
 }
 
-pub fn scan_repository(root_path: &str, tx: Sender<FileEntry>, exclude: Vec<String>) {
+/// Which files get indexed, shared by the repository scan and the MCP watcher.
+#[derive(Debug, Clone)]
+pub struct FileFilter {
+    /// File extensions to index (without the dot)
+    pub extensions: HashSet<String>,
+    /// Also index lockfiles and generated files (`is_generated`)
+    pub index_generated: bool,
+    /// Directory names skipped anywhere in the tree (`skip_dir_set`)
+    pub skip_dirs: HashSet<String>,
+}
+
+impl FileFilter {
+    /// Whether any component of `path` below `root` is a skipped directory. Only the
+    /// part inside the repository counts: a checkout under e.g. `~/build/` is still indexed.
+    pub fn in_skipped_dir(&self, path: &Path, root: &Path) -> bool {
+        let path = path.strip_prefix(root).unwrap_or(path);
+        path.components().any(|c| c.as_os_str().to_str().is_some_and(|name| self.skip_dirs.contains(name)))
+    }
+
+    /// Files without a grammar or structure chunker for their extension fall back
+    /// to the heuristic chunker.
+    /// Extensionless files are indexed as the language their shebang names.
+    pub fn should_process_file(&self, path: &Path) -> bool {
+        self.accepts(path, || read_first_line(path))
+    }
+
+    /// `should_process_file` for a file that isn't on disk (an archive entry),
+    /// given its first line.
+    pub fn should_process_entry(&self, path: &Path, first_line: &str) -> bool {
+        self.accepts(path, || Some(first_line.to_string()))
+    }
+
+    fn accepts(&self, path: &Path, first_line: impl FnOnce() -> Option<String>) -> bool {
+        let ext = match path.extension() {
+            Some(ext) => ext.to_str().unwrap_or(""),
+            None => match first_line().as_deref().and_then(shebang_extension) {
+                Some(ext) => ext,
+                None => return false,
+            },
+        };
+        self.extensions.contains(ext) && (self.index_generated || !is_generated(path))
+    }
+}
+
+pub fn scan_repository(root_path: &str, tx: Sender<FileEntry>, exclude: Vec<String>, update_gitignore: bool, threads: usize, filter: FileFilter) {
     let mut builder = WalkBuilder::new(root_path);
     builder
+        .threads(threads) // 0 lets ignore pick based on the core count
         .hidden(false)
         .git_ignore(true)
         .add_custom_ignore_filename(".codesearchignore");
@@ -42,24 +141,29 @@ pub fn scan_repository(root_path: &str, tx: Sender<FileEntry>, exclude: Vec<Stri
         }
     }
 
-    // Ensure .code-search/ is in .gitignore
-    ensure_gitignore(root_path);
+    // Ensure .code-search/ is in .gitignore (unless the user opted out)
+    if update_gitignore {
+        ensure_gitignore(root_path);
+    }
 
+    let include = load_include_matcher(root_path);
     let root_path_owned = root_path.to_string();
 
     builder.build_parallel().run(|| {
         let tx = tx.clone();
         let root = root_path_owned.clone();
+        let include = include.clone();
+        let filter = filter.clone();
         Box::new(move |result| {
             if let Ok(entry) = result {
                 let path = entry.path();
-                // Explicitly filter common noise directories
-                if path.components().any(|c| c.as_os_str() == "target" || c.as_os_str() == ".git" || c.as_os_str() == "node_modules") {
+                // Explicitly filter build output and other noise directories
+                if filter.in_skipped_dir(path, Path::new(&root)) {
                     return ignore::WalkState::Continue;
                 }
 
                 if entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
-                    if should_process_file(path) {
+                    if filter.should_process_file(path) {
                         // Calculate mtime for change detection
                         let mtime = match fs::metadata(path) {
                             Ok(metadata) => metadata.modified()
@@ -75,6 +179,12 @@ pub fn scan_repository(root_path: &str, tx: Sender<FileEntry>, exclude: Vec<Stri
                             .to_string_lossy()
                             .to_string();
 
+                        if let Some(include) = &include {
+                            if !include.matched_path_or_any_parents(&relative_path, false).is_ignore() {
+                                return ignore::WalkState::Continue;
+                            }
+                        }
+
                         let _ = tx.send(FileEntry {
                             path: relative_path,
                             mtime,
@@ -87,14 +197,314 @@ pub fn scan_repository(root_path: &str, tx: Sender<FileEntry>, exclude: Vec<Stri
     });
 }
 
-const VALID_EXTENSIONS: &[&str] = &[
-    "rs", "py", "js", "ts", "jsx", "tsx", "go", "java", "cpp", "c", "h", "hpp", "php", "rb", "cs", 
-    "md", "txt", "json", "yml", "yaml", "toml"
+/// Loads the `.codesearchinclude` allowlist (gitignore syntax) from the repo root.
+/// A "match" here means the file is wanted. Kept separate from the walker overrides
+/// because override whitelists take precedence over `.gitignore` and would pull
+/// ignored files back in; this only ever narrows the scan.
+fn load_include_matcher(root_path: &str) -> Option<Gitignore> {
+    let include_path = Path::new(root_path).join(".codesearchinclude");
+    if !include_path.is_file() {
+        return None;
+    }
+
+    let mut builder = GitignoreBuilder::new(root_path);
+    if let Some(e) = builder.add(&include_path) {
+        eprintln!("Warning: failed to read {:?}: {}", include_path, e);
+    }
+    match builder.build() {
+        // An empty allowlist would exclude everything; treat it as absent
+        Ok(matcher) if !matcher.is_empty() => Some(matcher),
+        Ok(_) => None,
+        Err(e) => {
+            eprintln!("Warning: invalid .codesearchinclude, ignoring it: {}", e);
+            None
+        }
+    }
+}
+
+/// Indexed unless `--ext` / CODE_SEARCH_EXTENSIONS says otherwise.
+const DEFAULT_EXTENSIONS: &[&str] = &[
+    "rs", "py", "js", "ts", "jsx", "tsx", "go", "java", "cpp", "c", "h", "hpp", "php", "rb", "cs", "sh", "bash", "zsh",
+    "md", "txt", "json", "yml", "yaml", "toml", "ipynb", "sql", "vue", "svelte"
+];
+
+/// Natural-language files, embedded by the docs model when one is configured.
+const PROSE_EXTENSIONS: &[&str] = &["md", "markdown", "mdx", "rst", "txt", "adoc"];
+
+/// Directories that hold build output, dependencies or VCS data.
+const DEFAULT_SKIP_DIRS: &[&str] = &[
+    "target", ".git", "node_modules", "dist", "build", "vendor", ".venv", "venv", "__pycache__", ".next",
 ];
 
-pub fn should_process_file(path: &Path) -> bool {
-    let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("");
-    VALID_EXTENSIONS.contains(&ext)
+/// Lockfiles and other machine-written files with an indexed extension;
+/// skipped unless `index_generated` is set.
+const GENERATED_FILE_NAMES: &[&str] = &[
+    "Cargo.lock", "package-lock.json", "npm-shrinkwrap.json", "yarn.lock", "pnpm-lock.yaml",
+    "composer.lock", "Gemfile.lock", "Pipfile.lock", "poetry.lock", "flake.lock", "go.sum",
+];
+const GENERATED_SUFFIXES: &[&str] = &[
+    ".min.js", ".min.css", ".bundle.js", ".pb.go", "_pb2.py", ".g.dart", ".designer.cs",
+];
+
+/// Lines per chunk for a new index when none is requested.
+pub const DEFAULT_MAX_LINES: usize = 60;
+
+/// Runs of base64-like characters (and whitespace-free lines) at least this long
+/// are replaced with a placeholder before chunking.
+const DEFAULT_BLOB_MIN_LEN: usize = 200;
+
+/// True when the env var is set to anything but "", "0" or "false".
+pub fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|v| !matches!(v.trim(), "" | "0" | "false"))
+        .unwrap_or(false)
+}
+
+/// Override with CODE_SEARCH_BLOB_MIN_LEN; 0 indexes content unchanged.
+fn blob_min_len() -> usize {
+    std::env::var("CODE_SEARCH_BLOB_MIN_LEN")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_BLOB_MIN_LEN)
+}
+
+/// Blank lines allowed between a comment and the code (or comment) it's merged with.
+/// 0 merges only comments directly above the code, as chunking always has.
+const DEFAULT_COMMENT_GAP: usize = 0;
+
+/// Override with CODE_SEARCH_COMMENT_GAP, e.g. 1 to also merge a comment block
+/// separated from its code by a blank line.
+fn comment_gap() -> usize {
+    std::env::var("CODE_SEARCH_COMMENT_GAP")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_COMMENT_GAP)
+}
+
+/// Whether a node starting on `start_row` follows one ending on `prev_end_row`
+/// with at most `gap` blank lines in between.
+fn within_gap(prev_end_row: usize, start_row: usize, gap: usize) -> bool {
+    start_row <= prev_end_row + 1 + gap
+}
+
+/// Replaces embedded data (base64, data URI payloads, minified one-token lines)
+/// with `<elided N chars>`. Works line by line so line numbers stay valid.
+fn elide_blobs(content: &str, min_len: usize) -> Cow<'_, str> {
+    if min_len == 0 {
+        return Cow::Borrowed(content);
+    }
+
+    let mut out = String::with_capacity(content.len());
+    let mut changed = false;
+    for line in content.split_inclusive('\n') {
+        let token = line.trim();
+        // Count characters, not bytes: a line of CJK text is a third as long as its UTF-8
+        let token_chars = token.chars().count();
+        if token_chars >= min_len && !token.contains(char::is_whitespace) {
+            // The whole line is one giant token; keep its indentation and line ending
+            let indent_len = line.len() - line.trim_start().len();
+            out.push_str(&line[..indent_len]);
+            out.push_str(&format!("<elided {} chars>", token_chars));
+            out.push_str(&line[indent_len + token.len()..]);
+            changed = true;
+        } else {
+            changed |= elide_runs(line, min_len, &mut out);
+        }
+    }
+
+    if changed { Cow::Owned(out) } else { Cow::Borrowed(content) }
+}
+
+/// Copies `line` to `out`, eliding base64-alphabet runs of at least `min_len`.
+fn elide_runs(line: &str, min_len: usize, out: &mut String) -> bool {
+    let is_blob_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '=' | '-' | '_');
+    let mut changed = false;
+    let mut rest = line;
+    while let Some(start) = rest.find(is_blob_char) {
+        // Blob characters are ASCII, so byte length == character count
+        let run_len = rest[start..].find(|c: char| !is_blob_char(c)).unwrap_or(rest.len() - start);
+        out.push_str(&rest[..start]);
+        let run = &rest[start..start + run_len];
+        if run_len >= min_len {
+            out.push_str(&format!("<elided {} chars>", run_len));
+            changed = true;
+        } else {
+            out.push_str(run);
+        }
+        rest = &rest[start + run_len..];
+    }
+    out.push_str(rest);
+    changed
+}
+
+/// Which chunk survives when one captured node lies inside another (e.g. a method in a class).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NestedChunks {
+    /// Index the containing symbol whole; nested symbols are not repeated.
+    Outermost,
+    /// Replace a class (module, interface, ...) by its members. Functions still keep
+    /// nested closures inline, so a callback never displaces the function around it.
+    Innermost,
+}
+
+/// Class-based languages index members rather than whole classes; Rust traits and modules
+/// read better whole. CODE_SEARCH_NESTED_CHUNKS overrides this with comma-separated entries,
+/// either a bare policy for every language or `ext=policy` (e.g. `outermost,java=innermost`).
+fn nested_chunks(ext: &str) -> NestedChunks {
+    let mut policy = match ext {
+        "rs" | "go" => NestedChunks::Outermost,
+        _ => NestedChunks::Innermost,
+    };
+    let configured = std::env::var("CODE_SEARCH_NESTED_CHUNKS").unwrap_or_default();
+    for entry in configured.split(',').map(str::trim) {
+        let (lang, value) = match entry.split_once('=') {
+            Some((lang, value)) => (Some(lang.trim()), value.trim()),
+            None => (None, entry),
+        };
+        if lang.is_some_and(|lang| lang != ext) {
+            continue;
+        }
+        match value {
+            "outermost" => policy = NestedChunks::Outermost,
+            "innermost" => policy = NestedChunks::Innermost,
+            _ => {}
+        }
+    }
+    policy
+}
+
+/// Symbol kinds that group other symbols and give way to them under `Innermost`.
+fn is_container(kind: Option<&str>) -> bool {
+    matches!(kind, Some("class" | "struct" | "enum" | "trait" | "interface" | "type" | "module"))
+}
+
+/// Which `(start_byte, end_byte, is_container)` ranges give way to the ranges inside them:
+/// containers with a nested chunk, under `Innermost`. Sorted as for `nested_chunk_mask`.
+fn split_containers(ranges: &[(usize, usize, bool)], policy: NestedChunks) -> Vec<bool> {
+    ranges.iter().enumerate().map(|(i, &(_, end, container))| {
+        policy == NestedChunks::Innermost && container && ranges[i + 1..].iter()
+            .take_while(|&&(start, _, _)| start < end)
+            .any(|&(_, inner_end, _)| inner_end <= end)
+    }).collect()
+}
+
+/// Marks which `(start_byte, end_byte, is_container)` ranges to keep so that none lies inside another.
+/// `ranges` must be sorted by start, longer first, so a range's children follow it directly.
+fn nested_chunk_mask(ranges: &[(usize, usize, bool)], policy: NestedChunks) -> Vec<bool> {
+    let split = split_containers(ranges, policy);
+
+    // Whatever is left keeps its outermost level
+    let mut outer_end = 0;
+    ranges.iter().zip(split).map(|(&(_, end, _), split)| {
+        if split {
+            return false;
+        }
+        let keep = end > outer_end;
+        outer_end = outer_end.max(end);
+        keep
+    }).collect()
+}
+
+/// The rows of each split container that no kept chunk covers, as `(index, first_row,
+/// last_row)` runs over inclusive `(start_row, end_row)` ranges sorted like `nested_chunk_mask`'s.
+/// A row inside a nested container belongs to the inner one; containers inside a kept chunk have none.
+fn container_remainders(rows: &[(usize, usize)], keep: &[bool], split: &[bool]) -> Vec<(usize, usize, usize)> {
+    let Some(last_row) = rows.iter().map(|&(_, end)| end).max() else {
+        return Vec::new();
+    };
+    let mut owner = vec![None; last_row + 1];
+    // Outer containers come first, so inner ones overwrite their rows
+    for (i, &(start, end)) in rows.iter().enumerate().filter(|&(i, _)| split[i]) {
+        owner[start..=end].fill(Some(i));
+    }
+    for (&(start, end), _) in rows.iter().zip(keep).filter(|&(_, &kept)| kept) {
+        owner[start..=end].fill(None);
+    }
+
+    let mut runs = Vec::new();
+    let mut row = 0;
+    for run in owner.chunk_by(|a, b| a == b) {
+        if let Some(i) = run[0] {
+            runs.push((i, row, row + run.len() - 1));
+        }
+        row += run.len();
+    }
+    runs
+}
+
+/// Applies an extension list to the defaults: `zig,vue` adds to them, a leading `=`
+/// (`=rs,toml`) replaces them. Dots and case are ignored.
+pub fn extension_set(spec: &str) -> HashSet<String> {
+    let (replace, list) = match spec.trim().strip_prefix('=') {
+        Some(list) => (true, list),
+        None => (false, spec),
+    };
+    let defaults = DEFAULT_EXTENSIONS.iter().filter(|_| !replace).map(|ext| ext.to_string());
+    let added = list.split(',')
+        .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+        .filter(|ext| !ext.is_empty());
+    defaults.chain(added).collect()
+}
+
+/// Applies a directory-name list to `DEFAULT_SKIP_DIRS` the way `extension_set`
+/// does: `out,tmp` adds to them, a leading `=` replaces them.
+pub fn skip_dir_set(spec: &str) -> HashSet<String> {
+    let (replace, list) = match spec.trim().strip_prefix('=') {
+        Some(list) => (true, list),
+        None => (false, spec),
+    };
+    let defaults = DEFAULT_SKIP_DIRS.iter().filter(|_| !replace).map(|dir| dir.to_string());
+    let added = list.split(',')
+        .map(|dir| dir.trim().trim_matches('/').to_string())
+        .filter(|dir| !dir.is_empty());
+    defaults.chain(added).collect()
+}
+
+/// Whether `path` holds prose (documentation) rather than code or config.
+pub fn is_prose(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| PROSE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Interpreters named in shebangs, by the extension whose chunker handles them.
+const SHEBANG_INTERPRETERS: &[(&str, &[&str])] = &[
+    ("py", &["python", "python2", "python3", "pypy", "pypy3"]),
+    ("js", &["node", "nodejs", "deno", "bun"]),
+    ("ts", &["ts-node", "tsx"]),
+    ("rb", &["ruby"]),
+    ("php", &["php"]),
+    ("sh", &["sh", "bash", "zsh", "dash", "ksh"]),
+];
+
+/// Extension of the language a `#!` line runs, e.g. `py` for `#!/usr/bin/env python3`.
+/// Looks through `env` (and its flags) and ignores version suffixes like `python3.11`.
+pub fn shebang_extension(content: &str) -> Option<&'static str> {
+    let line = content.lines().next()?.strip_prefix("#!")?;
+    let mut words = line.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|w| !w.starts_with('-') && !w.contains('='))?;
+    }
+    let name = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    SHEBANG_INTERPRETERS.iter()
+        .find(|(_, names)| names.contains(&program) || names.contains(&name))
+        .map(|(ext, _)| *ext)
+}
+
+/// First line of a file, reading no more than a shebang needs.
+fn read_first_line(path: &Path) -> Option<String> {
+    use std::io::Read;
+    let mut head = [0u8; 256];
+    let len = fs::File::open(path).ok()?.read(&mut head).ok()?;
+    let head = String::from_utf8_lossy(&head[..len]);
+    head.lines().next().map(str::to_string)
+}
+
+/// Whether `path` is a lockfile or build output (by name) rather than hand-written code.
+pub fn is_generated(path: &Path) -> bool {
+    let name = path.file_name().and_then(|s| s.to_str()).unwrap_or("");
+    GENERATED_FILE_NAMES.contains(&name) || GENERATED_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
 }
 
 pub fn process_file(path: &Path, root_path: &str, max_lines: usize) -> Result<Vec<FileChunk>> {
@@ -110,13 +520,112 @@ pub fn process_file(path: &Path, root_path: &str, max_lines: usize) -> Result<Ve
         .to_string_lossy()
         .to_string();
 
+    process_content(path, &content, &relative_path, mtime, max_lines)
+}
+
+/// Chunks in-memory `content` as the file `relative_path`, without touching the disk.
+/// `path_for_ext` only selects the chunker by extension; every chunk gets the
+/// `content_hash` of the whole content.
+pub fn process_content(path_for_ext: &Path, content: &str, relative_path: &str, mtime: u64, max_lines: usize) -> Result<Vec<FileChunk>> {
+    let hash = content_hash(content.as_bytes());
+    let mut chunks = chunk_content(path_for_ext, content, relative_path, mtime, max_lines)?;
+    for chunk in &mut chunks {
+        chunk.content_hash = hash;
+    }
+    Ok(chunks)
+}
+
+fn chunk_content(path: &Path, content: &str, relative_path: &str, mtime: u64, max_lines: usize) -> Result<Vec<FileChunk>> {
+    // Extensionless scripts go through the chunker of the language their shebang names
+    let script_path;
+    let path = match shebang_extension(content).filter(|_| path.extension().is_none()) {
+        Some(ext) => {
+            script_path = path.with_extension(ext);
+            script_path.as_path()
+        }
+        None => path,
+    };
+
+    // Notebooks are JSON, but only the cell sources are worth indexing
+    if path.extension().is_some_and(|ext| ext == "ipynb") {
+        return chunk_notebook(content, relative_path, mtime, max_lines)
+            .ok_or_else(|| anyhow::anyhow!("Invalid notebook: {}", relative_path));
+    }
+
+    // Base64 blobs and minified lines would dominate a chunk's embedding
+    let content = elide_blobs(content, blob_min_len());
+
+    // Single-file components mix a script with markup; neither grammar reads the whole file
+    if path.extension().is_some_and(|ext| ext == "vue" || ext == "svelte") {
+        return Ok(chunk_component(&content, relative_path, mtime, max_lines));
+    }
+
     // Try AST chunking first
-    if let Some(chunks) = chunk_with_tree_sitter(path, &content, &relative_path, mtime, max_lines) {
+    if let Some(chunks) = chunk_with_tree_sitter(path, &content, relative_path, mtime, max_lines) {
+        return Ok(chunks);
+    }
+
+    // Docs and config files have no grammar, but their own structure still beats the heuristic
+    if let Some(chunks) = chunk_by_structure(path, &content, relative_path, mtime, max_lines) {
         return Ok(chunks);
     }
 
     // Fallback to heuristic
-    Ok(chunk_with_heuristic(&content, &relative_path, mtime, max_lines))
+    Ok(chunk_with_heuristic(&content, relative_path, mtime, max_lines))
+}
+
+/// Stable 64-bit FNV-1a. Used for on-disk names and stored hashes, so it must not
+/// change between builds (unlike `DefaultHasher`).
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// `content[start..end]`, widened to the nearest char boundaries: a byte range ending
+/// inside a multibyte character would otherwise panic or turn into U+FFFD.
+fn char_slice(content: &str, start: usize, end: usize) -> &str {
+    let mut end = end.min(content.len());
+    while !content.is_char_boundary(end) {
+        end += 1;
+    }
+    let mut start = start.min(end);
+    while !content.is_char_boundary(start) {
+        start -= 1;
+    }
+    &content[start..end]
+}
+
+/// Import lines kept per file: embedding models only read a few hundred tokens,
+/// and every line here is taken from the chunk's own share.
+const MAX_IMPORT_LINES: usize = 12;
+
+/// Top-level node kinds that bring names into scope, per extension.
+fn import_kinds(ext: &str) -> &'static [&'static str] {
+    match ext {
+        "rs" => &["use_declaration", "extern_crate_declaration"],
+        "py" => &["import_statement", "import_from_statement"],
+        "go" => &["import_declaration"],
+        "js" | "jsx" | "mjs" | "cjs" | "ts" | "tsx" => &["import_statement"],
+        "java" => &["import_declaration"],
+        "cpp" | "cc" | "cxx" | "hpp" | "h" => &["preproc_include", "using_declaration"],
+        "php" => &["namespace_use_declaration"],
+        "cs" => &["using_directive"],
+        _ => &[],
+    }
+}
+
+/// The file's top-level imports, in order and capped at `MAX_IMPORT_LINES` lines.
+fn file_imports(root: tree_sitter::Node, content: &str, ext: &str) -> String {
+    let kinds = import_kinds(ext);
+    let mut cursor = root.walk();
+    let lines: Vec<&str> = root.children(&mut cursor)
+        .filter(|node| kinds.contains(&node.kind()))
+        .filter_map(|node| content.get(node.byte_range()))
+        .flat_map(str::lines)
+        .map(str::trim_end)
+        .filter(|line| !line.trim().is_empty())
+        .take(MAX_IMPORT_LINES)
+        .collect();
+    lines.join("\n")
 }
 
 fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime: u64, max_lines: usize) -> Option<Vec<FileChunk>> {
@@ -165,6 +674,9 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
             (interface_declaration) @interface
             (class_declaration) @class
             (enum_declaration) @enum
+            (internal_module) @namespace
+            (module) @namespace
+            (decorator) @decorator
             "#),
         "tsx" => (tree_sitter_typescript::language_tsx(), 
             r#"
@@ -174,6 +686,9 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
             (arrow_function) @arrow
             (interface_declaration) @interface
             (class_declaration) @class
+            (internal_module) @namespace
+            (module) @namespace
+            (decorator) @decorator
             (jsx_element) @jsx
             "#),
         "java" => (tree_sitter_java::language(), 
@@ -211,6 +726,11 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
             (class_declaration) @class
             (interface_declaration) @interface
             "#),
+        "sh" | "bash" | "zsh" => (tree_sitter_bash::language(),
+            r#"
+            (comment) @comment
+            (function_definition) @func
+            "#),
         _ => return None,
     };
     
@@ -221,6 +741,7 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
 
     let tree = parser.parse(content, None)?;
     let query = Query::new(&language, query_str).ok()?;
+    let imports = file_imports(tree.root_node(), content, ext);
     
     let mut cursor = QueryCursor::new();
     let mut matches = cursor.matches(&query, tree.root_node(), content.as_bytes());
@@ -232,6 +753,10 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
         start_row: usize,
         end_row: usize,
         is_comment: bool,
+        // Decorators attach to the next code span like comments, but aren't doc text
+        is_decorator: bool,
+        symbol_name: Option<String>,
+        symbol_kind: Option<&'static str>,
     }
 
     let check_is_comment = |idx: u32| -> bool {
@@ -243,6 +768,14 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
     while let Some(m) = matches.next() {
         for capture in m.captures {
              let is_comment = check_is_comment(capture.index);
+             let capture_name = query.capture_names()[capture.index as usize];
+             let is_decorator = capture_name == "decorator";
+             // Only decorators outside their target need merging: a class's own decorators
+             // are already inside the class node, but `@Component(...) export class`
+             // and method decorators are siblings of the node they decorate
+             if is_decorator && !capture.node.parent().is_some_and(|p| matches!(p.kind(), "export_statement" | "class_body")) {
+                 continue;
+             }
              let range = capture.node.range();
              spans.push(NodeSpan {
                  start_byte: range.start_byte,
@@ -250,6 +783,9 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
                  start_row: range.start_point.row,
                  end_row: range.end_point.row,
                  is_comment,
+                 is_decorator,
+                 symbol_name: if is_comment || is_decorator { None } else { symbol_name(capture.node, content) },
+                 symbol_kind: symbol_kind(capture_name),
              });
         }
     }
@@ -277,20 +813,29 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
         end_row: usize,
         start_byte: usize,
         end_byte: usize,
+        // Where the code node itself starts, after any merged comments or decorators
+        code_start_byte: usize,
+        code_start_row: usize,
+        // Rows of the whole symbol; a container's remainder pieces cover only part of it
+        symbol_rows: (usize, usize),
+        // Byte range of the leading comment block, if one was merged in
+        doc_range: Option<(usize, usize)>,
+        symbol_name: Option<String>,
+        symbol_kind: Option<&'static str>,
     }
 
     let mut comment_start_row: Option<usize> = None;
     let mut comment_start_byte: Option<usize> = None;
     let mut last_comment_end_row: Option<usize> = None;
+    // Bytes of the comments (not decorators) in the pending block
+    let mut doc_bytes: Option<(usize, usize)> = None;
+    let gap = comment_gap();
 
     for span in spans {
-        if span.is_comment {
+        if span.is_comment || span.is_decorator {
             // Check if contiguous with previous comment
             let is_contiguous = match last_comment_end_row {
-                Some(end_r) => span.start_row <= end_r + 1, // allow 0 or 1 line gap? strict adjacency means `span.start_row == end_r + 1` or `== end_r` (same line?)
-                // Usually comments are on separate lines.
-                // Let's allow max 1 empty line? Or strict?
-                // Strict: `span.start_row == end_r + 1`.
+                Some(end_r) => within_gap(end_r, span.start_row, gap),
                 None => true,
             };
 
@@ -305,15 +850,20 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
                  comment_start_row = Some(span.start_row);
                  comment_start_byte = Some(span.start_byte);
                  last_comment_end_row = Some(span.end_row);
+                 doc_bytes = None;
+            }
+            if span.is_comment {
+                doc_bytes = Some((doc_bytes.map_or(span.start_byte, |(start, _)| start), span.end_byte));
             }
         } else {
             // It is a code block
             let mut final_start_row = span.start_row;
             let mut final_start_byte = span.start_byte;
+            let mut doc_range = None;
             
             // Check if we have a pending comment block immediately before this code
             if let Some(comment_end) = last_comment_end_row {
-                if span.start_row <= comment_end + 1 {
+                if within_gap(comment_end, span.start_row, gap) {
                     // It is adjacent! Include comments.
                     if let Some(c_start) = comment_start_row {
                         final_start_row = c_start;
@@ -321,6 +871,7 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
                      if let Some(c_byte) = comment_start_byte {
                         final_start_byte = c_byte;
                     }
+                    doc_range = doc_bytes;
                 }
             }
             
@@ -330,19 +881,63 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
                 end_row: span.end_row,
                 start_byte: final_start_byte,
                 end_byte: span.end_byte,
+                code_start_byte: span.start_byte,
+                code_start_row: span.start_row,
+                symbol_rows: (final_start_row, span.end_row),
+                doc_range,
+                symbol_name: span.symbol_name,
+                symbol_kind: span.symbol_kind,
             });
             
             // Reset comments
             comment_start_row = None;
             comment_start_byte = None;
             last_comment_end_row = None;
+            doc_bytes = None;
         }
     }
     
-    // Deduplicate exact chunks (nested nodes might trigger multiple times, though we only capture top-levels usually)
-    // Actually our queries capture specific nodes.
-    // Filter duplicates
-    merged_chunks.dedup_by(|a, b| a.start_byte == b.start_byte && a.end_byte == b.end_byte);
+    // Nested captures (a method inside a captured class) would index the same lines twice;
+    // keep one chunk per region. Exact duplicates count as nested.
+    merged_chunks.sort_by_key(|c| (c.start_byte, std::cmp::Reverse(c.end_byte)));
+    let ranges: Vec<(usize, usize, bool)> = merged_chunks.iter()
+        .map(|c| (c.start_byte, c.end_byte, is_container(c.symbol_kind)))
+        .collect();
+    let policy = nested_chunks(ext);
+    let keep = nested_chunk_mask(&ranges, policy);
+    // A container that gives way to its methods still holds fields, constructors and
+    // docstrings; what no member covers stays indexed under the container's name
+    let rows: Vec<(usize, usize)> = merged_chunks.iter().map(|c| (c.start_row, c.end_row)).collect();
+    let lines: Vec<&str> = content.lines().collect();
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(content.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    let mut remainders = Vec::new();
+    for (i, first, last) in container_remainders(&rows, &keep, &split_containers(&ranges, policy)) {
+        let container = &merged_chunks[i];
+        // The header line and closing braces alone say nothing the members don't
+        let has_members = (first..=last.min(lines.len().saturating_sub(1)))
+            .any(|row| row != container.code_start_row && lines[row].chars().any(char::is_alphanumeric));
+        if !has_members {
+            continue;
+        }
+        remainders.push(CodeChunk {
+            start_row: first,
+            end_row: last,
+            start_byte: if first == container.start_row { container.start_byte } else { line_starts[first] },
+            end_byte: line_starts[last] + lines.get(last).map_or(0, |line| line.len()),
+            code_start_byte: container.code_start_byte,
+            code_start_row: container.code_start_row,
+            symbol_rows: container.symbol_rows,
+            doc_range: container.doc_range.filter(|_| first == container.start_row),
+            symbol_name: container.symbol_name.clone(),
+            symbol_kind: container.symbol_kind,
+        });
+    }
+    let mut keep = keep.into_iter();
+    merged_chunks.retain(|_| keep.next().unwrap_or(true));
+    merged_chunks.extend(remainders);
+    merged_chunks.sort_by_key(|c| c.start_byte);
 
     let mut file_chunks = Vec::new();
     let mut idx = 0;
@@ -354,18 +949,38 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
         if start_line > end_line { continue; }
 
         let chunk_lines = end_line - start_line + 1;
+        let (symbol_start, symbol_end) = (chunk.symbol_rows.0 + 1, chunk.symbol_rows.1 + 1);
         
         if chunk.end_byte > content.len() { continue; } 
-        let chunk_bytes = &content.as_bytes()[chunk.start_byte..chunk.end_byte];
-        let chunk_text = String::from_utf8_lossy(chunk_bytes).to_string();
+        let chunk_text = char_slice(content, chunk.start_byte, chunk.end_byte).to_string();
+        let doc_text = chunk.doc_range
+            .map(|(start, end)| char_slice(content, start, end).to_string())
+            .unwrap_or_default();
+        // Pieces that start past the symbol's first line would otherwise lose which symbol
+        // they belong to. Only their embedding gets it: the stored content stays the file's own lines
+        let signature = char_slice(content, chunk.code_start_byte, chunk.end_byte)
+            .lines().find(|l| !l.trim().is_empty()).unwrap_or("").to_string();
+        let past_signature = chunk.start_row > chunk.code_start_row;
         
         if chunk_lines > max_lines {
              // Split huge function using heuristic fallback
              let sub_chunks = chunk_with_heuristic(&chunk_text, relative_path, mtime, max_lines);
-             for mut sub in sub_chunks {
+             for (i, mut sub) in sub_chunks.into_iter().enumerate() {
+                 if (i > 0 || past_signature) && !sub.content.starts_with(&signature) {
+                     sub.signature = signature.clone();
+                 }
                  sub.line_start += start_line - 1;
                  sub.line_end += start_line - 1;
                  sub.chunk_index = idx; 
+                 sub.symbol_start = symbol_start;
+                 sub.symbol_end = symbol_end;
+                 sub.symbol_name = chunk.symbol_name.clone();
+                 sub.symbol_kind = chunk.symbol_kind.map(str::to_string);
+                 // The comment block sits at the top, so only the first piece carries it
+                 if i == 0 {
+                     sub.doc_text = doc_text.clone();
+                 }
+                 sub.imports = imports.clone();
                  file_chunks.push(sub);
                  idx += 1;
              }
@@ -377,6 +992,14 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
                  line_start: start_line,
                  line_end: end_line,
                  mtime,
+                 doc_text,
+                 symbol_start,
+                 symbol_end,
+                 symbol_name: chunk.symbol_name,
+                 symbol_kind: chunk.symbol_kind.map(str::to_string),
+                 content_hash: 0,
+                 imports: imports.clone(),
+                 signature: if past_signature { signature } else { String::new() },
              });
              idx += 1;
         }
@@ -384,7 +1007,461 @@ fn chunk_with_tree_sitter(path: &Path, content: &str, relative_path: &str, mtime
     
     Some(file_chunks)
 }
-    
+
+/// One chunk per code/markdown cell of a Jupyter notebook; outputs (often base64
+/// images) are skipped. Line numbers are 1-based cell numbers, since the raw JSON
+/// lines mean nothing to a reader. Cells over `max_lines` are split, keeping the cell number.
+fn chunk_notebook(content: &str, relative_path: &str, mtime: u64, max_lines: usize) -> Option<Vec<FileChunk>> {
+    let notebook: serde_json::Value = serde_json::from_str(content).ok()?;
+    let cells = notebook.get("cells")?.as_array()?;
+
+    let mut file_chunks = Vec::new();
+    for (i, cell) in cells.iter().enumerate() {
+        let cell_type = cell.get("cell_type").and_then(|t| t.as_str()).unwrap_or("");
+        if cell_type != "code" && cell_type != "markdown" {
+            continue;
+        }
+        // `source` is either one string or a list of lines (with their newlines)
+        let source = match cell.get("source") {
+            Some(serde_json::Value::String(s)) => s.clone(),
+            Some(serde_json::Value::Array(lines)) => lines.iter().filter_map(|l| l.as_str()).collect(),
+            _ => continue,
+        };
+        if source.trim().is_empty() {
+            continue;
+        }
+        let source = elide_blobs(&source, blob_min_len());
+
+        let cell_number = i + 1;
+        for mut chunk in chunk_with_heuristic(&source, relative_path, mtime, max_lines) {
+            chunk.chunk_index = file_chunks.len();
+            chunk.line_start = cell_number;
+            chunk.line_end = cell_number;
+            chunk.symbol_start = cell_number;
+            chunk.symbol_end = cell_number;
+            chunk.symbol_name = Some(format!("{} cell {}", cell_type, cell_number));
+            chunk.symbol_kind = Some(CELL_KIND.to_string());
+            file_chunks.push(chunk);
+        }
+    }
+    Some(file_chunks)
+}
+
+/// A top-level `<script>` or `<style>` element of a Vue or Svelte component.
+struct ComponentBlock<'a> {
+    tag: &'a str,
+    /// Attributes of the opening tag, e.g. ` setup lang="ts"`
+    attrs: &'a str,
+    /// Byte range of the whole element and of the text between its tags
+    element: std::ops::Range<usize>,
+    body: std::ops::Range<usize>,
+}
+
+/// The `<script>` and `<style>` elements of `content`, in order. Plain text search:
+/// components are rarely valid HTML, and these tags don't nest.
+fn component_blocks(content: &str) -> Vec<ComponentBlock<'_>> {
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = content[pos..].find('<') {
+        let start = pos + found;
+        let rest = &content[start + 1..];
+        let Some(tag) = ["script", "style"].into_iter()
+            .find(|tag| rest.strip_prefix(tag).is_some_and(|after| after.starts_with(|c: char| c == '>' || c.is_whitespace())))
+        else {
+            pos = start + 1;
+            continue;
+        };
+        let Some(open_end) = content[start..].find('>').map(|i| start + i + 1) else { break };
+        let attrs = &content[start + 1 + tag.len()..open_end - 1];
+        // `<script src="..." />` has no body
+        let (body_end, end) = if attrs.ends_with('/') {
+            (open_end, open_end)
+        } else {
+            let close = format!("</{}>", tag);
+            let Some(body_end) = content[open_end..].find(&close).map(|i| open_end + i) else { break };
+            (body_end, body_end + close.len())
+        };
+        blocks.push(ComponentBlock { tag, attrs, element: start..end, body: open_end..body_end });
+        pos = end;
+    }
+    blocks
+}
+
+/// Extension of the grammar for a component script, from its `lang` attribute.
+fn script_extension(attrs: &str) -> &'static str {
+    let lang = attrs.split_whitespace()
+        .find_map(|attr| attr.strip_prefix("lang="))
+        .map(|value| value.trim_matches(|c| c == '"' || c == '\''));
+    match lang {
+        Some("ts" | "typescript") => "ts",
+        Some("tsx") => "tsx",
+        Some("jsx") => "jsx",
+        _ => "js",
+    }
+}
+
+/// Narrows `range` to whole lines without the blank ones around it, so chunks
+/// neither start on an empty line nor lose the first line's indentation.
+fn trim_to_lines(content: &str, range: std::ops::Range<usize>) -> std::ops::Range<usize> {
+    let text = &content[range.clone()];
+    let first = range.start + (text.len() - text.trim_start().len());
+    let start = content[..first].rfind('\n').map_or(0, |i| i + 1).max(range.start);
+    start..(range.start + text.trim_end().len()).max(start)
+}
+
+/// Chunks a Vue or Svelte component: each `<script>` with the JS/TS grammar its
+/// `lang` names, and the markup around the blocks (Vue's `<template>`, Svelte's
+/// top level) with the heuristic chunker. `<style>` is skipped. Every piece is
+/// chunked on its own and its line numbers shifted back to the file's.
+fn chunk_component(content: &str, relative_path: &str, mtime: u64, max_lines: usize) -> Vec<FileChunk> {
+    let blocks = component_blocks(content);
+
+    let mut pieces = Vec::new();
+    let mut markup_start = 0;
+    for block in &blocks {
+        pieces.push((markup_start..block.element.start, None));
+        if block.tag == "script" {
+            pieces.push((block.body.clone(), Some(script_extension(block.attrs))));
+        }
+        markup_start = block.element.end;
+    }
+    pieces.push((markup_start..content.len(), None));
+
+    let mut file_chunks = Vec::new();
+    for (range, script_ext) in pieces {
+        let range = trim_to_lines(content, range);
+        let text = &content[range.clone()];
+        if text.trim().is_empty() {
+            continue;
+        }
+        let chunks = script_ext
+            .and_then(|ext| chunk_with_tree_sitter(&Path::new(relative_path).with_extension(ext), text, relative_path, mtime, max_lines))
+            .unwrap_or_else(|| chunk_with_heuristic(text, relative_path, mtime, max_lines));
+        let line_offset = content[..range.start].matches('\n').count();
+        for mut chunk in chunks {
+            chunk.line_start += line_offset;
+            chunk.line_end += line_offset;
+            chunk.symbol_start += line_offset;
+            chunk.symbol_end += line_offset;
+            file_chunks.push(chunk);
+        }
+    }
+    file_chunks.sort_by_key(|chunk| chunk.line_start);
+    for (i, chunk) in file_chunks.iter_mut().enumerate() {
+        chunk.chunk_index = i;
+    }
+    file_chunks
+}
+
+/// Symbol kind of notebook chunks, whose line numbers are cell numbers (`chunk_notebook`).
+pub const CELL_KIND: &str = "cell";
+
+/// Every value `symbol_kind` can produce (for validating user filters).
+pub const SYMBOL_KINDS: &[&str] = &[
+    "function", "method", "class", "struct", "enum", "trait", "interface", "type", "module", "macro", "jsx",
+    CELL_KIND,
+];
+
+/// Maps a query capture name to the symbol kind stored with the chunk.
+fn symbol_kind(capture_name: &str) -> Option<&'static str> {
+    Some(match capture_name {
+        "func" | "arrow" => "function",
+        "method" => "method",
+        "class" => "class",
+        "struct" => "struct",
+        "enum" => "enum",
+        "trait" => "trait",
+        "interface" => "interface",
+        "type" => "type",
+        "mod" | "module" | "namespace" => "module",
+        "macro" => "macro",
+        "jsx" => "jsx",
+        _ => return None,
+    })
+}
+
+/// Best-effort identifier of a definition node. Most grammars expose a `name`
+/// field; C/C++ nest it in declarators, Go in a `type_spec`, and arrow functions
+/// take the name of the variable they are assigned to.
+fn symbol_name(node: Node, source: &str) -> Option<String> {
+    let text = |n: Node| n.utf8_text(source.as_bytes()).ok().map(str::to_string);
+
+    if let Some(name) = node.child_by_field_name("name") {
+        return text(name);
+    }
+    if let Some(mut declarator) = node.child_by_field_name("declarator") {
+        while let Some(inner) = declarator.child_by_field_name("declarator") {
+            declarator = inner;
+        }
+        return text(declarator);
+    }
+    if node.kind() == "arrow_function" {
+        return node.parent()?.child_by_field_name("name").and_then(text);
+    }
+    let mut cursor = node.walk();
+    let spec_name = node.named_children(&mut cursor)
+        .find_map(|child| child.child_by_field_name("name"));
+    spec_name.and_then(text)
+}
+
+/// Chunks markdown by headings and JSON/YAML/TOML by top-level entries.
+/// Returns `None` for extensions without a dedicated strategy.
+fn chunk_by_structure(path: &Path, content: &str, relative_path: &str, mtime: u64, max_lines: usize) -> Option<Vec<FileChunk>> {
+    let ext = path.extension()?.to_str()?;
+    let lines: Vec<&str> = content.lines().collect();
+    if lines.is_empty() {
+        return None;
+    }
+
+    let sections = match ext {
+        // Each heading is its own topic, so sections are never merged
+        "md" => sections_from_starts(markdown_section_starts(&lines), lines.len()),
+        // Config entries are often one-liners; pack neighbours up to max_lines
+        "json" => pack_sections(sections_from_starts(json_section_starts(&lines), lines.len()), max_lines),
+        "yml" | "yaml" => pack_sections(sections_from_starts(yaml_section_starts(&lines), lines.len()), max_lines),
+        "toml" => pack_sections(sections_from_starts(toml_section_starts(&lines), lines.len()), max_lines),
+        // Migrations are often runs of one-line statements
+        "sql" => pack_sections(sections_from_starts(sql_section_starts(&lines), lines.len()), max_lines),
+        _ => return None,
+    };
+
+    let mut file_chunks = Vec::new();
+    let mut idx = 0;
+
+    for (start, end) in sections {
+        let section_text = lines[start..end].join("\n");
+        if section_text.trim().is_empty() {
+            continue;
+        }
+
+        if end - start > max_lines {
+            // Oversized section (e.g. a long README chapter), split it with the heuristic
+            for mut sub in chunk_with_heuristic(&section_text, relative_path, mtime, max_lines) {
+                sub.line_start += start;
+                sub.line_end += start;
+                sub.chunk_index = idx;
+                sub.symbol_start = start + 1;
+                sub.symbol_end = end;
+                file_chunks.push(sub);
+                idx += 1;
+            }
+        } else {
+            file_chunks.push(FileChunk {
+                file_path: relative_path.to_string(),
+                chunk_index: idx,
+                content: section_text,
+                line_start: start + 1,
+                line_end: end,
+                mtime,
+                doc_text: String::new(),
+                symbol_start: start + 1,
+                symbol_end: end,
+                symbol_name: None,
+                symbol_kind: None,
+                content_hash: 0,
+                imports: String::new(),
+                signature: String::new(),
+            });
+            idx += 1;
+        }
+    }
+
+    Some(file_chunks)
+}
+
+/// Turns sorted section start rows into `(start, end)` row ranges (end exclusive).
+/// Anything before the first start becomes a preamble section.
+fn sections_from_starts(mut starts: Vec<usize>, line_count: usize) -> Vec<(usize, usize)> {
+    starts.dedup();
+    if starts.first() != Some(&0) {
+        starts.insert(0, 0);
+    }
+
+    let mut sections = Vec::new();
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(line_count);
+        if start < end {
+            sections.push((start, end));
+        }
+    }
+    sections
+}
+
+/// Greedily merges adjacent sections while the result stays within max_lines.
+fn pack_sections(sections: Vec<(usize, usize)>, max_lines: usize) -> Vec<(usize, usize)> {
+    let mut packed: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in sections {
+        match packed.last_mut() {
+            Some(last) if end - last.0 <= max_lines => last.1 = end,
+            _ => packed.push((start, end)),
+        }
+    }
+    packed
+}
+
+/// Moves a section start up over the comment lines directly above it,
+/// so a comment stays with the entry it documents.
+fn include_leading_comments(lines: &[&str], mut row: usize) -> usize {
+    while row > 0 && lines[row - 1].trim_start().starts_with('#') {
+        row -= 1;
+    }
+    row
+}
+
+fn markdown_section_starts(lines: &[&str]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut in_fence = false;
+
+    for (i, line) in lines.iter().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+            continue;
+        }
+        if in_fence {
+            continue;
+        }
+
+        // ATX heading: 1-6 '#' followed by a space or end of line
+        let level = line.chars().take_while(|&c| c == '#').count();
+        let rest = &line[level..];
+        if (1..=6).contains(&level) && (rest.is_empty() || rest.starts_with(' ')) {
+            starts.push(i);
+        }
+    }
+    starts
+}
+
+fn json_section_starts(lines: &[&str]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut depth = 0i32;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, line) in lines.iter().enumerate() {
+        // A line opening inside the root object/array begins a top-level entry
+        let trimmed = line.trim_start();
+        if depth == 1 && !in_string && !trimmed.is_empty() && !trimmed.starts_with('}') && !trimmed.starts_with(']') {
+            starts.push(i);
+        }
+
+        for c in line.chars() {
+            if in_string {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    in_string = false;
+                }
+                continue;
+            }
+            match c {
+                '"' => in_string = true,
+                '{' | '[' => depth += 1,
+                '}' | ']' => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+    starts
+}
+
+fn yaml_section_starts(lines: &[&str]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        // Unindented keys, top-level list items and document markers
+        let top_level = line.chars().next().is_some_and(|c| !c.is_whitespace() && c != '#');
+        if top_level {
+            starts.push(include_leading_comments(lines, i));
+        }
+    }
+    starts
+}
+
+fn toml_section_starts(lines: &[&str]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        // `[table]` and `[[array.of.tables]]` headers
+        if line.starts_with('[') {
+            starts.push(include_leading_comments(lines, i));
+        }
+    }
+    starts
+}
+
+/// A statement starts on the first non-blank line after the `;` ending the previous
+/// one, so comments above a statement stay with it. Semicolons inside strings, quoted
+/// identifiers, comments and dollar-quoted bodies (`$$ ... $$` in Postgres functions)
+/// don't end a statement.
+fn sql_section_starts(lines: &[&str]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut dollar_tag: Option<&str> = None;
+    let mut in_block_comment = false;
+    let mut statement_ended = false;
+
+    for (i, line) in lines.iter().enumerate() {
+        if statement_ended && !line.trim().is_empty() {
+            starts.push(i);
+            statement_ended = false;
+        }
+
+        let mut chars = line.char_indices().peekable();
+        while let Some((pos, c)) = chars.next() {
+            let rest = &line[pos..];
+            if in_block_comment {
+                if rest.starts_with("*/") {
+                    chars.next();
+                    in_block_comment = false;
+                }
+                continue;
+            }
+            if let Some(tag) = dollar_tag {
+                if rest.starts_with(tag) {
+                    // Tags are ASCII, one char per byte
+                    chars.nth(tag.len() - 2);
+                    dollar_tag = None;
+                }
+                continue;
+            }
+            if let Some(q) = quote {
+                // A doubled quote ('it''s') closes and reopens, which ends up the same
+                if c == q {
+                    quote = None;
+                }
+                continue;
+            }
+            match c {
+                '\'' | '"' | '`' => quote = Some(c),
+                '-' if rest.starts_with("--") => break,
+                '/' if rest.starts_with("/*") => {
+                    chars.next();
+                    in_block_comment = true;
+                }
+                '$' => {
+                    if let Some(tag) = dollar_quote_tag(rest) {
+                        chars.nth(tag.len() - 2);
+                        dollar_tag = Some(tag);
+                    }
+                }
+                ';' => statement_ended = true,
+                _ => {}
+            }
+        }
+    }
+    starts
+}
+
+/// The `$tag$` (or `$$`) opening a dollar-quoted string at the start of `s`.
+/// Positional parameters like `$1` aren't tags.
+fn dollar_quote_tag(s: &str) -> Option<&str> {
+    let body_len = s[1..].find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))?;
+    let body = &s[1..1 + body_len];
+    let closed = s[1 + body_len..].starts_with('$');
+    (closed && !body.starts_with(|c: char| c.is_ascii_digit())).then(|| &s[..body_len + 2])
+}
+
 fn chunk_with_heuristic(content: &str, relative_path: &str, mtime: u64, max_lines: usize) -> Vec<FileChunk> {
     let lines: Vec<&str> = content.lines().collect();
     let mut chunks = Vec::new();
@@ -404,6 +1481,14 @@ fn chunk_with_heuristic(content: &str, relative_path: &str, mtime: u64, max_line
             line_start: 1,
             line_end: line_count,
             mtime,
+            doc_text: String::new(),
+            symbol_start: 1,
+            symbol_end: line_count,
+            symbol_name: None,
+            symbol_kind: None,
+            content_hash: 0,
+            imports: String::new(),
+            signature: String::new(),
         });
     } else {
         let mut start_line = 0;
@@ -450,6 +1535,14 @@ fn chunk_with_heuristic(content: &str, relative_path: &str, mtime: u64, max_line
                     line_start: start_line + 1,
                     line_end: end_line,
                     mtime,
+                    doc_text: String::new(),
+                    symbol_start: start_line + 1,
+                    symbol_end: end_line,
+                    symbol_name: None,
+                    symbol_kind: None,
+                    content_hash: 0,
+                    imports: String::new(),
+                    signature: String::new(),
                 });
                 idx += 1;
             }
@@ -470,7 +1563,11 @@ fn ensure_gitignore(root_path: &str) {
 
     if gitignore_path.exists() {
         if let Ok(content) = fs::read_to_string(&gitignore_path) {
-            if !content.contains(entry) {
+            // Whole-line match, tolerating a leading '/' and a missing trailing '/'
+            let present = content.lines().any(|line| {
+                matches!(line.trim(), ".code-search" | ".code-search/" | "/.code-search" | "/.code-search/")
+            });
+            if !present {
                 use std::io::Write;
                 if let Ok(mut file) = fs::OpenOptions::new().append(true).open(&gitignore_path) {
                     let _ = writeln!(file, "\n{}", entry);
@@ -484,3 +1581,377 @@ fn ensure_gitignore(root_path: &str) {
          }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks_of(name: &str, source: &str) -> Vec<FileChunk> {
+        process_content(Path::new(name), source, name, 0, 40).unwrap()
+    }
+
+    /// `(line_start, line_end, symbol_name)` per chunk, the golden shape below.
+    fn spans(name: &str, source: &str) -> Vec<(usize, usize, Option<String>)> {
+        chunks_of(name, source).into_iter()
+            .map(|c| (c.line_start, c.line_end, c.symbol_name))
+            .collect()
+    }
+
+    fn assert_spans(name: &str, source: &str, expected: &[(usize, usize, &str)]) {
+        let expected: Vec<_> = expected.iter()
+            .map(|&(start, end, symbol)| (start, end, Some(symbol.to_string())))
+            .collect();
+        assert_eq!(spans(name, source), expected, "{}", name);
+    }
+
+    #[test]
+    fn rust_doc_comment_merges_into_fn_chunk() {
+        let source = "use std::fmt;\n\n/// Adds two numbers.\n/// Wraps on overflow.\nfn add(a: u32, b: u32) -> u32 {\n    a.wrapping_add(b)\n}\n\nstruct Point {\n    x: i32,\n    y: i32,\n}\n";
+        let chunks = chunks_of("lib.rs", source);
+        assert_eq!(chunks.len(), 2);
+
+        let add = &chunks[0];
+        assert_eq!((add.line_start, add.line_end), (3, 7));
+        assert_eq!((add.symbol_start, add.symbol_end), (3, 7));
+        assert_eq!(add.symbol_name.as_deref(), Some("add"));
+        assert_eq!(add.symbol_kind.as_deref(), Some("function"));
+        assert!(add.content.starts_with("/// Adds two numbers.\n"));
+        assert!(add.content.ends_with("}"));
+        assert_eq!(add.doc_text, "/// Adds two numbers.\n/// Wraps on overflow.");
+
+        let point = &chunks[1];
+        assert_eq!((point.line_start, point.line_end), (9, 12));
+        assert_eq!(point.symbol_kind.as_deref(), Some("struct"));
+        assert!(point.doc_text.is_empty());
+    }
+
+    #[test]
+    fn char_slice_widens_to_char_boundaries() {
+        let s = "a日b🦀c";
+        // 日 is bytes 1..4, 🦀 bytes 5..9
+        assert_eq!(char_slice(s, 2, 3), "日");
+        assert_eq!(char_slice(s, 0, 2), "a日");
+        assert_eq!(char_slice(s, 6, 7), "🦀");
+        assert_eq!(char_slice(s, 4, 100), "b🦀c");
+    }
+
+    #[test]
+    fn multibyte_source_keeps_its_characters_and_lines() {
+        let source = "// 配置を読み込む 🚀\nfn 读取配置(路径: &str) -> String {\n    format!(\"✅ {}\", 路径)\n}\n\n/// Émoji: 🦀🦀\nfn crab() -> &'static str {\n    \"🦀\"\n}\n";
+        let chunks = chunks_of("i18n.rs", source);
+        assert_eq!(chunks.len(), 2);
+        let lines: Vec<&str> = source.lines().collect();
+        for chunk in &chunks {
+            assert!(!chunk.content.contains('\u{FFFD}'), "{}", chunk.content);
+            assert_eq!(chunk.content, lines[chunk.line_start - 1..chunk.line_end].join("\n"));
+        }
+        assert_eq!((chunks[0].line_start, chunks[0].line_end), (1, 4));
+        assert_eq!(chunks[0].symbol_name.as_deref(), Some("读取配置"));
+        assert_eq!((chunks[1].line_start, chunks[1].line_end), (6, 9));
+    }
+
+    #[test]
+    fn line_numbers_are_one_based() {
+        let chunks = chunks_of("lib.rs", "fn first() {}\n");
+        assert_eq!((chunks[0].line_start, chunks[0].line_end), (1, 1));
+    }
+
+    #[test]
+    fn golden_spans_per_language() {
+        assert_spans(
+            "a.py",
+            "import os\n\n\ndef greet(name):\n    \"\"\"Say hello.\"\"\"\n    return f\"hi {name}\"\n\n\nclass Shape:\n    def area(self):\n        return 0\n",
+            &[(4, 6, "greet"), (10, 11, "area")],
+        );
+        assert_spans(
+            "a.js",
+            "// Doubles a value.\nfunction double(x) {\n  return x * 2;\n}\n\nclass Counter {\n  inc() {\n    this.n++;\n  }\n}\n",
+            &[(1, 4, "double"), (7, 9, "inc")],
+        );
+        assert_spans(
+            "a.ts",
+            "interface Shape {\n  area(): number;\n}\n\n/** Unit square. */\nexport function unit(): Shape {\n  return { area: () => 1 };\n}\n",
+            &[(1, 3, "Shape"), (5, 8, "unit")],
+        );
+        assert_spans(
+            "a.go",
+            "package main\n\n// Add sums two ints.\nfunc Add(a, b int) int {\n\treturn a + b\n}\n\ntype Pair struct {\n\tA, B int\n}\n",
+            &[(3, 6, "Add"), (8, 10, "Pair")],
+        );
+        assert_spans(
+            "a.java",
+            "public class Greeter {\n    /** Says hi. */\n    public String greet() {\n        return \"hi\";\n    }\n}\n",
+            &[(2, 5, "greet")],
+        );
+    }
+
+    #[test]
+    fn vue_script_is_chunked_by_its_lang_at_file_lines() {
+        let source = "<template>\n  <div class=\"greeting\">{{ message }}</div>\n</template>\n\n<script setup lang=\"ts\">\ninterface Props {\n  name: string;\n}\n\nfunction greet(props: Props): string {\n  return `hi ${props.name}`;\n}\n</script>\n\n<style scoped>\n.greeting { color: red; }\n</style>\n";
+        let chunks = chunks_of("Greeting.vue", source);
+        assert!(chunks.iter().all(|c| !c.content.contains("color: red")), "style is not indexed");
+        assert!(chunks.iter().any(|c| c.content.contains("{{ message }}")), "markup is indexed");
+
+        // `interface` only parses as TypeScript
+        let props = chunks.iter().find(|c| c.symbol_name.as_deref() == Some("Props")).unwrap();
+        assert_eq!(props.symbol_kind.as_deref(), Some("interface"));
+        assert_eq!((props.line_start, props.line_end, props.symbol_start, props.symbol_end), (6, 8, 6, 8));
+
+        let greet = chunks.iter().find(|c| c.symbol_name.as_deref() == Some("greet")).unwrap();
+        assert_eq!((greet.line_start, greet.line_end, greet.symbol_start, greet.symbol_end), (10, 12, 10, 12));
+        assert_eq!(greet.content.lines().next(), source.lines().nth(9));
+        let indices: Vec<usize> = chunks.iter().map(|c| c.chunk_index).collect();
+        assert_eq!(indices, (0..chunks.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn split_pieces_embed_the_signature_but_store_the_file_lines() {
+        let body: String = (0..198).map(|i| format!("    total += {};\n", i)).collect();
+        let source = format!("fn sum_all(total: &mut u64) {{\n{}}}\n", body);
+        let lines: Vec<&str> = source.lines().collect();
+        let chunks = chunks_of("lib.rs", &source);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert_eq!(chunk.content, lines[chunk.line_start - 1..chunk.line_end].join("\n"));
+            let text = chunk.embedding_text(false, false);
+            assert!(text.starts_with("fn sum_all(total: &mut u64) {\n"), "{}", chunk.line_start);
+        }
+    }
+
+    #[test]
+    fn split_pieces_embed_the_signature_not_the_doc_comment() {
+        let body: String = (0..198).map(|i| format!("    total += {};\n", i)).collect();
+        let source = format!("/// Adds everything up.\n/// Saturates at u64::MAX.\nfn sum_all(total: &mut u64) {{\n{}}}\n", body);
+        let chunks = chunks_of("lib.rs", &source);
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks[0].line_start, 1);
+        for chunk in &chunks[1..] {
+            assert_eq!(chunk.signature, "fn sum_all(total: &mut u64) {");
+        }
+    }
+
+    #[test]
+    fn oversized_markdown_sections_keep_the_section_as_symbol() {
+        let chapter: String = (0..120).map(|i| format!("Line {} of the chapter.\n", i)).collect();
+        let source = format!("# Intro\n\nShort.\n\n## Chapter\n{}", chapter);
+        let chunks = chunks_of("README.md", &source);
+        let pieces: Vec<&FileChunk> = chunks.iter().filter(|c| c.line_start >= 5).collect();
+        assert!(pieces.len() > 1);
+        for piece in pieces {
+            assert_eq!((piece.symbol_start, piece.symbol_end), (5, 125));
+            assert!(piece.symbol_start <= piece.line_start && piece.line_end <= piece.symbol_end);
+        }
+    }
+
+    #[test]
+    fn comment_gap_counts_blank_lines() {
+        // A comment on row 0, code after 0, 1 and 2 blank lines
+        let rows = [1, 2, 3];
+        let merged = |gap| rows.map(|start_row| within_gap(0, start_row, gap));
+        assert_eq!(merged(0), [true, false, false]);
+        assert_eq!(merged(1), [true, true, false]);
+        assert_eq!(merged(2), [true, true, true]);
+    }
+
+    #[test]
+    fn comment_after_blank_line_stays_out_by_default() {
+        let chunks = chunks_of("lib.rs", "// Unrelated note.\n\nfn solo() {}\n");
+        let solo = chunks.iter().find(|c| c.symbol_name.as_deref() == Some("solo")).unwrap();
+        assert_eq!(solo.line_start, 3);
+        assert!(solo.doc_text.is_empty());
+    }
+
+    #[test]
+    fn skipped_dirs_only_count_inside_the_repository() {
+        let filter = FileFilter {
+            extensions: HashSet::from(["rs".to_string()]),
+            index_generated: false,
+            skip_dirs: HashSet::from(["build".to_string(), "target".to_string()]),
+        };
+        let root = Path::new("/home/dev/build/repo");
+        assert!(!filter.in_skipped_dir(Path::new("/home/dev/build/repo/src/lib.rs"), root));
+        assert!(filter.in_skipped_dir(Path::new("/home/dev/build/repo/target/debug/out.rs"), root));
+        // Paths that are already relative are checked as they are
+        assert!(filter.in_skipped_dir(Path::new("build/gen.rs"), Path::new("")));
+    }
+
+    #[test]
+    fn angular_decorators_stay_with_their_class_and_method() {
+        let source = "import { Component, HostListener, Input } from '@angular/core';\n\n@Component({\n  selector: 'app-hero',\n  template: '<h1>{{ hero }}</h1>',\n})\nexport class HeroComponent {\n  @Input() hero = '';\n}\n\nexport class Clicker {\n  @HostListener('click')\n  onClick() {\n    this.count++;\n  }\n}\n\nnamespace Heroes {\n  export const max = 10;\n}\n";
+        for name in ["hero.component.ts", "hero.component.tsx"] {
+            let chunks = chunks_of(name, source);
+            let class = chunks.iter().find(|c| c.symbol_name.as_deref() == Some("HeroComponent")).unwrap();
+            assert_eq!(class.symbol_kind.as_deref(), Some("class"), "{}", name);
+            assert_eq!((class.line_start, class.line_end), (3, 9), "{}", name);
+            assert!(class.content.starts_with("@Component({\n  selector: 'app-hero',"), "{}", name);
+            assert!(class.doc_text.is_empty(), "decorators are not doc text");
+
+            let method = chunks.iter().find(|c| c.symbol_name.as_deref() == Some("onClick")).unwrap();
+            assert_eq!((method.line_start, method.line_end), (12, 15), "{}", name);
+            assert!(method.content.starts_with("@HostListener('click')\n  onClick() {"), "{}", name);
+
+            let namespace = chunks.iter().find(|c| c.symbol_name.as_deref() == Some("Heroes")).unwrap();
+            assert_eq!(namespace.symbol_kind.as_deref(), Some("module"), "{}", name);
+            assert_eq!((namespace.line_start, namespace.line_end), (18, 20), "{}", name);
+        }
+    }
+
+    #[test]
+    fn bash_functions_are_chunked_with_their_comments() {
+        let source = "#!/usr/bin/env bash\nset -euo pipefail\n\n# Builds the release binary.\nbuild() {\n  cargo build --release\n}\n\nfunction deploy {\n  build\n  scp target/release/app \"$HOST:\"\n}\n\ndeploy\n";
+        for name in ["deploy.sh", "deploy.bash", "deploy.zsh"] {
+            assert_spans(name, source, &[(4, 7, "build"), (9, 12, "deploy")]);
+        }
+        let chunks = chunks_of("deploy.sh", source);
+        assert!(chunks.iter().all(|c| c.symbol_kind.as_deref() == Some("function")));
+        assert!(chunks[0].content.starts_with("# Builds the release binary.\n"));
+    }
+
+    #[test]
+    fn shebangs_name_the_language() {
+        assert_eq!(shebang_extension("#!/usr/bin/env python3\n"), Some("py"));
+        assert_eq!(shebang_extension("#!/usr/bin/python3.11 -u\n"), Some("py"));
+        assert_eq!(shebang_extension("#!/bin/bash\nset -e\n"), Some("sh"));
+        assert_eq!(shebang_extension("#!/usr/bin/env -S FOO=1 node --harmony\n"), Some("js"));
+        assert_eq!(shebang_extension("#!/usr/bin/env perl\n"), None);
+        assert_eq!(shebang_extension("print('no shebang')\n"), None);
+    }
+
+    #[test]
+    fn extensionless_scripts_are_indexed_by_their_shebang() {
+        let dir = std::env::temp_dir().join(format!("code-search-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let python = "#!/usr/bin/env python3\n\ndef main():\n    print('deploying')\n\n\nmain()\n";
+        let bash = "#!/bin/bash\nset -e\n\nbuild() {\n  cargo build --release\n}\n\nbuild\n";
+        std::fs::write(dir.join("deploy"), python).unwrap();
+        std::fs::write(dir.join("build"), bash).unwrap();
+        std::fs::write(dir.join("NOTES"), "just some text\n").unwrap();
+
+        let filter = FileFilter {
+            extensions: HashSet::from(["py".to_string(), "sh".to_string()]),
+            index_generated: false,
+            skip_dirs: HashSet::new(),
+        };
+        assert!(filter.should_process_file(&dir.join("deploy")));
+        assert!(filter.should_process_file(&dir.join("build")));
+        assert!(!filter.should_process_file(&dir.join("NOTES")));
+        // Only the extensions asked for
+        let rust_only = FileFilter { extensions: HashSet::from(["rs".to_string()]), ..filter };
+        assert!(!rust_only.should_process_file(&dir.join("deploy")));
+        // Archive entries aren't on disk; their first line is passed in
+        let filter = FileFilter { extensions: HashSet::from(["py".to_string()]), ..rust_only };
+        assert!(filter.should_process_entry(Path::new("bin/deploy"), "#!/usr/bin/env python3"));
+        assert!(!filter.should_process_entry(Path::new("bin/deploy"), "just some text"));
+        assert!(filter.should_process_entry(Path::new("src/app.py"), ""));
+
+        // Chunked by the grammar of the language the shebang names
+        let root = dir.to_string_lossy();
+        let chunks = process_file(&dir.join("deploy"), &root, 40).unwrap();
+        let main = chunks.iter().find(|c| c.symbol_name.as_deref() == Some("main")).unwrap();
+        assert_eq!((main.line_start, main.line_end, main.symbol_kind.as_deref()), (3, 4, Some("function")));
+        let chunks = process_file(&dir.join("build"), &root, 40).unwrap();
+        let build = chunks.iter().find(|c| c.symbol_name.as_deref() == Some("build")).unwrap();
+        assert_eq!((build.line_start, build.line_end), (4, 6));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn nested_chunk_mask_keeps_one_level_per_region() {
+        // class 0..100 { method 10..40 { closure 20..30 } method 50..90 }, then fn 100..120 { fn 105..110 }
+        let ranges = [(0, 100, true), (10, 40, false), (20, 30, false), (50, 90, false), (100, 120, false), (105, 110, false)];
+        assert_eq!(nested_chunk_mask(&ranges, NestedChunks::Outermost), [true, false, false, false, true, false]);
+        assert_eq!(nested_chunk_mask(&ranges, NestedChunks::Innermost), [false, true, false, true, true, false]);
+        // An exact duplicate counts as nested
+        assert_eq!(nested_chunk_mask(&[(0, 10, false), (0, 10, false)], NestedChunks::Outermost), [true, false]);
+        // A class without members stays
+        assert_eq!(nested_chunk_mask(&[(0, 10, true), (20, 30, true)], NestedChunks::Innermost), [true, true]);
+    }
+
+    #[test]
+    fn nested_classes_emit_no_overlapping_chunks() {
+        let source = "class Outer:\n    class Inner:\n        def a(self):\n            return 1\n\n        def b(self):\n            def helper():\n                return 2\n            return helper()\n\n    def c(self):\n        return 3\n";
+        let chunks = chunks_of("nested.py", source);
+        for pair in chunks.windows(2) {
+            assert!(pair[0].line_end < pair[1].line_start, "{:?} overlaps {:?}", pair[0].symbol_name, pair[1].symbol_name);
+        }
+        assert_spans("nested.py", source, &[(3, 4, "a"), (6, 9, "b"), (11, 12, "c")]);
+    }
+
+    #[test]
+    fn containment_policy_per_language() {
+        // Class-based languages index the methods, not the class again
+        assert_spans(
+            "Repo.java",
+            "public class Repo {\n    public void save() {\n        flush();\n    }\n\n    public void load() {\n        read();\n    }\n}\n",
+            &[(2, 4, "save"), (6, 8, "load")],
+        );
+        assert_spans(
+            "repo.py",
+            "class Repo:\n    def save(self):\n        self.flush()\n\n    def load(self):\n        self.read()\n",
+            &[(2, 3, "save"), (5, 6, "load")],
+        );
+        // Rust keeps traits and modules whole
+        assert_spans(
+            "repo.rs",
+            "trait Repo {\n    fn save(&self) {\n        self.flush();\n    }\n    fn load(&self);\n}\n\nmod tests {\n    fn helper() {}\n}\n",
+            &[(1, 6, "Repo"), (8, 10, "tests")],
+        );
+    }
+
+    #[test]
+    fn container_remainders_go_to_the_innermost_container() {
+        // class 0..20 { fields 1..2, method 3..6, class 8..15 { field 9, method 10..13 }, field 17 }
+        let rows = [(0, 20), (3, 6), (8, 15), (10, 13)];
+        let keep = [false, true, false, true];
+        let split = [true, false, true, false];
+        assert_eq!(
+            container_remainders(&rows, &keep, &split),
+            [(0, 0, 2), (0, 7, 7), (2, 8, 9), (2, 14, 15), (0, 16, 20)],
+        );
+        // Inside a kept function a class has nothing left
+        assert_eq!(container_remainders(&[(0, 10), (2, 8), (3, 5)], &[true, false, false], &[false, true, false]), []);
+    }
+
+    #[test]
+    fn class_members_outside_methods_stay_indexed() {
+        let java = "/** A repository. */\npublic class Repo {\n    private final Db db;\n\n    public Repo(Db db) {\n        this.db = db;\n    }\n\n    public void save() {\n        db.flush();\n    }\n\n    private int retries = 3;\n}\n";
+        let chunks = chunks_of("Repo.java", java);
+        assert_eq!(
+            spans("Repo.java", java),
+            [(1, 8, Some("Repo".to_string())), (9, 11, Some("save".to_string())), (12, 14, Some("Repo".to_string()))],
+        );
+        let class = &chunks[0];
+        assert!(class.content.contains("public Repo(Db db) {"));
+        assert_eq!(class.doc_text, "/** A repository. */");
+        assert_eq!((class.symbol_start, class.symbol_end), (1, 14));
+        assert!(class.signature.is_empty());
+        let tail = &chunks[2];
+        assert_eq!(tail.content, "\n    private int retries = 3;\n}");
+        assert_eq!(tail.signature, "public class Repo {");
+        assert!(tail.doc_text.is_empty());
+
+        let python = "class Repo:\n    \"\"\"Stores things.\"\"\"\n    retries = 3\n\n    def save(self):\n        self.flush()\n";
+        assert_spans("repo.py", python, &[(1, 4, "Repo"), (5, 6, "save")]);
+        assert!(chunks_of("repo.py", python)[0].content.contains("retries = 3"));
+    }
+
+    #[test]
+    fn oversized_fn_is_split_within_its_symbol_range() {
+        let body: String = (0..100).map(|i| format!("    let x{} = {};\n", i, i)).collect();
+        let source = format!("fn big() {{\n{}}}\n", body);
+        let chunks = chunks_of("lib.rs", &source);
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks[0].line_start, 1);
+        assert_eq!(chunks.last().unwrap().line_end, 102);
+        // Pieces overlap a little but leave no gaps
+        for pair in chunks.windows(2) {
+            assert!(pair[1].line_start > pair[0].line_start);
+            assert!(pair[1].line_start <= pair[0].line_end + 1);
+        }
+        for chunk in &chunks {
+            assert!(chunk.line_end - chunk.line_start < 40);
+            assert_eq!((chunk.symbol_start, chunk.symbol_end), (1, 102));
+            assert_eq!(chunk.symbol_name.as_deref(), Some("big"));
+        }
+    }
+}
@@ -1,63 +1,681 @@
-use crate::embeddings::EmbeddingModel;
-use crate::scanner::{scan_repository, process_file, FileEntry, FileChunk};
-use crate::store::VectorStore;
+use crate::embeddings::{configured_docs_model, configured_model_id, configured_rerank_model, ApiEmbedder, CrossEncoder, Embedder, EmbeddingModel, MODEL_DIM, MODEL_ID};
+use crate::error::SearchError;
+use crate::scanner::{env_flag, scan_repository, process_file, process_content, content_hash, is_prose, FileEntry, DEFAULT_MAX_LINES, FileChunk, FileFilter};
+use crate::store::{branch_suffix, EmbedSettings, IndexStats, IndexedChunk, VectorStore, CODE_TABLE, DOCS_TABLE};
 use crate::text_index::TextIndex;
 use anyhow::Result;
-use std::path::Path;
-use std::collections::{HashSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use rayon::prelude::*;
+use futures::StreamExt;
+
+const DEFAULT_COMMIT_BATCH_FILES: usize = 200;
+const PROGRESS_FILE: &str = "indexing.progress";
+/// What built the index, see `IndexManifest`.
+const INDEX_MANIFEST_FILE: &str = "manifest.json";
+/// Version of the on-disk index (tables, text index, chunking). Bump it when a
+/// release changes them in a way older indexes can't follow: `update_index` then
+/// rebuilds indexes with a lower version instead of misreading them.
+const INDEX_SCHEMA_VERSION: u32 = 2;
+/// Distinct queries remembered per `Searcher`.
+const QUERY_CACHE_ENTRIES: usize = 128;
+
+/// Default `SearchOptions::keyword_boost`.
+const DEFAULT_KEYWORD_BOOST: f32 = 0.1;
+/// Multiplier on reciprocal rank fusion points, for the text rank and for agreeing
+/// queries (`fuse_queries`). A top rank at the default k (60) then adds ~0.17, the
+/// order of a full default keyword boost, rather than a negligible ~0.017.
+const RRF_SCALE: f32 = 100.0 * DEFAULT_KEYWORD_BOOST;
+
+/// Query embeddings kept by `Searcher::embed_queries`, least recently used first out.
+const QUERY_EMBEDDING_ENTRIES: usize = 256;
+/// How long a repository walk is reused, overridable via `CODE_SEARCH_SCAN_TTL_SECS`.
+const DEFAULT_SCAN_TTL_SECS: u64 = 5;
+
+/// Per-call settings for `Searcher::search`.
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    /// Max lines per chunk for a new index (None = `DEFAULT_MAX_LINES`). An existing
+    /// index keeps the size it was built with, see `Searcher::chunk_max_lines`
+    pub max_lines: Option<usize>,
+    /// Lines of each chunk's content a new index keeps in the vector store (None keeps
+    /// all, 0 none); results read the rest back from the file (`load_full_content`)
+    pub store_content_lines: Option<usize>,
+    /// Glob patterns to exclude from the scan
+    pub exclude: Vec<String>,
+    /// Max number of results returned
+    pub limit: usize,
+    /// Append `.code-search/` to the repository's .gitignore when missing
+    pub update_gitignore: bool,
+    /// OR-expand the lexical (tantivy) query with programming synonyms
+    pub synonyms: bool,
+    /// Lexical matches need every query term, not just one of them
+    pub match_all: bool,
+    /// Hand the query to tantivy as query syntax instead of plain words (`keyword_query`)
+    pub query_syntax: bool,
+    /// More queries searched alongside the main one; their candidates are merged
+    /// into one ranked list (`fuse_queries`)
+    pub extra_queries: Vec<String>,
+    /// Vector candidates fetched per requested result before reranking
+    pub recall_multiplier: usize,
+    /// Minimum number of vector candidates fetched regardless of limit
+    pub recall_floor: usize,
+    /// Added to the score (roughly 0-1) of chunks containing every query word;
+    /// partial matches get the matching fraction
+    pub keyword_boost: f32,
+    /// Reciprocal rank fusion constant: a list's item at rank r scores 1/(k + r).
+    /// Lower values sharpen the preference for each list's top items
+    pub rrf_k: f32,
+    /// Drop reranked candidates whose content doesn't match
+    pub grep: Option<regex::Regex>,
+    /// Return the full span of a symbol that was split into sub-chunks
+    pub whole_symbol: bool,
+    /// Only return chunks with one of these symbol kinds (empty = all)
+    pub kinds: Vec<String>,
+    /// Only return chunks of files modified within this long before the query
+    pub since: Option<std::time::Duration>,
+    /// Only search chunks of this file (path relative to the repository root)
+    pub file: Option<String>,
+    /// Fill in `SearchResult::embedding` (e.g. for `cluster_results`)
+    pub with_vectors: bool,
+    /// Set (e.g. from a Ctrl-C handler) to stop indexing after the current batch is committed
+    pub interrupt: Option<Arc<AtomicBool>>,
+    /// Threads for the repository walk (0 = all cores). Chunking runs on rayon's
+    /// global pool, which is sized once per process by `init_thread_pool`
+    pub threads: usize,
+    /// Reuse results of an identical earlier query while the index is unchanged
+    pub cache: bool,
+    /// File extensions to index (without the dot)
+    pub extensions: HashSet<String>,
+    /// Also index lockfiles and generated files (`scanner::is_generated`)
+    pub index_generated: bool,
+    /// Directory names never indexed (`scanner::skip_dir_set`)
+    pub skip_dirs: HashSet<String>,
+    /// Maintain and query the tantivy text index; off means vector-only ranking
+    pub text_index: bool,
+    /// Prepend each file's import statements to its chunks before embedding
+    pub include_imports: bool,
+    /// Prepend a header line with the file path and symbol name before embedding
+    pub embed_header: bool,
+    /// Embed whitespace-normalized text (`normalize_whitespace`); results still show the original
+    pub normalize: bool,
+    /// Prune and compact the tables after writes, when `VectorStore::cleanup_due` says so
+    pub cleanup: bool,
+    /// Tag chunks with the git branch they were indexed on (`current_branch`) and
+    /// only read and write the current branch's
+    pub branch_aware: bool,
+    /// Query the chunks of every branch, not just the current one
+    pub all_branches: bool,
+    /// Rescore the final candidates with a cross-encoder (`CrossEncoder`)
+    pub rerank: bool,
+    /// Filled in with per-stage counts and timings as the search runs
+    pub plan: Option<Arc<Mutex<SearchPlan>>>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            max_lines: None,
+            store_content_lines: std::env::var("CODE_SEARCH_STORE_CONTENT_LINES")
+                .ok()
+                .and_then(|s| s.parse().ok()),
+            exclude: vec![],
+            limit: 10,
+            // Opt out globally with CODE_SEARCH_NO_GITIGNORE=1
+            update_gitignore: !env_flag("CODE_SEARCH_NO_GITIGNORE"),
+            synonyms: false,
+            query_syntax: env_flag("CODE_SEARCH_QUERY_SYNTAX"),
+            extra_queries: vec![],
+            match_all: std::env::var("CODE_SEARCH_MATCH").is_ok_and(|v| v.trim().eq_ignore_ascii_case("all")),
+            recall_multiplier: 3,
+            recall_floor: std::env::var("CODE_SEARCH_RECALL_FLOOR")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(50),
+            keyword_boost: DEFAULT_KEYWORD_BOOST,
+            rrf_k: std::env::var("CODE_SEARCH_RRF_K")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|k: &f32| *k > 0.0)
+                .unwrap_or(60.0),
+            grep: None,
+            whole_symbol: false,
+            kinds: vec![],
+            since: None,
+            file: None,
+            with_vectors: false,
+            interrupt: None,
+            threads: std::env::var("CODE_SEARCH_THREADS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(0),
+            cache: true,
+            extensions: crate::scanner::extension_set(
+                &std::env::var("CODE_SEARCH_EXTENSIONS").unwrap_or_default()
+            ),
+            index_generated: env_flag("CODE_SEARCH_INDEX_GENERATED"),
+            skip_dirs: crate::scanner::skip_dir_set(
+                &std::env::var("CODE_SEARCH_SKIP_DIRS").unwrap_or_default()
+            ),
+            text_index: !env_flag("CODE_SEARCH_DISABLE_TEXT"),
+            include_imports: env_flag("CODE_SEARCH_INCLUDE_IMPORTS"),
+            embed_header: env_flag("CODE_SEARCH_EMBED_HEADER"),
+            normalize: env_flag("CODE_SEARCH_NORMALIZE"),
+            cleanup: !env_flag("CODE_SEARCH_NO_CLEANUP"),
+            branch_aware: env_flag("CODE_SEARCH_BRANCH_AWARE"),
+            all_branches: false,
+            rerank: env_flag("CODE_SEARCH_RERANK"),
+            plan: None,
+        }
+    }
+}
+
+impl SearchOptions {
+    /// The file selection part of the options, as used by the scanner and watcher.
+    pub fn file_filter(&self) -> FileFilter {
+        FileFilter {
+            extensions: self.extensions.clone(),
+            index_generated: self.index_generated,
+            skip_dirs: self.skip_dirs.clone(),
+        }
+    }
+
+    /// The options that change what gets embedded, which an index records.
+    pub fn embed_settings(&self) -> EmbedSettings {
+        EmbedSettings {
+            include_imports: self.include_imports,
+            embed_header: self.embed_header,
+            normalize: self.normalize,
+        }
+    }
+}
+
+/// What each stage of a search produced and how long it took (`--explain-plan`).
+/// Counts of stages that didn't run (e.g. no re-index) stay 0.
+#[derive(Debug, Default)]
+pub struct SearchPlan {
+    pub files_scanned: usize,
+    pub files_reindexed: usize,
+    pub chunks_embedded: usize,
+    /// Vector candidates requested per table
+    pub fetch_limit: usize,
+    pub vector_candidates: usize,
+    /// Hits from the text index
+    pub text_candidates: usize,
+    /// Distinct files ranked by reciprocal rank fusion
+    pub fused: usize,
+    pub above_threshold: usize,
+    /// Candidates left by `--grep`, when given
+    pub after_grep: Option<usize>,
+    pub returned: usize,
+    /// Results came from the query cache, so no ranking stage ran
+    pub cached: bool,
+    /// Wall time per stage, in the order they ran
+    pub stages: Vec<(&'static str, std::time::Duration)>,
+}
+
+impl std::fmt::Display for SearchPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Search plan:")?;
+        writeln!(f, "  files scanned      {}", self.files_scanned)?;
+        writeln!(f, "  files reindexed    {}", self.files_reindexed)?;
+        writeln!(f, "  chunks embedded    {}", self.chunks_embedded)?;
+        if self.cached {
+            writeln!(f, "  (results served from the query cache)")?;
+        } else {
+            writeln!(f, "  vector candidates  {} (fetch limit {})", self.vector_candidates, self.fetch_limit)?;
+            writeln!(f, "  text candidates    {}", self.text_candidates)?;
+            writeln!(f, "  fused              {} files", self.fused)?;
+            writeln!(f, "  above threshold    {}", self.above_threshold)?;
+            if let Some(after_grep) = self.after_grep {
+                writeln!(f, "  after --grep       {}", after_grep)?;
+            }
+        }
+        writeln!(f, "  returned           {}", self.returned)?;
+        writeln!(f, "Timings:")?;
+        for (stage, elapsed) in &self.stages {
+            writeln!(f, "  {:<18} {:.1}ms", stage, elapsed.as_secs_f64() * 1000.0)?;
+        }
+        let total: std::time::Duration = self.stages.iter().map(|(_, elapsed)| *elapsed).sum();
+        write!(f, "  {:<18} {:.1}ms", "total", total.as_secs_f64() * 1000.0)
+    }
+}
+
+/// Applies `update` to the plan being recorded, if any.
+fn record(options: &SearchOptions, update: impl FnOnce(&mut SearchPlan)) {
+    if let Some(plan) = &options.plan {
+        update(&mut plan.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+}
+
+
+/// Lines of each result's content to display, from CODE_SEARCH_SNIPPET_LINES
+/// (unset or 0 shows whole chunks).
+pub fn snippet_lines() -> Option<usize> {
+    std::env::var("CODE_SEARCH_SNIPPET_LINES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&n| n > 0)
+}
+
+/// Caps rayon's global pool (used for chunking files) at `threads`; 0 keeps
+/// one thread per core. Must run before the first parallel call.
+pub fn init_thread_pool(threads: usize) -> Result<()> {
+    if threads == 0 {
+        return Ok(());
+    }
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+        .map_err(|e| anyhow::anyhow!("Failed to configure {} worker threads: {}", threads, e))
+}
 
 pub struct Searcher {
-    model: EmbeddingModel,
+    model: Box<dyn Embedder>,
+    // Embeds prose files into their own table when set; otherwise `model` embeds everything
+    docs_model: Option<Box<dyn Embedder>>,
+    // One store per repository root, table and branch, shared by searches and watcher updates
+    stores: Mutex<HashMap<(PathBuf, String, String), Arc<VectorStore>>>,
+    // Text indexes by directory, kept open with their writer unless CODE_SEARCH_RELEASE_TEXT_INDEX is set
+    text_indexes: Mutex<HashMap<PathBuf, Arc<TextIndex>>>,
+    keep_text_indexes: bool,
+    // Central index location; None keeps the index in `<repo>/.code-search`
+    index_dir: Option<PathBuf>,
+    // Tables and text index of the logical index within that location
+    names: IndexNames,
+    // query_cache_key -> (table version, results); stale once the version moves on
+    query_cache: Mutex<HashMap<String, (u64, Vec<crate::store::SearchResult>)>>,
+    // (model id, query) -> query embedding; unlike results these don't depend on the index
+    query_embeddings: Mutex<lru::LruCache<(String, String), Vec<f32>>>,
+    // scan_cache_key -> (walk time, files); reused for the scan TTL unless the watcher saw a change
+    scan_cache: Mutex<HashMap<String, (std::time::Instant, Vec<FileEntry>)>>,
+    // Loaded by the first `rerank` query
+    reranker: std::sync::OnceLock<CrossEncoder>,
 }
 
 impl Searcher {
+    /// Uses the embeddings API configured by CODE_SEARCH_EMBEDDINGS_URL, or else
+    /// the local model (downloaded on first use). CODE_SEARCH_DOCS_MODEL adds a
+    /// local model for prose files.
     pub fn new() -> Result<Self> {
+        let model_load = |e: anyhow::Error| SearchError::ModelLoad(format!("{:#}", e));
+        let model: Box<dyn Embedder> = match ApiEmbedder::from_env().map_err(model_load)? {
+            Some(api) => Box::new(api),
+            None => Box::new(EmbeddingModel::new().map_err(model_load)?),
+        };
+        let docs_model = match configured_docs_model() {
+            Some(id) => Some(Box::new(EmbeddingModel::load(&id).map_err(model_load)?) as Box<dyn Embedder>),
+            None => None,
+        };
         Ok(Self {
-            model: EmbeddingModel::new()?,
+            model,
+            docs_model,
+            stores: Mutex::new(HashMap::new()),
+            text_indexes: Mutex::new(HashMap::new()),
+            keep_text_indexes: !env_flag("CODE_SEARCH_RELEASE_TEXT_INDEX"),
+            index_dir: default_index_dir(),
+            names: IndexNames::new(default_table().as_deref()),
+            query_cache: Mutex::new(HashMap::new()),
+            query_embeddings: Mutex::new(lru::LruCache::new(
+                std::num::NonZeroUsize::new(QUERY_EMBEDDING_ENTRIES).expect("cache size is non-zero"),
+            )),
+            scan_cache: Mutex::new(HashMap::new()),
+            reranker: std::sync::OnceLock::new(),
         })
     }
 
-    pub async fn search(&self, repo_path: &str, query: &str, max_lines: usize, exclude: Vec<String>, limit: usize) -> Result<Vec<crate::store::SearchResult>> {
-        let path = Path::new(repo_path);
-        if !path.exists() {
-            return Err(anyhow::anyhow!("Repository path not found: {}", repo_path));
+    /// Runs a throwaway embedding through each local model, so the first query
+    /// doesn't pay for first-use allocations (for long-lived sessions).
+    pub fn warm_up(&self) -> Result<()> {
+        self.model.warm_up()?;
+        if let Some(docs_model) = &self.docs_model {
+            docs_model.warm_up()?;
         }
+        Ok(())
+    }
+
+    /// Overrides the index location (and `CODE_SEARCH_INDEX_DIR`) when `Some`.
+    pub fn with_index_dir(mut self, index_dir: Option<PathBuf>) -> Self {
+        if index_dir.is_some() {
+            self.index_dir = index_dir;
+        }
+        self
+    }
+
+    /// Selects a named index (overriding `CODE_SEARCH_TABLE`) when `Some`, so several
+    /// logical indexes can share one index location.
+    pub fn with_table(mut self, table: Option<String>) -> Self {
+        if table.is_some() {
+            self.names = IndexNames::new(table.as_deref());
+        }
+        self
+    }
+
+    /// Where a repository's index lives: `<repo>/.code-search` by default, or a
+    /// per-repository directory under the configured index dir, named after the
+    /// repo and keyed by a hash of its canonical path.
+    pub fn index_path(&self, repo_root: &Path) -> PathBuf {
+        index_path_in(self.index_dir.as_deref(), repo_root)
+    }
+
+    /// Whether `file_path` is embedded by the docs model and stored in the docs table.
+    fn is_docs(&self, file_path: &str) -> bool {
+        self.docs_model.is_some() && is_prose(Path::new(file_path))
+    }
+
+    /// The model for the docs table (`docs`) or the code table.
+    fn embedder(&self, docs: bool) -> &dyn Embedder {
+        match &self.docs_model {
+            Some(model) if docs => model.as_ref(),
+            _ => self.model.as_ref(),
+        }
+    }
+
+    /// The index `options` select for a repository (see `IndexNames::for_branch`).
+    fn names_for(&self, repo_root: &Path, options: &SearchOptions) -> IndexNames {
+        self.names.clone().for_branch(repo_root, options.branch_aware)
+    }
+
+    /// The cross-encoder for `options.rerank`, downloaded and loaded on first use.
+    fn reranker(&self) -> Result<&CrossEncoder> {
+        if let Some(reranker) = self.reranker.get() {
+            return Ok(reranker);
+        }
+        let model_id = configured_rerank_model();
+        eprintln!("Loading reranker ({})...", model_id);
+        let reranker = CrossEncoder::load(&model_id).map_err(|e| SearchError::ModelLoad(format!("{:#}", e)))?;
+        Ok(self.reranker.get_or_init(|| reranker))
+    }
+
+    /// The code store, plus the docs store when a docs model is configured,
+    /// each tagged with whether it is the docs one.
+    async fn all_stores(&self, repo_root: &Path, options: &SearchOptions) -> Result<Vec<(bool, Arc<VectorStore>)>> {
+        let mut stores = vec![(false, self.store_for(repo_root, false, options).await?)];
+        if self.docs_model.is_some() {
+            stores.push((true, self.store_for(repo_root, true, options).await?));
+        }
+        Ok(stores)
+    }
 
-        let db_path = path.join(".code-search");
+    /// `all_stores` for indexing: tables with an older layout, or embedded with other
+    /// `SearchOptions::embed_settings`, are dropped first so they get rebuilt.
+    /// Read-only paths leave them alone and fail instead. Also returns whether any
+    /// table was dropped, for `write_index_manifest`.
+    async fn writable_stores(&self, repo_root: &Path, options: &SearchOptions) -> Result<(Vec<(bool, Arc<VectorStore>)>, bool)> {
+        let stores = self.all_stores(repo_root, options).await?;
+        let db_path = self.index_path(repo_root);
+        let mut recreated = false;
+        for (_, store) in &stores {
+            recreated |= store.prepare_for_writes(&db_path.to_string_lossy(), options.embed_settings()).await.map_err(SearchError::index)?;
+        }
+        Ok((stores, recreated))
+    }
+
+    /// Returns the docs (`docs`) or code store for a repository, opening it on first use.
+    async fn store_for(&self, repo_root: &Path, docs: bool, options: &SearchOptions) -> Result<Arc<VectorStore>> {
+        let names = self.names_for(repo_root, options);
+        self.open_store(repo_root, names.table(docs), &names.branch, docs).await
+    }
+
+    /// Lines per chunk for (re-)indexing: the size the index was built with, so an
+    /// index never mixes chunk sizes. `options.max_lines` only sizes a new index, and
+    /// `options.store_content_lines` likewise only applies to tables not created yet.
+    async fn chunk_max_lines(&self, repo_root: &Path, options: &SearchOptions) -> Result<usize> {
+        let stores = self.all_stores(repo_root, options).await?;
+        if let Some(requested) = options.store_content_lines {
+            let mut ignored = false;
+            for (_, store) in &stores {
+                match store.content_lines() {
+                    Some(built) => ignored |= built != requested,
+                    None if store.version().await?.is_some() => ignored = true,
+                    None => store.set_content_lines(requested),
+                }
+            }
+            if ignored {
+                eprintln!(
+                    "Warning: index was built with a different --store-content-lines; ignoring --store-content-lines {} (delete the index to rebuild it)",
+                    requested
+                );
+            }
+        }
+        let built = stores.iter().find_map(|(_, store)| store.max_lines());
+        if let (Some(built), Some(requested)) = (built, options.max_lines) {
+            if built != requested {
+                eprintln!(
+                    "Warning: index was built with --max-lines {}; ignoring --max-lines {} (delete the index to rechunk)",
+                    built, requested
+                );
+            }
+        }
+        let max_lines = built.or(options.max_lines).unwrap_or(DEFAULT_MAX_LINES);
+        for (_, store) in &stores {
+            store.set_max_lines(max_lines);
+        }
+        Ok(max_lines)
+    }
+
+    /// Rebuilds an index written with an older `INDEX_SCHEMA_VERSION` (deleting it
+    /// so this run indexes from scratch) and refuses one from a newer tool.
+    fn check_index_manifest(&self, repo_root: &Path) -> Result<()> {
+        let index_path = self.index_path(repo_root);
+        let Some(manifest) = read_index_manifest(&index_path) else {
+            return Ok(());
+        };
+        if manifest.schema_version > INDEX_SCHEMA_VERSION {
+            return Err(SearchError::IndexIncompatible(format!(
+                "Index at {} was built by code-search {} (index schema {}), which is newer than this version ({}, schema {}). \
+                 Upgrade, or delete the index directory to rebuild it.",
+                index_path.display(), manifest.tool_version, manifest.schema_version,
+                env!("CARGO_PKG_VERSION"), INDEX_SCHEMA_VERSION
+            )).into());
+        }
+        if manifest.schema_version < INDEX_SCHEMA_VERSION {
+            eprintln!(
+                "Index at {} was built by code-search {} (index schema {}); rebuilding it for schema {}. \
+                 Every file is embedded again, which can take a few minutes.",
+                index_path.display(), manifest.tool_version, manifest.schema_version, INDEX_SCHEMA_VERSION
+            );
+            self.drop_cached_handles(repo_root);
+            std::fs::remove_dir_all(&index_path)
+                .map_err(|e| anyhow::anyhow!("Cannot remove the old index at {}: {}", index_path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Records what built the index: when the manifest is missing, when the tables
+    /// were just `recreated`, or when it names another model or chunk size than the
+    /// tables now use (e.g. after they were deleted and rebuilt). Otherwise the
+    /// existing manifest, and its `created_at`, is kept.
+    fn write_index_manifest(&self, repo_root: &Path, max_lines: usize, recreated: bool) -> Result<()> {
+        let index_path = self.index_path(repo_root);
+        let manifest = IndexManifest {
+            schema_version: INDEX_SCHEMA_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            model: self.model.model_id().to_string(),
+            embedding_dim: self.model.dim()?,
+            docs_model: self.docs_model.as_ref().map(|model| model.model_id().to_string()),
+            max_lines,
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        };
+        if !recreated && read_index_manifest(&index_path).is_some_and(|existing| existing.describes_same_tables(&manifest)) {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&index_path)?;
+        std::fs::write(index_path.join(INDEX_MANIFEST_FILE), serde_json::to_string_pretty(&manifest)?)?;
+        Ok(())
+    }
+
+    /// Forgets the stores and text indexes opened for a repository, e.g. before its
+    /// index directory is deleted.
+    fn drop_cached_handles(&self, repo_root: &Path) {
+        let root = std::fs::canonicalize(repo_root).unwrap_or_else(|_| repo_root.to_path_buf());
+        self.stores.lock().unwrap_or_else(|e| e.into_inner()).retain(|(path, _, _), _| *path != root);
+        let index_path = self.index_path(repo_root);
+        self.text_indexes.lock().unwrap_or_else(|e| e.into_inner()).retain(|path, _| !path.starts_with(&index_path));
+    }
+
+    /// Opens `table` of a repository's index, scoped to `branch`'s rows, once and
+    /// shares it afterwards. Going through a single instance is what lets the store
+    /// serialize writes (only the checked-out branch is written to).
+    async fn open_store(&self, repo_root: &Path, table: &str, branch: &str, docs: bool) -> Result<Arc<VectorStore>> {
+        let root = std::fs::canonicalize(repo_root).unwrap_or_else(|_| repo_root.to_path_buf());
+        let key = (root, table.to_string(), branch.to_string());
+        let cached = self.stores.lock().unwrap_or_else(|e| e.into_inner()).get(&key).cloned();
+        if let Some(store) = cached {
+            return Ok(store);
+        }
+
+        let db_path = self.index_path(repo_root);
+        std::fs::create_dir_all(&db_path)?;
         let db_path_str = db_path.to_str()
             .ok_or_else(|| anyhow::anyhow!("Invalid unicode path: {:?}", db_path))?;
-        let store = VectorStore::new(db_path_str).await?;
+        let model = self.embedder(docs);
+        let dim = model.dim().map_err(|e| SearchError::ModelLoad(format!("{:#}", e)))?;
+        let store = VectorStore::new(db_path_str, table, model.model_id(), Some(dim)).await.map_err(|e| {
+            // Keep a model mismatch as-is, anything else means the index can't be opened
+            e.downcast::<SearchError>().unwrap_or_else(SearchError::index)
+        })?;
+        let store = Arc::new(store.with_branch(branch));
+
+        // Another caller may have raced us here; keep whichever got in first
+        let mut stores = self.stores.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(stores.entry(key).or_insert(store).clone())
+    }
+
+    /// Brings the index up to date, then runs the query against it.
+    pub async fn search(&self, repo_path: &str, query: &str, options: &SearchOptions) -> Result<Vec<crate::store::SearchResult>> {
+        self.update_index(repo_path, options).await?;
+        self.query_index(repo_path, query, options).await
+    }
+
+    /// Opens the repository's text index, or None when `options.text_index` is off.
+    /// The index and its writer are opened once and shared afterwards, like the stores;
+    /// with CODE_SEARCH_RELEASE_TEXT_INDEX each call opens its own, so the writer lock
+    /// is free for other processes in between.
+    fn open_text_index(&self, root: &Path, options: &SearchOptions) -> Result<Option<Arc<TextIndex>>> {
+        if !options.text_index {
+            return Ok(None);
+        }
+        let tantivy_path = self.index_path(root).join(self.names_for(root, options).text_index);
+        let cached = self.text_indexes.lock().unwrap_or_else(|e| e.into_inner()).get(&tantivy_path).cloned();
+        if let Some(text_index) = cached {
+            return Ok(Some(text_index));
+        }
+        let text_index = Arc::new(TextIndex::load_or_create(tantivy_path.to_str().unwrap()).map_err(SearchError::index)?);
+        if self.keep_text_indexes {
+            self.text_indexes.lock().unwrap_or_else(|e| e.into_inner())
+                .insert(tantivy_path, text_index.clone());
+        }
+        Ok(Some(text_index))
+    }
+
+    /// Removes a file's chunks from the text index, if there is one.
+    fn forget_text(&self, root: &Path, options: &SearchOptions, relative_paths: &[String]) -> Result<()> {
+        if let Some(text_index) = self.open_text_index(root, options)? {
+            text_index.delete_files(relative_paths);
+            text_index.save("")?;
+        }
+        Ok(())
+    }
+
+    /// Walks the repository, or reuses a walk with the same file selection from
+    /// the last few seconds so back-to-back queries don't re-walk large trees.
+    fn scan(&self, repo_path: &str, options: &SearchOptions) -> Vec<FileEntry> {
+        let key = scan_cache_key(Path::new(repo_path), options);
+        let ttl = scan_ttl();
+        {
+            let cache = self.scan_cache.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some((scanned_at, entries)) = cache.get(&key).filter(|(at, _)| at.elapsed() < ttl) {
+                eprintln!("Reusing scan of {} from {:.1}s ago ({} files).", repo_path, scanned_at.elapsed().as_secs_f32(), entries.len());
+                return entries.clone();
+            }
+        }
 
-        // 1. Scan Repository (Metadata only)
         eprintln!("Scanning repository: {}", repo_path);
         
         let (tx, rx) = crossbeam_channel::unbounded();
         let repo_path_owned = repo_path.to_string();
-        let exclude_owned = exclude.clone();
-        
-        let repo_path_for_scan = repo_path_owned.clone();
+        let exclude_owned = options.exclude.clone();
+        let threads = options.threads;
+        let filter = options.file_filter();
+        // An external index isn't in the repo, so there's nothing to gitignore
+        let update_gitignore = options.update_gitignore && self.index_dir.is_none();
         
         std::thread::spawn(move || {
-            scan_repository(&repo_path_for_scan, tx, exclude_owned);
+            scan_repository(&repo_path_owned, tx, exclude_owned, update_gitignore, threads, filter);
         });
         
-        // Collect all file entries
-        let current_entries: Vec<FileEntry> = rx.iter().collect();
+        let current_entries = unique_entries(rx.iter());
         eprintln!("Found {} files in repository.", current_entries.len());
 
+        if !ttl.is_zero() {
+            let mut cache = self.scan_cache.lock().unwrap_or_else(|e| e.into_inner());
+            cache.retain(|_, (at, _)| at.elapsed() < ttl);
+            cache.insert(key, (std::time::Instant::now(), current_entries.clone()));
+        }
+        current_entries
+    }
+
+    /// Re-indexes files added, changed or removed since the last run (mtime diff).
+    pub async fn update_index(&self, repo_path: &str, options: &SearchOptions) -> Result<()> {
+        let repo_path_owned = repo_path.to_string();
+        let path = Path::new(repo_path);
+        if !path.exists() {
+            return Err(SearchError::PathNotFound(repo_path.to_string()).into());
+        }
+
+        // 1. Scan Repository (Metadata only)
+        let scan_started = std::time::Instant::now();
+        let current_entries = self.scan(repo_path, options);
+        record(options, |plan| {
+            plan.files_scanned = current_entries.len();
+            plan.stages.push(("scan", scan_started.elapsed()));
+        });
+        let index_started = std::time::Instant::now();
+        let seen_files_in_scan: HashSet<String> = current_entries.iter().map(|e| e.path.clone()).collect();
+        if current_entries.is_empty() {
+            return Err(SearchError::NoIndexableFiles(repo_path.to_string()).into());
+        }
+
+        let index_path = self.index_path(path);
+        if !index_path.exists() {
+            eprintln!(
+                "No index at {} yet; building it now. The first run embeds every file and can take a few minutes; \
+                 later searches only re-index files that changed.",
+                index_path.display()
+            );
+        }
+        self.check_index_manifest(path)?;
+        let (stores, recreated) = self.writable_stores(path, options).await?;
+        let max_lines = self.chunk_max_lines(path, options).await?;
+        self.write_index_manifest(path, max_lines, recreated)?;
+
         // 2. Fetch Existing Index Metadata
-        let indexed_metadata = store.get_indexed_metadata().await?;
+        let mut indexed_metadata = HashMap::new();
+        for (docs, store) in &stores {
+            let (kept, misplaced): (Vec<_>, Vec<_>) = store.get_indexed_metadata().await
+                .map_err(SearchError::index)?
+                .into_iter()
+                .partition(|(file, _)| self.is_docs(file) == *docs);
+            // Prose indexed before a docs model was configured moves to the docs table
+            if !misplaced.is_empty() {
+                eprintln!("Moving {} files to the {} table...", misplaced.len(), if *docs { "code" } else { "docs" });
+                let files: Vec<String> = misplaced.into_iter().map(|(file, _)| file).collect();
+                store.delete_files(&files).await.map_err(SearchError::index)?;
+            }
+            indexed_metadata.extend(kept);
+        }
         eprintln!("Found {} files in existing index.", indexed_metadata.len());
 
         // 3. Compute Diffs
         let mut files_to_reindex = Vec::new();
-        let mut seen_files_in_scan = HashSet::new();
 
         // Check for modifications/additions
         for entry in &current_entries {
-            seen_files_in_scan.insert(entry.path.clone());
-            
             if let Some(&indexed_mtime) = indexed_metadata.get(&entry.path) {
                 // If mtime changed (newer OR older), re-index.
                 if entry.mtime != indexed_mtime {
@@ -77,77 +695,424 @@ impl Searcher {
             }
         }
 
-        // 4. Handle Deletions
+        // 4. Detect renames: a new file with the same content (and extension, so it
+        // chunks the same) as a removed one takes over its rows instead of being re-embedded.
+        // Not with the header, which embeds the old path
+        let mut renamed = Vec::new();
+        if !files_to_remove.is_empty() && !options.embed_header {
+            let mut removed_by_hash: HashMap<u64, String> = HashMap::new();
+            for (_, store) in &stores {
+                let hashes = store.file_hashes(&files_to_remove).await.map_err(SearchError::index)?;
+                removed_by_hash.extend(hashes.into_iter().map(|(path, hash)| (hash, path)));
+            }
+            files_to_reindex.retain(|entry| {
+                if removed_by_hash.is_empty() || indexed_metadata.contains_key(&entry.path) {
+                    return true;
+                }
+                let Ok(bytes) = std::fs::read(path.join(&entry.path)) else { return true };
+                let hash = content_hash(&bytes);
+                let same_ext = |old: &String| Path::new(old).extension() == Path::new(&entry.path).extension();
+                if !removed_by_hash.get(&hash).is_some_and(same_ext) {
+                    return true;
+                }
+                renamed.push((removed_by_hash.remove(&hash).unwrap(), *entry));
+                false
+            });
+            files_to_remove.retain(|removed| !renamed.iter().any(|(old, _)| old == removed));
+        }
+
+        // 5. After a branch switch, files another branch indexed with the same content
+        // take over its rows (vectors included) instead of being re-embedded
+        let mut adopted = Vec::new();
+        if !self.names_for(path, options).branch.is_empty() && !files_to_reindex.is_empty() {
+            let mut wanted: [HashMap<String, (u64, u64)>; 2] = Default::default();
+            for entry in &files_to_reindex {
+                if let Ok(bytes) = std::fs::read(path.join(&entry.path)) {
+                    wanted[self.is_docs(&entry.path) as usize]
+                        .insert(entry.path.clone(), (content_hash(&bytes), entry.mtime));
+                }
+            }
+            let mut reused = HashSet::new();
+            for (docs, store) in &stores {
+                let files = store.adopt_from_other_branches(&wanted[*docs as usize]).await.map_err(SearchError::index)?;
+                reused.extend(files);
+            }
+            if !reused.is_empty() {
+                eprintln!("Reused {} files indexed on other branches.", reused.len());
+            }
+            files_to_reindex.retain(|entry| {
+                if !reused.contains(&entry.path) {
+                    return true;
+                }
+                adopted.push(*entry);
+                false
+            });
+        }
+
+        record(options, |plan| plan.files_reindexed = files_to_reindex.len());
+
+        if !renamed.is_empty() {
+            eprintln!("Moving {} renamed files in the index...", renamed.len());
+            for (old, entry) in &renamed {
+                // Same extension, so old and new paths live in the same table
+                let store = self.store_for(path, self.is_docs(old), options).await?;
+                store.rename_file(old, &entry.path, entry.mtime).await.map_err(SearchError::index)?;
+            }
+        }
+
+        // The text index has no vectors to keep, so renamed files are simply indexed
+        // under their new path, and files reused from another branch like new ones. An empty one (new, or dropped on a schema change) is
+        // refilled from the files the vector store already holds.
+        let text_index = self.open_text_index(path, options)?;
+        if let Some(text_index) = &text_index {
+            let mut text_only: Vec<&FileEntry> = renamed.iter().map(|(_, entry)| *entry).collect();
+            text_only.extend(&adopted);
+            if text_index.num_docs() == 0 {
+                let pending: HashSet<&str> = files_to_reindex.iter().map(|e| e.path.as_str()).collect();
+                let backfill: Vec<&FileEntry> = current_entries.iter()
+                    .filter(|e| indexed_metadata.contains_key(&e.path) && !pending.contains(e.path.as_str()))
+                    .collect();
+                if !backfill.is_empty() {
+                    eprintln!("Rebuilding the text index for {} files...", backfill.len());
+                }
+                text_only.extend(backfill);
+            }
+            let chunks: Vec<FileChunk> = text_only.par_iter()
+                .filter_map(|entry| process_file(&path.join(&entry.path), repo_path, max_lines).ok())
+                .flatten()
+                .collect();
+            text_index.index_chunks(&chunks)?;
+            let moved_away: Vec<String> = renamed.iter().map(|(old, _)| old.clone()).collect();
+            text_index.delete_files(&moved_away);
+            text_index.delete_files(&files_to_remove);
+            text_index.save("")?;
+        }
+
+        // 5. Handle Deletions
         if !files_to_remove.is_empty() {
              eprintln!("Removing {} deleted files from index...", files_to_remove.len());
-             store.delete_files(&files_to_remove).await?;
+             for (docs, store) in &stores {
+                 let in_store: Vec<String> = files_to_remove.iter().filter(|p| self.is_docs(p) == *docs).cloned().collect();
+                 store.delete_files(&in_store).await.map_err(SearchError::index)?;
+             }
         }
 
-        // 5. Handle Upserts (Re-indexing)
+        // 6. Handle Upserts (Re-indexing)
         if !files_to_reindex.is_empty() {
             eprintln!("Re-indexing {} files...", files_to_reindex.len());
-            
-            // Parallel processing of files to generate chunks
-            let chunks_to_upsert: Vec<FileChunk> = files_to_reindex.par_iter()
-                .filter_map(|entry| {
-                     let full_path = Path::new(&repo_path_owned).join(&entry.path); // Use repo_path_owned
-                     process_file(&full_path, &repo_path_owned, max_lines).ok()
-                })
-                .flatten()
-                .collect();
 
-            if !chunks_to_upsert.is_empty() {
-                eprintln!("Generated {} chunks from {} files.", chunks_to_upsert.len(), files_to_reindex.len());
-                
-                let texts: Vec<String> = chunks_to_upsert.iter().map(|c| c.content.clone()).collect();
-                
-                 // Batch embedding
-                 let mut all_embeddings = Vec::new();
-                 let total_chunks = texts.len();
-                 let mut processed = 0;
-                 eprintln!("Generating embeddings for {} chunks...", total_chunks);
-                 
-                 for chunk_batch in texts.chunks(32) {
-                     let embeddings = self.model.embed_batch(chunk_batch)?;
-                     all_embeddings.extend(embeddings);
-                     processed += chunk_batch.len();
-                     if processed % 320 == 0 || processed == total_chunks {
-                        eprintln!("Processed {}/{} chunks...", processed, total_chunks);
-                     }
-                 }
-                 
-                 store.upsert(&chunks_to_upsert, &all_embeddings).await?;
-                 
-                 // Update Text Index
-                 let tantivy_path = path.join(".code-search/text_index");
-                 let text_index = TextIndex::load_or_create(tantivy_path.to_str().unwrap())?;
-                 
-                 for chunk in &chunks_to_upsert {
-                     let _ = text_index.index_text(&chunk.file_path, &chunk.content);
-                 }
-                 text_index.save("")?; // Path ignored
+            // Commit every `batch_files` files so a long first index is queryable as it
+            // goes and a crash only loses the current batch: committed files carry their
+            // mtime in the store, so the next run's diff skips them.
+            let batch_files = commit_batch_files();
+            let total_files = files_to_reindex.len();
+            let mut files_done = 0;
+
+            // Marks a run in progress; left behind if we are interrupted or crash
+            let progress_path = self.index_path(path).join(self.names_for(path, options).progress);
+            if let Some(previous) = read_progress(&progress_path) {
+                eprintln!(
+                    "Resuming interrupted indexing run ({}/{} files were committed; {} left to index).",
+                    previous.committed_files, previous.total_files, total_files
+                );
+            }
+            write_progress(&progress_path, &IndexProgress { total_files, committed_files: 0 });
+
+            for file_batch in files_to_reindex.chunks(batch_files) {
+                if options.interrupt.as_ref().is_some_and(|flag| flag.load(Ordering::SeqCst)) {
+                    return Err(anyhow::anyhow!(
+                        "Indexing interrupted after committing {}/{} files; run the search again to resume",
+                        files_done, total_files
+                    ));
+                }
+
+                // Parallel processing of files to generate chunks
+                let chunks_to_upsert: Vec<FileChunk> = file_batch.par_iter()
+                    .filter_map(|entry| {
+                         let full_path = Path::new(&repo_path_owned).join(&entry.path); // Use repo_path_owned
+                         process_file(&full_path, &repo_path_owned, max_lines).ok()
+                    })
+                    .flatten()
+                    .collect();
+                files_done += file_batch.len();
+
+                if chunks_to_upsert.is_empty() {
+                    continue;
+                }
+                eprintln!("Generated {} chunks from {} files.", chunks_to_upsert.len(), file_batch.len());
+                self.commit_chunks(path, chunks_to_upsert, text_index.as_deref(), options).await?;
+                eprintln!("Committed {}/{} files.", files_done, total_files);
+                write_progress(&progress_path, &IndexProgress { total_files, committed_files: files_done });
             }
+            let _ = std::fs::remove_file(&progress_path);
         } else {
             eprintln!("Index is up to date. Skipping embedding.");
         }
         
-        // Cleanup old versions (optimization)
-        let _ = store.cleanup().await;
+        // Cleanup old versions (optimization), once enough writes piled up
+        for (_, store) in &stores {
+            if options.cleanup && store.cleanup_due().await.unwrap_or(false) {
+                let _ = store.cleanup().await;
+            }
+        }
+        record(options, |plan| plan.stages.push(("index", index_started.elapsed())));
+        Ok(())
+    }
+
+    /// Embeds whole files' chunks and writes them to the text index and their tables.
+    async fn commit_chunks(&self, root: &Path, chunks: Vec<FileChunk>, text_index: Option<&TextIndex>, options: &SearchOptions) -> Result<()> {
+        // Each table's chunks go through its own model
+        let (docs_chunks, code_chunks): (Vec<FileChunk>, Vec<FileChunk>) = chunks.into_iter()
+            .partition(|c| self.is_docs(&c.file_path));
+        let mut embedded = Vec::new();
+        for (docs, chunks) in [(false, code_chunks), (true, docs_chunks)] {
+            if !chunks.is_empty() {
+                let embeddings = self.embed_chunks(self.embedder(docs), &chunks, options)?;
+                embedded.push((docs, chunks, embeddings));
+            }
+        }
+
+        // Text index first: if we stop before the vector upsert these files
+        // are simply re-indexed next time (index_chunks replaces by path)
+        if let Some(text_index) = text_index {
+            for (_, chunks, _) in &embedded {
+                text_index.index_chunks(chunks)?;
+            }
+            text_index.save("")?; // Path ignored
+        }
+
+        for (docs, chunks, embeddings) in &embedded {
+            let store = self.store_for(root, *docs, options).await?;
+            store.upsert(chunks, embeddings).await.map_err(SearchError::index)?;
+        }
+        Ok(())
+    }
+
+    /// Indexes the files of a tar stream (gzipped or not) as the contents of `repo_path`,
+    /// without extracting them: entries pass the same file filter as a scan, are chunked
+    /// from memory and stamped with their archive mtimes. Files already indexed are
+    /// replaced, others are left alone. Returns the number of chunks indexed.
+    pub async fn index_archive(&self, repo_path: &str, archive: impl std::io::Read, strip_components: usize, options: &SearchOptions) -> Result<usize> {
+        use std::io::{BufRead, Read};
+
+        let path = Path::new(repo_path);
+        let mut reader = std::io::BufReader::new(archive);
+        let gzipped = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+        let reader: Box<dyn Read> = if gzipped {
+            Box::new(flate2::read::GzDecoder::new(reader))
+        } else {
+            Box::new(reader)
+        };
+        let mut archive = tar::Archive::new(reader);
+
+        let filter = options.file_filter();
+        self.check_index_manifest(path)?;
+        let (_, recreated) = self.writable_stores(path, options).await?;
+        let max_lines = self.chunk_max_lines(path, options).await?;
+        self.write_index_manifest(path, max_lines, recreated)?;
+        let text_index = self.open_text_index(path, options)?;
+        let batch_files = commit_batch_files();
+        let mut batch = Vec::new();
+        let mut batch_len = 0;
+        let mut files = 0;
+        let mut indexed = 0;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            // `git archive --prefix` and release tarballs wrap everything in a top directory
+            let relative: PathBuf = entry.path()?.components()
+                .filter(|c| !matches!(c, std::path::Component::CurDir))
+                .skip(strip_components)
+                .collect();
+            if relative.as_os_str().is_empty() || filter.in_skipped_dir(&relative, Path::new("")) {
+                continue;
+            }
+            let mut content = String::new();
+            if entry.read_to_string(&mut content).is_err() {
+                continue; // Not UTF-8, like the files process_file can't read
+            }
+            // The path is inside the archive, so extensionless files are judged by the entry's own shebang
+            if !filter.should_process_entry(&relative, content.lines().next().unwrap_or("")) {
+                continue;
+            }
+            let mtime = entry.header().mtime().unwrap_or(0);
+            let relative_path = relative.to_string_lossy().to_string();
+            let chunks = match process_content(&relative, &content, &relative_path, mtime, max_lines) {
+                Ok(chunks) => chunks,
+                Err(e) => {
+                    eprintln!("Skipping {}: {}", relative_path, e);
+                    continue;
+                }
+            };
+            indexed += chunks.len();
+            batch.extend(chunks);
+            batch_len += 1;
+            files += 1;
+            if batch_len >= batch_files {
+                self.commit_chunks(path, std::mem::take(&mut batch), text_index.as_deref(), options).await?;
+                batch_len = 0;
+                eprintln!("Committed {} files.", files);
+            }
+        }
+        if !batch.is_empty() {
+            self.commit_chunks(path, batch, text_index.as_deref(), options).await?;
+        }
+        eprintln!("Indexed {} files from the archive.", files);
+        Ok(indexed)
+    }
+
+    /// Embeds chunk contents with `model`, `batch_size()` at a time.
+    fn embed_chunks(&self, model: &dyn Embedder, chunks: &[FileChunk], options: &SearchOptions) -> Result<Vec<Vec<f32>>> {
+        let texts = embedding_texts(chunks, options);
+        record(options, |plan| plan.chunks_embedded += texts.len());
+        let mut all_embeddings = Vec::new();
+        let total_chunks = texts.len();
+        let mut processed = 0;
+        eprintln!("Generating embeddings for {} chunks ({})...", total_chunks, model.model_id());
+
+        for chunk_batch in texts.chunks(model.batch_size()) {
+            let embeddings = model.embed_batch(chunk_batch)?;
+            all_embeddings.extend(embeddings);
+            processed += chunk_batch.len();
+            if processed % 320 == 0 || processed == total_chunks {
+               eprintln!("Processed {}/{} chunks...", processed, total_chunks);
+            }
+        }
+        Ok(all_embeddings)
+    }
+
+    /// Embeds `queries` with the code or docs model, reusing embeddings of queries
+    /// seen before. Entries are keyed by model id, so another model never gets them.
+    fn embed_queries(&self, docs: bool, queries: &[String]) -> Result<Vec<Vec<f32>>> {
+        let embedder = self.embedder(docs);
+        let model_id = embedder.model_id().to_string();
+        let mut embeddings: Vec<Option<Vec<f32>>> = {
+            let mut cache = self.query_embeddings.lock().unwrap_or_else(|e| e.into_inner());
+            queries.iter().map(|query| cache.get(&(model_id.clone(), query.clone())).cloned()).collect()
+        };
+        let missing: Vec<String> = queries.iter().zip(&embeddings)
+            .filter(|(_, embedding)| embedding.is_none())
+            .map(|(query, _)| query.clone())
+            .collect();
+        if !missing.is_empty() {
+            let mut fresh = embedder.embed_batch(&missing)?.into_iter();
+            let mut cache = self.query_embeddings.lock().unwrap_or_else(|e| e.into_inner());
+            for (query, slot) in queries.iter().zip(&mut embeddings).filter(|(_, slot)| slot.is_none()) {
+                let embedding = fresh.next().ok_or_else(|| anyhow::anyhow!("Embedder returned fewer vectors than queries"))?;
+                cache.put((model_id.clone(), query.clone()), embedding.clone());
+                *slot = Some(embedding);
+            }
+        }
+        Ok(embeddings.into_iter().flatten().collect())
+    }
+
+    /// Searches the index as it is, without scanning the repository for changes.
+    pub async fn query_index(&self, repo_path: &str, query: &str, options: &SearchOptions) -> Result<Vec<crate::store::SearchResult>> {
+        let limit = options.limit;
+        let path = Path::new(repo_path);
+        if !path.exists() {
+            return Err(SearchError::PathNotFound(repo_path.to_string()).into());
+        }
+
+        let stores = self.all_stores(path, options).await?;
+        let db_path = self.index_path(path);
+        for (_, store) in &stores {
+            store.ensure_current(&db_path.to_string_lossy())?;
+        }
+
+        // Every upsert/delete bumps a table's version, which invalidates the cached results.
+        // Versions only grow, so their sum moves whenever any table changes.
+        // `since` is relative to now, so the same options select different files later on
+        let cache_key = if options.cache && options.since.is_none() {
+            let mut version = None;
+            for (_, store) in &stores {
+                if let Some(v) = store.version().await.map_err(SearchError::index)? {
+                    version = Some(version.unwrap_or(0) + v);
+                }
+            }
+            let names = self.names_for(path, options);
+            let key = format!("{}:{}:{}", names.code, names.branch, query_cache_key(path, query, options));
+            version.map(|version| (key, version))
+        } else {
+            None
+        };
+        if let Some((key, version)) = &cache_key {
+            let cache = self.query_cache.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some((_, results)) = cache.get(key).filter(|(v, _)| v == version) {
+                record(options, |plan| {
+                    plan.cached = true;
+                    plan.returned = results.len();
+                });
+                return Ok(results.clone());
+            }
+        }
 
         // 6. Search (Hybrid: Recall + Rerank)
         // Load Text Index
-        let tantivy_path = path.join(".code-search/text_index");
-        let text_index = TextIndex::load_or_create(tantivy_path.to_str().unwrap())?;
+        let text_index = self.open_text_index(path, options)?;
         
         // Vector Search
-        let fetch_limit = std::cmp::max(limit * 3, 50);
-        let query_embedding = self.model.embed_batch(&[query.to_string()])?;
-        let vector_results = store.search(&query_embedding[0], fetch_limit).await?;
+        // More candidates give the reranker more to work with, at the cost of latency
+        let fetch_limit = std::cmp::max(limit * options.recall_multiplier.max(1), options.recall_floor);
+        let min_mtime = options.since.map(|since| {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            now.saturating_sub(since.as_secs())
+        });
+        let file = options.file.as_deref().map(|file| repo_relative(path, file));
+        let queries: Vec<String> = std::iter::once(query.to_string()).chain(options.extra_queries.iter().cloned()).collect();
+        let mut per_query = vec![Vec::new(); queries.len()];
+        let (mut embed_time, mut search_time) = (std::time::Duration::ZERO, std::time::Duration::ZERO);
+        for (docs, store) in &stores {
+            let started = std::time::Instant::now();
+            let query_embeddings = self.embed_queries(*docs, &queries)?;
+            embed_time += started.elapsed();
+            let started = std::time::Instant::now();
+            for (results, query_embedding) in per_query.iter_mut().zip(&query_embeddings) {
+                let found = store.search(
+                    query_embedding, fetch_limit, &options.kinds, min_mtime, file.as_deref(),
+                    options.with_vectors, options.all_branches,
+                ).await;
+                results.extend(found.map_err(SearchError::index)?);
+            }
+            search_time += started.elapsed();
+        }
+        record(options, |plan| {
+            plan.fetch_limit = fetch_limit;
+            plan.stages.push(("embed query", embed_time));
+            plan.stages.push(("vector search", search_time));
+        });
+        if stores.len() > 1 || options.all_branches {
+            for results in &mut per_query {
+                // Both models score by cosine similarity of normalized vectors, so the
+                // code and docs candidates are merged on it
+                results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+                // A chunk unchanged between branches has a row per branch
+                let mut seen = HashSet::new();
+                results.retain(|r| seen.insert((r.file_path.clone(), r.line_start, r.line_end)));
+                results.truncate(fetch_limit);
+            }
+        }
+        let vector_results = fuse_queries(per_query, options.rrf_k, fetch_limit);
         
-        // Text Search
-        let text_results = text_index.search(query);
+        // Text Search (synonyms only widen the lexical side; the embedding already generalizes).
+        // Text ranks are per file, so within a single file they can't reorder anything
+        let text_started = std::time::Instant::now();
+        let text_results = match &text_index {
+            _ if file.is_some() => Vec::new(),
+            None => Vec::new(),
+            Some(text_index) => interleave(queries.iter()
+                .map(|query| text_index.search(&keyword_query(query, options), options.match_all))
+                .collect()),
+        };
+        record(options, |plan| plan.stages.push(("text search", text_started.elapsed())));
+        let rank_started = std::time::Instant::now();
         
-        // RRF Fusion
+        // RRF Fusion; without text results (text index disabled, or a `--file`
+        // search) no candidate gets a text boost and the vector order stands
         // Map: FilePath -> (VectorRank, TextRank)
         let mut rankings: HashMap<String, (Option<usize>, Option<usize>)> = HashMap::new();
         
@@ -158,14 +1123,14 @@ impl Searcher {
                 .or_insert((Some(rank), None));
         }
         
-        // Text Ranks
+        // Text Ranks (a file's best chunk counts; later hits of the same file rank lower)
         for (rank, (path, _score)) in text_results.iter().enumerate() {
              rankings.entry(path.clone())
-                .and_modify(|e| e.1 = Some(rank))
+                .and_modify(|e| { e.1.get_or_insert(rank); })
                 .or_insert((None, Some(rank)));
         }
         
-        let k = 60.0;
+        let k = options.rrf_k;
         let mut fused_scores: Vec<(String, f32)> = rankings.iter().map(|(path, (r_vec, r_text))| {
             let score_vec = if let Some(r) = r_vec { 1.0 / (k + *r as f32) } else { 0.0 };
             let score_text = if let Some(r) = r_text { 1.0 / (k + *r as f32) } else { 0.0 };
@@ -194,44 +1159,115 @@ impl Searcher {
         // Let's stick to: RRF re-ranking of the retrieved candidates from Vector Store.
         // We used `fetch_limit` (limit * 3).
         
+        let recalled = vector_results.len();
         let mut candidates = vector_results;
+        // Scoring, --grep and the reranker all look at the content
+        if let Some(content_lines) = stores.iter().find_map(|(_, store)| store.content_lines()) {
+            let max_lines = stores.iter().find_map(|(_, store)| store.max_lines()).unwrap_or(DEFAULT_MAX_LINES);
+            load_full_content(path, &mut candidates, content_lines, max_lines);
+        }
         
         for candidate in &mut candidates {
             // Check text rank
             if let Some((_, Some(text_rank))) = rankings.get(&candidate.file_path) {
                 // Boost score based on text rank
-                // Simple additive boost? Or replace score with RRF?
-                // Let's add RRF component to the existing score?
-                // Existing score: 0.0-1.0.
-                // RRF score: ~0.03 max.
-                // Let's scale RRF.
                  let rrf_boost = 1.0 / (k + *text_rank as f32);
-                 candidate.score += rrf_boost * 10.0; // Significant boost
+                 candidate.score += rrf_boost * RRF_SCALE;
             }
         }
         
-        // Rerank: Apply keyword boost (existing logic)
-        let query_lower = query.to_lowercase();
+        // Rerank: keyword boost proportional to the share of query words in the chunk,
+        // so "parse json" still helps a chunk that has both words apart. With several
+        // queries, the one the chunk matches best counts
+        let query_tokens: Vec<HashSet<String>> = queries.iter()
+            .map(|query| tokenize(query).collect::<HashSet<String>>())
+            .filter(|tokens| !tokens.is_empty())
+            .collect();
         
-        for candidate in &mut candidates {
-            if candidate.content.to_lowercase().contains(&query_lower) {
-                candidate.score += 0.1;
+        if !query_tokens.is_empty() {
+            for candidate in &mut candidates {
+                candidate.score += options.keyword_boost * keyword_share(&query_tokens, &candidate.content);
             }
         }
         
         // Filter low scores
         candidates.retain(|c| c.score > 0.01);
+        let above_threshold = candidates.len();
+
+        // Exact post-filter on top of the fuzzy recall
+        if let Some(re) = &options.grep {
+            candidates.retain(|c| re.is_match(&c.content));
+        }
+
+        record(options, |plan| {
+            plan.vector_candidates = recalled;
+            plan.text_candidates = text_results.len();
+            plan.fused = rankings.len();
+            plan.above_threshold = above_threshold;
+            plan.after_grep = options.grep.is_some().then_some(candidates.len());
+            plan.stages.push(("fuse and score", rank_started.elapsed()));
+        });
+
+        // Second stage: the cross-encoder's relevance replaces the fused score
+        if options.rerank && !candidates.is_empty() {
+            let rerank_started = std::time::Instant::now();
+            let passages: Vec<&str> = candidates.iter().map(|c| c.content.as_str()).collect();
+            let reranker = self.reranker()?;
+            // A chunk only has to be relevant to one of the queries
+            let mut scores = vec![f32::MIN; passages.len()];
+            for query in &queries {
+                for (best, score) in scores.iter_mut().zip(reranker.score(query, &passages)?) {
+                    *best = best.max(score);
+                }
+            }
+            for (candidate, score) in candidates.iter_mut().zip(scores) {
+                candidate.score = score;
+            }
+            record(options, |plan| plan.stages.push(("rerank", rerank_started.elapsed())));
+        }
+        let select_started = std::time::Instant::now();
+
+        if candidates.is_empty() {
+            // Tell "index not built" apart from "nothing matched"
+            let mut chunks = 0;
+            for (_, store) in &stores {
+                chunks += store.count_chunks().await.map_err(SearchError::index)?;
+            }
+            if chunks == 0 {
+                eprintln!("No results: the index is empty (no indexable files under {}?)", repo_path);
+            } else {
+                let mut why = format!(
+                    "No results: the index holds {} chunks; vector search returned {} candidates{}, {} scored below the 0.01 threshold",
+                    chunks,
+                    recalled,
+                    if options.kinds.is_empty() { String::new() } else { format!(" of kind {}", options.kinds.join("/")) }
+                        + &options.since.map_or(String::new(), |since| format!(" modified in the last {}s", since.as_secs()))
+                        + &file.as_ref().map_or(String::new(), |file| format!(" in {}", file)),
+                    recalled - above_threshold
+                );
+                if options.grep.is_some() {
+                    why.push_str(&format!(", {} didn't match --grep", above_threshold));
+                }
+                eprintln!("{}", why);
+            }
+        }
 
         // Sort by new score (descending)
         candidates.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+
+        // Sub-chunks of one split symbol expand to the same span; keep the best-scoring one
+        if options.whole_symbol {
+            let mut seen_symbols = HashSet::new();
+            candidates.retain(|c| seen_symbols.insert((c.file_path.clone(), c.symbol_start, c.symbol_end)));
+        }
         
-        // Diversity: Limit chunks per file (Max 3)
+        // Diversity: Limit chunks per file (Max 3), unless the search is scoped to one file
         let mut file_counts = std::collections::HashMap::new();
         let mut diverse_candidates = Vec::new();
-        
+        let per_file = if file.is_some() { usize::MAX } else { 3 };
         for candidate in candidates {
             let count = file_counts.entry(candidate.file_path.clone()).or_insert(0);
-            if *count < 3 {
+            if *count < per_file {
                 diverse_candidates.push(candidate);
                 *count += 1;
             }
@@ -240,62 +1276,81 @@ impl Searcher {
             }
         }
         
+        if options.whole_symbol {
+            for candidate in &mut diverse_candidates {
+                expand_to_symbol(path, candidate);
+            }
+        }
+
+        if let Some((key, version)) = cache_key {
+            let mut cache = self.query_cache.lock().unwrap_or_else(|e| e.into_inner());
+            if cache.len() >= QUERY_CACHE_ENTRIES {
+                // Entries for older versions can never hit again; drop them first
+                cache.retain(|_, (v, _)| *v == version);
+                if cache.len() >= QUERY_CACHE_ENTRIES {
+                    cache.clear();
+                }
+            }
+            cache.insert(key, (version, diverse_candidates.clone()));
+        }
+        record(options, |plan| {
+            plan.returned = diverse_candidates.len();
+            plan.stages.push(("select", select_started.elapsed()));
+        });
+        
         Ok(diverse_candidates)
     }
 
-    pub async fn index_file(&self, path: &Path, root: &str, max_lines: usize) -> Result<()> {
+    pub async fn index_file(&self, path: &Path, root: &str, options: &SearchOptions) -> Result<()> {
          eprintln!("Indexing updated file: {:?}", path);
-         let db_path = Path::new(root).join(".code-search");
-         let db_path_str = db_path.to_str()
-             .ok_or_else(|| anyhow::anyhow!("Invalid unicode path: {:?}", db_path))?;
-         
-         // Note: Opening store for every file event is not ideal for high throughput,
-         // but fine for interactive editing (watch mode).
-         let store = VectorStore::new(db_path_str).await?;
-
+         // A cached walk may predate this change (or miss a new file)
+         self.scan_cache.lock().unwrap_or_else(|e| e.into_inner()).clear();
          let relative_path = pathdiff::diff_paths(path, root)
             .unwrap_or(path.to_path_buf())
             .to_string_lossy()
             .to_string();
+         let docs = self.is_docs(&relative_path);
+         let store = self.store_for(Path::new(root), docs, options).await?;
+         let recreated = store.prepare_for_writes(&self.index_path(Path::new(root)).to_string_lossy(), options.embed_settings()).await?;
 
          if !path.exists() {
              eprintln!("File deleted: {}", relative_path);
+             self.forget_text(Path::new(root), options, std::slice::from_ref(&relative_path))?;
              store.delete_files(&[relative_path]).await?;
              return Ok(());
          }
          
          // Only process if it is a supported code file
-         if !crate::scanner::should_process_file(path) {
+         if !options.file_filter().should_process_file(path) {
              return Ok(());
          }
 
          // Process file
+         let max_lines = self.chunk_max_lines(Path::new(root), options).await?;
+         self.write_index_manifest(Path::new(root), max_lines, recreated)?;
          match process_file(path, root, max_lines) {
              Ok(chunks) => {
                  if chunks.is_empty() {
                      // Empty file or no code
                      // Should we delete it if it existed? Yes.
+                     self.forget_text(Path::new(root), options, std::slice::from_ref(&relative_path))?;
                      store.delete_files(&[relative_path]).await?;
                      return Ok(());
                  }
                  
-                 let texts: Vec<String> = chunks.iter().map(|c| c.content.clone()).collect();
-                 let embeddings = self.model.embed_batch(&texts)?;
+                 let texts = embedding_texts(&chunks, options);
+                 let embeddings = self.embedder(docs).embed_batch(&texts)?;
                  
                  // Reuse upsert which handles deleting old chunks for this file
                  store.upsert(&chunks, &embeddings).await?;
+                 // A long watch session would otherwise pile up versions
+                 if options.cleanup && store.cleanup_due().await.unwrap_or(false) {
+                     let _ = store.cleanup().await;
+                 }
                  
-                 // Update Text Index
-                 let tantivy_path = Path::new(root).join(".code-search/text_index");
-                 {
-                    // Accessing text_index via Searcher might be cleaner if we cached it.
-                    // But here we load/save to ensure persistence.
-                    // TODO: Optimize by keeping in memory and saving periodically?
-                    let text_index = TextIndex::load_or_create(tantivy_path.to_str().unwrap())?;
-                    let texts: Vec<String> = chunks.iter().map(|c| c.content.clone()).collect();
-                    for text in texts {
-                         let _ = text_index.index_text(&relative_path, &text);
-                    }
+                 // Update Text Index; `save` commits, so every event is persisted
+                 if let Some(text_index) = self.open_text_index(Path::new(root), options)? {
+                    text_index.index_chunks(&chunks)?;
                     text_index.save("")?;
                  }
                  
@@ -308,4 +1363,1110 @@ impl Searcher {
          
          Ok(())
     }
+
+    /// Removes a path that no longer exists from the index: the file itself, or
+    /// every indexed file under it if it was a directory (e.g. moved away by a rename).
+    /// Like every store `all_stores` opens, it only sees the current branch's rows.
+    pub async fn forget_path(&self, path: &Path, root: &str, options: &SearchOptions) -> Result<()> {
+        self.scan_cache.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        let root_path = Path::new(root);
+        let relative = pathdiff::diff_paths(path, root).unwrap_or(path.to_path_buf());
+        let mut forgotten = Vec::new();
+        for (_, store) in self.all_stores(root_path, options).await? {
+            let files: Vec<String> = store.get_indexed_metadata().await?
+                .into_keys()
+                .filter(|file| Path::new(file).starts_with(&relative))
+                .collect();
+            store.delete_files(&files).await?;
+            forgotten.extend(files);
+        }
+        if !forgotten.is_empty() {
+            eprintln!("Removed {} files under {} from the index", forgotten.len(), relative.display());
+            self.forget_text(root_path, options, &forgotten)?;
+        }
+        Ok(())
+    }
+}
+
+/// Groups of interchangeable programming terms for lexical query expansion.
+const SYNONYM_GROUPS: &[&[&str]] = &[
+    &["add", "create", "insert", "append", "push"],
+    &["remove", "delete", "drop", "erase", "pop"],
+    &["get", "fetch", "retrieve", "load", "read"],
+    &["set", "update", "assign", "write"],
+    &["find", "search", "lookup", "query"],
+    &["start", "begin", "init", "initialize"],
+    &["stop", "end", "terminate", "shutdown"],
+    &["error", "err", "failure", "exception"],
+    &["config", "configuration", "settings", "options"],
+    &["parse", "decode", "deserialize"],
+    &["serialize", "encode"],
+    &["check", "validate", "verify"],
+];
+
+/// The query as the text index gets it: plain words (`plain_words`) unless
+/// `options.query_syntax` asks for tantivy's syntax, then synonym-expanded.
+fn keyword_query(query: &str, options: &SearchOptions) -> String {
+    let query = if options.query_syntax { query.to_string() } else { plain_words(query) };
+    if options.synonyms { expand_synonyms(&query) } else { query }
+}
+
+/// Reduces a natural-language query to words tantivy can't read as syntax:
+/// punctuation (`:`, quotes, brackets, `+`/`-`, ...) becomes a space, which the
+/// tokenizer splits on anyway, and the operators `AND`/`OR`/`NOT` are lowercased
+/// into plain words. `foo::bar(x)` searches for `foo bar x`.
+fn plain_words(query: &str) -> String {
+    query
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .map(|word| match word {
+            "AND" | "OR" | "NOT" | "IN" => word.to_lowercase(),
+            _ => word.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Rewrites each word that has synonyms as an OR group, e.g.
+/// `remove item` -> `(remove OR delete OR drop OR erase OR pop) item`.
+/// Words without synonyms and anything that isn't a plain word (quotes,
+/// `field:value`, operators) are passed through unchanged.
+pub fn expand_synonyms(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| {
+            let lower = word.to_lowercase();
+            if !lower.chars().all(|c| c.is_alphanumeric()) {
+                return word.to_string();
+            }
+            match SYNONYM_GROUPS.iter().find(|group| group.contains(&lower.as_str())) {
+                Some(group) => {
+                    let mut terms = vec![lower.as_str()];
+                    terms.extend(group.iter().copied().filter(|t| *t != lower));
+                    format!("({})", terms.join(" OR "))
+                }
+                None => word.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Names of one logical index inside an index location. The default index uses
+/// `code_chunks`, `doc_chunks` and `text_index`; a named one (`--table <name>`,
+/// CODE_SEARCH_TABLE) uses `<name>`, `<name>_docs` and `text_index_<name>`.
+#[derive(Clone, Debug)]
+struct IndexNames {
+    code: String,
+    docs: String,
+    text_index: String,
+    progress: String,
+    // Value of the tables' `branch` column this index reads and writes (see `for_branch`)
+    branch: String,
+}
+
+impl IndexNames {
+    fn new(table: Option<&str>) -> Self {
+        match table.filter(|table| *table != CODE_TABLE) {
+            None => Self {
+                code: CODE_TABLE.to_string(),
+                docs: DOCS_TABLE.to_string(),
+                text_index: "text_index".to_string(),
+                progress: PROGRESS_FILE.to_string(),
+                branch: String::new(),
+            },
+            Some(name) => Self {
+                code: name.to_string(),
+                docs: format!("{}_docs", name),
+                text_index: format!("text_index_{}", name),
+                progress: format!("indexing_{}.progress", name),
+                branch: String::new(),
+            },
+        }
+    }
+
+    fn table(&self, docs: bool) -> &str {
+        if docs { &self.docs } else { &self.code }
+    }
+
+    /// With `branch_aware`, this index's variant for the branch checked out at
+    /// `repo_root`: the same tables, but only their rows of that branch, and a text
+    /// index and progress file of its own. Unchanged outside git or on a detached HEAD.
+    fn for_branch(self, repo_root: &Path, branch_aware: bool) -> Self {
+        match current_branch(repo_root).filter(|_| branch_aware) {
+            Some(branch) => {
+                let suffix = branch_suffix(&branch);
+                Self {
+                    text_index: format!("{}.{}", self.text_index, suffix),
+                    progress: format!("{}.{}", self.progress, suffix),
+                    branch,
+                    ..self
+                }
+            }
+            None => self,
+        }
+    }
+}
+
+/// What gets embedded for each chunk under the given options.
+fn embedding_texts(chunks: &[FileChunk], options: &SearchOptions) -> Vec<String> {
+    chunks.iter()
+        .map(|c| {
+            // Only the content: the header and imports sit at column 0 and would stop the dedent
+            if options.normalize {
+                let normalized = FileChunk { content: normalize_whitespace(&c.content), ..c.clone() };
+                normalized.embedding_text(options.include_imports, options.embed_header).into_owned()
+            } else {
+                c.embedding_text(options.include_imports, options.embed_header).into_owned()
+            }
+        })
+        .collect()
+}
+
+/// Strips the indentation all lines share, collapses other runs of whitespace to one
+/// space (keeping relative indentation) and drops blank lines. Saves the model's
+/// limited input tokens for code instead of layout.
+fn normalize_whitespace(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().filter(|line| !line.trim().is_empty()).collect();
+    let common_indent = lines.iter()
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    lines.iter()
+        .map(|line| {
+            // Leading whitespace is ASCII in practice; fall back to the full line otherwise
+            let line = line.get(common_indent..).unwrap_or(line);
+            let body = line.trim_start();
+            let indent = &line[..line.len() - body.len()];
+            let words: Vec<&str> = body.split_whitespace().collect();
+            format!("{}{}", indent, words.join(" "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The checked-out branch of the git repository containing `repo_root`, read from
+/// `HEAD` (following a worktree's `.git` file). None outside git or on a detached HEAD.
+fn current_branch(repo_root: &Path) -> Option<String> {
+    let root = std::fs::canonicalize(repo_root).ok()?;
+    let dot_git = root.ancestors().map(|dir| dir.join(".git")).find(|git| git.exists())?;
+    let git_dir = if dot_git.is_file() {
+        let link = std::fs::read_to_string(&dot_git).ok()?;
+        let target = PathBuf::from(link.trim().strip_prefix("gitdir:")?.trim());
+        dot_git.parent()?.join(target)
+    } else {
+        dot_git
+    };
+    let head = std::fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    head.trim().strip_prefix("ref: refs/heads/").map(str::to_string)
+}
+
+/// Index name from CODE_SEARCH_TABLE, if set.
+fn default_table() -> Option<String> {
+    std::env::var("CODE_SEARCH_TABLE").ok().filter(|table| !table.is_empty())
+}
+
+/// Checks that `name` can name a table: letters, digits, `_`, `-` and `.`.
+pub fn check_table_name(name: &str) -> Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        anyhow::bail!("Invalid index name '{}' (use letters, digits, '_', '-' and '.')", name);
+    }
+    Ok(())
+}
+
+/// Central index directory from CODE_SEARCH_INDEX_DIR, if set.
+fn default_index_dir() -> Option<PathBuf> {
+    std::env::var_os("CODE_SEARCH_INDEX_DIR").map(PathBuf::from)
+}
+
+/// See `Searcher::index_path`.
+fn index_path_in(index_dir: Option<&Path>, repo_root: &Path) -> PathBuf {
+    match index_dir {
+        None => repo_root.join(".code-search"),
+        Some(dir) => {
+            let canonical = std::fs::canonicalize(repo_root).unwrap_or_else(|_| repo_root.to_path_buf());
+            let name = canonical.file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| "root".to_string());
+            dir.join(format!("{}-{:016x}", name, content_hash(canonical.to_string_lossy().as_bytes())))
+        }
+    }
+}
+
+/// Opens the index of `repo_path` for reading, without the model. Fails instead of
+/// creating an empty index when there is none yet.
+async fn open_existing_store(repo_path: &str, index_dir: Option<PathBuf>, table: Option<String>) -> Result<(VectorStore, PathBuf)> {
+    let db_path = existing_index_path(repo_path, index_dir)?;
+    if !db_path.exists() {
+        anyhow::bail!("No index at {}; run a text search first to build it", db_path.display());
+    }
+    if let Some(manifest) = read_index_manifest(&db_path).filter(|m| m.schema_version != INDEX_SCHEMA_VERSION) {
+        return Err(SearchError::IndexIncompatible(format!(
+            "Index at {} has index schema {}, but this version of code-search reads schema {}; \
+             run a search with the matching version to rebuild it",
+            db_path.display(), manifest.schema_version, INDEX_SCHEMA_VERSION
+        )).into());
+    }
+    let names = IndexNames::new(table.or_else(default_table).as_deref())
+        .for_branch(Path::new(repo_path), SearchOptions::default().branch_aware);
+    let store = open_table_at(&db_path, &names.code, &names.branch, &configured_model_id()).await?;
+    Ok((store, db_path))
+}
+
+/// Index location of an existing repository (which need not be indexed yet).
+fn existing_index_path(repo_path: &str, index_dir: Option<PathBuf>) -> Result<PathBuf> {
+    let path = Path::new(repo_path);
+    if !path.exists() {
+        return Err(SearchError::PathNotFound(repo_path.to_string()).into());
+    }
+    Ok(index_path_in(index_dir.or_else(default_index_dir).as_deref(), path))
+}
+
+async fn open_table_at(db_path: &Path, table: &str, branch: &str, model_id: &str) -> Result<VectorStore> {
+    let db_path_str = db_path.to_str()
+        .ok_or_else(|| anyhow::anyhow!("Invalid unicode path: {:?}", db_path))?;
+    let store = VectorStore::new(db_path_str, table, model_id, None).await?;
+    store.ensure_current(db_path_str)?;
+    Ok(store.with_branch(branch))
+}
+
+/// Size and freshness of `repo_path`'s index across its tables, as it is: nothing
+/// is re-indexed and no model is loaded. All zero when there is no index yet.
+pub async fn index_stats(repo_path: &str, index_dir: Option<PathBuf>, table: Option<String>) -> Result<IndexStats> {
+    let db_path = existing_index_path(repo_path, index_dir)?;
+    let mut stats = IndexStats::default();
+    if !db_path.exists() {
+        return Ok(stats);
+    }
+    let names = IndexNames::new(table.or_else(default_table).as_deref())
+        .for_branch(Path::new(repo_path), SearchOptions::default().branch_aware);
+    let mut tables = vec![(names.code.as_str(), configured_model_id())];
+    tables.extend(configured_docs_model().map(|model| (names.docs.as_str(), model)));
+    for (table, model_id) in tables {
+        let store = open_table_at(&db_path, table, &names.branch, &model_id).await.map_err(|e| {
+            e.downcast::<SearchError>().unwrap_or_else(SearchError::index)
+        })?;
+        let table_stats = store.stats().await.map_err(SearchError::index)?;
+        stats.chunks += table_stats.chunks;
+        stats.files += table_stats.files;
+        stats.newest_mtime = stats.newest_mtime.max(table_stats.newest_mtime);
+    }
+    Ok(stats)
+}
+
+/// Paths of every file in `repo_path`'s index (across its tables), sorted, as it
+/// is: nothing is re-indexed and no model is loaded. Empty when there is no index.
+pub async fn list_indexed_files(repo_path: &str, index_dir: Option<PathBuf>, table: Option<String>, filter: Option<&str>) -> Result<Vec<String>> {
+    let db_path = existing_index_path(repo_path, index_dir)?;
+    if !db_path.exists() {
+        return Ok(Vec::new());
+    }
+    let names = IndexNames::new(table.or_else(default_table).as_deref())
+        .for_branch(Path::new(repo_path), SearchOptions::default().branch_aware);
+    let mut tables = vec![(names.code.as_str(), configured_model_id())];
+    tables.extend(configured_docs_model().map(|model| (names.docs.as_str(), model)));
+    let mut files = Vec::new();
+    for (table, model_id) in tables {
+        let store = open_table_at(&db_path, table, &names.branch, &model_id).await.map_err(|e| {
+            e.downcast::<SearchError>().unwrap_or_else(SearchError::index)
+        })?;
+        files.extend(store.list_files(filter).await?);
+    }
+    // Each file lives in one table, but merging two sorted lists needs a sort anyway
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Where `repo_path`'s index lives (or would live), honoring `index_dir` and
+/// CODE_SEARCH_INDEX_DIR like the searcher does.
+pub fn index_location(repo_path: &str, index_dir: Option<PathBuf>) -> Result<PathBuf> {
+    existing_index_path(repo_path, index_dir)
+}
+
+/// Deletes `index_path`, the index of `repo_path` from `index_location`, with
+/// all its tables. Refuses anything that isn't a real directory or that holds
+/// the repository itself, so a bad `--index-dir` can't take the repository with it.
+pub fn remove_index(repo_path: &str, index_path: &Path) -> Result<()> {
+    let metadata = std::fs::symlink_metadata(index_path)?;
+    if !metadata.is_dir() {
+        anyhow::bail!("{} is not a directory; remove it by hand if it should go", index_path.display());
+    }
+    let repo_root = std::fs::canonicalize(repo_path)?;
+    if repo_root.starts_with(std::fs::canonicalize(index_path)?) {
+        anyhow::bail!("Refusing to delete {}: it contains the repository", index_path.display());
+    }
+    std::fs::remove_dir_all(index_path)?;
+    Ok(())
+}
+
+/// Prunes old versions of `repo_path`'s tables and compacts their fragments
+/// now, regardless of `VectorStore::cleanup_due`. No model is loaded.
+pub async fn compact_index(repo_path: &str, index_dir: Option<PathBuf>, table: Option<String>) -> Result<()> {
+    let db_path = existing_index_path(repo_path, index_dir)?;
+    if !db_path.exists() {
+        anyhow::bail!("No index at {}; run a text search first to build it", db_path.display());
+    }
+    let names = IndexNames::new(table.or_else(default_table).as_deref())
+        .for_branch(Path::new(repo_path), SearchOptions::default().branch_aware);
+    let mut tables = vec![(names.code.as_str(), configured_model_id())];
+    tables.extend(configured_docs_model().map(|model| (names.docs.as_str(), model)));
+    for (table, model_id) in tables {
+        open_table_at(&db_path, table, &names.branch, &model_id).await?.cleanup().await?;
+    }
+    Ok(())
+}
+
+/// Writes every indexed chunk of `repo_path` to `out`, one JSON object per line,
+/// and returns the number of rows written.
+pub async fn export_index(
+    repo_path: &str,
+    index_dir: Option<PathBuf>,
+    table: Option<String>,
+    with_vectors: bool,
+    out: &mut dyn std::io::Write,
+) -> Result<usize> {
+    let (store, _) = open_existing_store(repo_path, index_dir, table).await?;
+    // Opening the store checked that the table was built by this model
+    let model = with_vectors.then(configured_model_id);
+    let mut rows = store.scan_all(with_vectors).await?;
+    let mut count = 0;
+    while let Some(row) = rows.next().await {
+        let mut row = row?;
+        row.model = model.clone();
+        serde_json::to_writer(&mut *out, &row)?;
+        out.write_all(b"\n")?;
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+/// Loads rows written by `export --with-vectors` into the index of `repo_path`
+/// without running the model, and returns the number of chunks imported.
+/// With `restamp`, chunks take the mtime of the local file so the next search
+/// treats them as current (only valid if the checkout matches the exported one).
+pub async fn import_index(
+    repo_path: &str,
+    index_dir: Option<PathBuf>,
+    table: Option<String>,
+    input: impl std::io::BufRead,
+    restamp: bool,
+) -> Result<usize> {
+    let path = Path::new(repo_path);
+    if !path.exists() {
+        return Err(SearchError::PathNotFound(repo_path.to_string()).into());
+    }
+
+    // Validate everything before writing, and group by file: upsert replaces
+    // all chunks of the files it is given, so a batch must hold whole files
+    let mut files: BTreeMap<String, Vec<IndexedChunk>> = BTreeMap::new();
+    let mut dim = None;
+    let model_id = configured_model_id();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row: IndexedChunk = serde_json::from_str(&line)
+            .map_err(|e| anyhow::anyhow!("Invalid row on line {}: {}", i + 1, e))?;
+        let Some(vector) = &row.vector else {
+            anyhow::bail!("Row on line {} has no vector; export with --with-vectors", i + 1);
+        };
+        let expected = *dim.get_or_insert(vector.len());
+        if vector.len() != expected {
+            anyhow::bail!(
+                "Row on line {} has a {}-dimensional vector, but earlier rows have {} dimensions",
+                i + 1, vector.len(), expected
+            );
+        }
+        // Searches would embed queries with the configured model, which can't match another model's vectors
+        match row.model.as_deref() {
+            Some(model) if model != model_id => anyhow::bail!(
+                "Row on line {} was embedded by '{}', but the configured model is '{}'; \
+                 import with the embedder configuration the export was made with",
+                i + 1, model, model_id
+            ),
+            // Exports that predate the stamp can still be checked against the bundled model
+            None if model_id == MODEL_ID && vector.len() != MODEL_DIM => anyhow::bail!(
+                "Row on line {} has a {}-dimensional vector, but '{}' makes {}-dimensional ones",
+                i + 1, vector.len(), model_id, MODEL_DIM
+            ),
+            _ => {}
+        }
+        files.entry(row.file_path.clone()).or_default().push(row);
+    }
+
+    let db_path = index_path_in(index_dir.or_else(default_index_dir).as_deref(), path);
+    std::fs::create_dir_all(&db_path)
+        .map_err(|e| anyhow::anyhow!("Cannot create index directory {}: {}", db_path.display(), e))?;
+    let db_path_str = db_path.to_str()
+        .ok_or_else(|| anyhow::anyhow!("Invalid unicode path: {:?}", db_path))?;
+    // An existing index with another width is rejected here
+    let names = IndexNames::new(table.or_else(default_table).as_deref())
+        .for_branch(path, SearchOptions::default().branch_aware);
+    let store = VectorStore::new(db_path_str, &names.code, &configured_model_id(), dim).await?
+        .with_branch(&names.branch);
+    store.prepare_for_writes(db_path_str, SearchOptions::default().embed_settings()).await?;
+    let text_index = if SearchOptions::default().text_index {
+        let tantivy_path = db_path.join(&names.text_index);
+        Some(TextIndex::load_or_create(tantivy_path.to_str().unwrap()).map_err(SearchError::index)?)
+    } else {
+        None
+    };
+
+    let local_mtime = |file: &str| -> Option<u64> {
+        let modified = std::fs::metadata(path.join(file)).ok()?.modified().ok()?;
+        Some(modified.duration_since(std::time::UNIX_EPOCH).ok()?.as_secs())
+    };
+
+    let total_files = files.len();
+    let mut files_done = 0;
+    let mut imported = 0;
+    let mut files = files.into_values().peekable();
+    while files.peek().is_some() {
+        let mut chunks = Vec::new();
+        let mut embeddings = Vec::new();
+        for rows in files.by_ref().take(commit_batch_files()) {
+            let mtime = if restamp { local_mtime(&rows[0].file_path) } else { None };
+            for row in rows {
+                embeddings.push(row.vector.unwrap_or_default()); // Checked above
+                chunks.push(FileChunk {
+                    mtime: mtime.unwrap_or(row.mtime),
+                    file_path: row.file_path,
+                    chunk_index: row.chunk_index,
+                    content: row.content,
+                    line_start: row.line_start,
+                    line_end: row.line_end,
+                    // Not stored in the vector table, so not exported
+                    doc_text: String::new(),
+                    symbol_start: row.symbol_start,
+                    symbol_end: row.symbol_end,
+                    symbol_name: row.symbol_name,
+                    symbol_kind: row.symbol_kind,
+                    content_hash: row.content_hash,
+                    imports: String::new(),
+                    signature: String::new(),
+                });
+            }
+            files_done += 1;
+        }
+        if let Some(text_index) = &text_index {
+            text_index.index_chunks(&chunks)?;
+            text_index.save("")?;
+        }
+        store.upsert(&chunks, &embeddings).await.map_err(SearchError::index)?;
+        imported += chunks.len();
+        eprintln!("Imported {}/{} files.", files_done, total_files);
+    }
+
+    Ok(imported)
+}
+
+/// Nearest chunks to a precomputed query embedding. Doesn't load the model or
+/// re-index: the repository must already have an index. `index_dir` and `table`
+/// override CODE_SEARCH_INDEX_DIR and CODE_SEARCH_TABLE like the `Searcher` builders.
+pub async fn search_by_vector(
+    repo_path: &str,
+    index_dir: Option<PathBuf>,
+    table: Option<String>,
+    vector: &[f32],
+    limit: usize,
+    kinds: &[String],
+) -> Result<Vec<crate::store::SearchResult>> {
+    let (store, db_path) = open_existing_store(repo_path, index_dir, table).await?;
+
+    match store.embedding_dim().await? {
+        None => anyhow::bail!("No index at {}; run a text search first to build it", db_path.display()),
+        Some(dim) if dim != vector.len() => anyhow::bail!(
+            "Query vector has {} dimensions, but the index stores {}-dimensional embeddings",
+            vector.len(), dim
+        ),
+        Some(_) => {}
+    }
+
+    // Stored embeddings are unit length and scores assume it; averaged or
+    // externally produced vectors usually aren't
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        anyhow::bail!("Query vector is all zeros");
+    }
+    let normalized: Vec<f32> = vector.iter().map(|x| x / norm).collect();
+
+    let mut results = store.search(&normalized, limit, kinds, None, None, false, false).await?;
+    if let Some(content_lines) = store.content_lines() {
+        load_full_content(Path::new(repo_path), &mut results, content_lines, store.max_lines().unwrap_or(DEFAULT_MAX_LINES));
+    }
+    Ok(results)
+}
+
+/// Ranks chunks by keyword (BM25) score alone, straight from the text index:
+/// no model load and no re-indexing, so the repository must already have an
+/// index. Honors `limit`, `synonyms`, `grep` and `file` from `options`.
+pub fn search_text(
+    repo_path: &str,
+    index_dir: Option<PathBuf>,
+    table: Option<String>,
+    query: &str,
+    options: &SearchOptions,
+) -> Result<Vec<crate::store::SearchResult>> {
+    let names = IndexNames::new(table.or_else(default_table).as_deref())
+        .for_branch(Path::new(repo_path), options.branch_aware);
+    let tantivy_path = existing_index_path(repo_path, index_dir)?.join(&names.text_index);
+    if !tantivy_path.exists() {
+        anyhow::bail!("No text index at {}; run a search without --text-only first to build it", tantivy_path.display());
+    }
+    let text_index = TextIndex::open_read_only(tantivy_path.to_str().unwrap()).map_err(|e| {
+        e.downcast::<SearchError>().unwrap_or_else(SearchError::index)
+    })?;
+    let file = options.file.as_deref().map(|file| repo_relative(Path::new(repo_path), file));
+
+    // Post-filters can only narrow the hits, so fetch extra when they're set
+    let narrowed = options.grep.is_some() || file.is_some();
+    let fetch_limit = if narrowed {
+        std::cmp::max(options.limit * options.recall_multiplier.max(1), options.recall_floor)
+    } else {
+        options.limit
+    };
+    let mut results = interleave(std::iter::once(query).chain(options.extra_queries.iter().map(String::as_str))
+        .map(|query| text_index.search_chunks(&keyword_query(query, options), fetch_limit, options.match_all))
+        .collect());
+    let mut seen = HashSet::new();
+    results.retain(|r| seen.insert((r.file_path.clone(), r.line_start)));
+    if let Some(file) = &file {
+        results.retain(|r| &r.file_path == file);
+    }
+    if let Some(re) = &options.grep {
+        results.retain(|r| re.is_match(&r.content));
+    }
+    results.truncate(options.limit);
+    Ok(results)
+}
+
+/// Turns a `--file` argument into the repo-relative path stored in the index.
+/// Accepts paths relative to the repository root, or absolute/cwd-relative paths
+/// of files inside it.
+fn repo_relative(repo_root: &Path, file: &str) -> String {
+    let candidate = Path::new(file);
+    if candidate.is_absolute() || (!repo_root.join(candidate).exists() && candidate.exists()) {
+        let diff = std::fs::canonicalize(candidate).ok().zip(std::fs::canonicalize(repo_root).ok())
+            .and_then(|(file, root)| pathdiff::diff_paths(file, root));
+        if let Some(diff) = diff {
+            return diff.to_string_lossy().to_string();
+        }
+    }
+    file.trim_start_matches("./").to_string()
+}
+
+/// Everything that decides which files a walk returns.
+fn scan_cache_key(repo_root: &Path, options: &SearchOptions) -> String {
+    let repo = std::fs::canonicalize(repo_root).unwrap_or_else(|_| repo_root.to_path_buf());
+    let sorted = |set: &HashSet<String>| set.iter().cloned().collect::<std::collections::BTreeSet<_>>();
+    format!("{:?}", (
+        repo,
+        &options.exclude,
+        sorted(&options.extensions),
+        sorted(&options.skip_dirs),
+        options.index_generated,
+    ))
+}
+
+/// Everything besides the index contents that shapes a query's results.
+fn query_cache_key(repo_root: &Path, query: &str, options: &SearchOptions) -> String {
+    let repo = std::fs::canonicalize(repo_root).unwrap_or_else(|_| repo_root.to_path_buf());
+    // Debug is only implemented for tuples of up to 12 fields, hence the nesting
+    format!("{:?}", (
+        repo,
+        query,
+        options.limit,
+        (options.synonyms, options.match_all, options.query_syntax, &options.extra_queries),
+        (options.recall_multiplier, options.recall_floor),
+        (options.keyword_boost, options.rrf_k),
+        options.grep.as_ref().map(regex::Regex::as_str),
+        options.whole_symbol,
+        &options.kinds,
+        &options.file,
+        (options.with_vectors, options.rerank),
+        (options.text_index, &options.exclude, options.all_branches, options.embed_settings()),
+    ))
+}
+
+/// Merges the vector candidates of several queries into one list, one entry per
+/// chunk (by file and first line). A chunk keeps its best similarity and gains
+/// reciprocal rank fusion points (`1/(k + rank)` × `RRF_SCALE`) for every further
+/// query that found it, so chunks relevant to several of the queries rise above
+/// those that only one of them found. Keeps the best `limit`.
+fn fuse_queries(mut per_query: Vec<Vec<crate::store::SearchResult>>, k: f32, limit: usize) -> Vec<crate::store::SearchResult> {
+    if per_query.len() == 1 {
+        let mut results = per_query.pop().unwrap_or_default();
+        results.truncate(limit);
+        return results;
+    }
+    let mut fused: Vec<crate::store::SearchResult> = Vec::new();
+    // Per fused chunk: the sum of its RRF points and the largest single one
+    let mut points: Vec<(f32, f32)> = Vec::new();
+    let mut index: HashMap<(String, usize), usize> = HashMap::new();
+    for results in per_query {
+        for (rank, result) in results.into_iter().enumerate() {
+            let rrf = 1.0 / (k + rank as f32);
+            match index.get(&(result.file_path.clone(), result.line_start)) {
+                Some(&i) => {
+                    points[i].0 += rrf;
+                    points[i].1 = points[i].1.max(rrf);
+                    if result.score > fused[i].score {
+                        fused[i] = result;
+                    }
+                }
+                None => {
+                    index.insert((result.file_path.clone(), result.line_start), fused.len());
+                    fused.push(result);
+                    points.push((rrf, rrf));
+                }
+            }
+        }
+    }
+    for (result, (sum, best)) in fused.iter_mut().zip(points) {
+        result.score += (sum - best) * RRF_SCALE;
+    }
+    fused.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    // Every query brought up to `limit` candidates; the merged list is no longer
+    fused.truncate(limit);
+    fused
+}
+
+/// Round-robin merge of ranked lists: every list's first item, then every second
+/// one, and so on. Duplicates are left for the caller, which keeps the first.
+fn interleave<T>(lists: Vec<Vec<T>>) -> Vec<T> {
+    let mut lists: Vec<_> = lists.into_iter().map(Vec::into_iter).collect();
+    let mut merged = Vec::new();
+    loop {
+        let before = merged.len();
+        merged.extend(lists.iter_mut().filter_map(Iterator::next));
+        if merged.len() == before {
+            return merged;
+        }
+    }
+}
+
+/// Greedy threshold clustering by cosine similarity of the results' embeddings:
+/// in order, each result joins the first cluster whose representative (its first
+/// member) is at least `threshold` similar, or starts a new one. Results without an
+/// embedding stay on their own. Returns clusters as indices into `results`.
+pub fn cluster_results(results: &[crate::store::SearchResult], threshold: f32) -> Vec<Vec<usize>> {
+    let mut clusters: Vec<Vec<usize>> = Vec::new();
+    for (i, result) in results.iter().enumerate() {
+        let joined = result.embedding.as_deref().and_then(|embedding| {
+            clusters.iter_mut().find(|cluster| {
+                results[cluster[0]].embedding.as_deref()
+                    .is_some_and(|rep| cosine_similarity(rep, embedding) >= threshold)
+            })
+        });
+        match joined {
+            Some(cluster) => cluster.push(i),
+            None => clusters.push(vec![i]),
+        }
+    }
+    clusters
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    dot / (norm(a) * norm(b)).max(1e-9)
+}
+
+/// Lowercased alphanumeric words, split like tantivy's default tokenizer
+/// (so `parse_json` is `parse` + `json`).
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+}
+
+/// Best share, over the queries' token sets, of a query's tokens that occur
+/// anywhere in `content`: 1.0 when every word is there, adjacent or not.
+fn keyword_share(query_tokens: &[HashSet<String>], content: &str) -> f32 {
+    let content_tokens: HashSet<String> = tokenize(content).collect();
+    query_tokens.iter()
+        .map(|tokens| tokens.iter().filter(|t| content_tokens.contains(*t)).count() as f32 / tokens.len() as f32)
+        .fold(0.0, f32::max)
+}
+
+/// `manifest.json` in the index directory: what built the index, for upgrades
+/// (`Searcher::check_index_manifest`) and for tools inspecting an index.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct IndexManifest {
+    /// `INDEX_SCHEMA_VERSION` of the tool that created the index
+    pub schema_version: u32,
+    /// Version of the tool that created the index
+    pub tool_version: String,
+    pub model: String,
+    pub embedding_dim: usize,
+    pub docs_model: Option<String>,
+    /// Lines per chunk (`--max-lines`)
+    pub max_lines: usize,
+    /// Unix seconds when the manifest was written; for indexes built before
+    /// manifests existed, that is the first run that found it missing
+    pub created_at: u64,
+}
+
+impl IndexManifest {
+    /// Whether both manifests describe tables built the same way; which tool
+    /// version wrote them and when don't count.
+    fn describes_same_tables(&self, other: &IndexManifest) -> bool {
+        (self.schema_version, &self.model, self.embedding_dim, &self.docs_model, self.max_lines)
+            == (other.schema_version, &other.model, other.embedding_dim, &other.docs_model, other.max_lines)
+    }
+}
+
+fn read_index_manifest(index_path: &Path) -> Option<IndexManifest> {
+    serde_json::from_str(&std::fs::read_to_string(index_path.join(INDEX_MANIFEST_FILE)).ok()?).ok()
+}
+
+/// Progress of the current indexing run, kept next to the index while it runs.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct IndexProgress {
+    total_files: usize,
+    committed_files: usize,
+}
+
+fn read_progress(path: &Path) -> Option<IndexProgress> {
+    serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()
+}
+
+fn write_progress(path: &Path, progress: &IndexProgress) {
+    if let Ok(json) = serde_json::to_string(progress) {
+        let _ = std::fs::write(path, json);
+    }
+}
+
+/// Scanned entries, once per path: the parallel walk can reach the same file twice
+/// (e.g. overlapping overrides), which would otherwise duplicate its chunks.
+fn unique_entries(entries: impl IntoIterator<Item = FileEntry>) -> Vec<FileEntry> {
+    let mut seen = HashSet::new();
+    entries.into_iter().filter(|entry| seen.insert(entry.path.clone())).collect()
+}
+
+/// How long a repository walk is reused; CODE_SEARCH_SCAN_TTL_SECS=0 walks on every search.
+fn scan_ttl() -> std::time::Duration {
+    let secs = std::env::var("CODE_SEARCH_SCAN_TTL_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_SCAN_TTL_SECS);
+    std::time::Duration::from_secs(secs)
+}
+
+/// Files embedded and committed per batch while (re-)indexing.
+/// Override with CODE_SEARCH_COMMIT_BATCH.
+fn commit_batch_files() -> usize {
+    std::env::var("CODE_SEARCH_COMMIT_BATCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_COMMIT_BATCH_FILES)
+        .max(1)
+}
+
+/// Restores the content of results whose stored copy was cut to `content_lines`
+/// lines, by chunking their files again (once per file) and taking the chunk at the
+/// same position. Results whose file changed or can't be read keep the stored part.
+/// Notebook cells match by cell number, as rechunking numbers them the same way.
+fn load_full_content(repo_root: &Path, results: &mut [crate::store::SearchResult], content_lines: usize, max_lines: usize) {
+    let root = repo_root.to_string_lossy();
+    let mut chunked: HashMap<String, Vec<FileChunk>> = HashMap::new();
+    for result in results.iter_mut() {
+        // Anything shorter than the cap was stored whole
+        if result.content.lines().count() < content_lines {
+            continue;
+        }
+        let chunks = chunked.entry(result.file_path.clone()).or_insert_with(|| {
+            process_file(&repo_root.join(&result.file_path), &root, max_lines).unwrap_or_default()
+        });
+        if let Some(chunk) = chunks.iter().find(|c| {
+            (c.chunk_index, c.line_start, c.line_end) == (result.chunk_index, result.line_start, result.line_end)
+        }) {
+            result.content = chunk.content.clone();
+        }
+    }
+}
+
+/// Replaces a sub-chunk's content with the full symbol it was split from,
+/// read back from the file. Keeps the fragment if the file can't be read.
+fn expand_to_symbol(repo_root: &Path, result: &mut crate::store::SearchResult) {
+    if result.lines_are_cells() || (result.symbol_start, result.symbol_end) == (result.line_start, result.line_end) {
+        return;
+    }
+    let Ok(content) = std::fs::read_to_string(repo_root.join(&result.file_path)) else {
+        return;
+    };
+    let lines: Vec<&str> = content
+        .lines()
+        .skip(result.symbol_start.saturating_sub(1))
+        .take(result.symbol_end.saturating_sub(result.symbol_start) + 1)
+        .collect();
+    if lines.is_empty() {
+        return;
+    }
+    result.content = lines.join("\n");
+    result.line_start = result.symbol_start;
+    result.line_end = result.symbol_end;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("code-search-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn branches_share_tables_and_differ_in_branch() {
+        let repo = temp_dir();
+        std::fs::create_dir_all(repo.join(".git")).unwrap();
+        std::fs::write(repo.join(".git/HEAD"), "ref: refs/heads/feature/login\n").unwrap();
+
+        let names = IndexNames::new(None).for_branch(&repo, true);
+        assert_eq!(names.code, CODE_TABLE);
+        assert_eq!(names.docs, DOCS_TABLE);
+        assert_eq!(names.branch, "feature/login");
+        assert!(names.text_index.starts_with("text_index.feature-login-"));
+
+        let plain = IndexNames::new(None).for_branch(&repo, false);
+        assert_eq!((plain.branch.as_str(), plain.text_index.as_str()), ("", "text_index"));
+
+        // A detached HEAD indexes without a branch
+        std::fs::write(repo.join(".git/HEAD"), "0123456789abcdef0123456789abcdef01234567\n").unwrap();
+        assert_eq!(IndexNames::new(None).for_branch(&repo, true).branch, "");
+        let _ = std::fs::remove_dir_all(&repo);
+    }
+
+    #[test]
+    fn cache_key_covers_result_changing_options() {
+        let repo = Path::new("/nonexistent/repo");
+        let base = SearchOptions::default();
+        let key = |options: &SearchOptions| query_cache_key(repo, "parse config", options);
+        let variants = [
+            SearchOptions { text_index: !base.text_index, ..base.clone() },
+            SearchOptions { exclude: vec!["*.md".to_string()], ..base.clone() },
+            SearchOptions { all_branches: !base.all_branches, ..base.clone() },
+            SearchOptions { normalize: !base.normalize, ..base.clone() },
+            SearchOptions { embed_header: !base.embed_header, ..base.clone() },
+            SearchOptions { include_imports: !base.include_imports, ..base.clone() },
+        ];
+        for variant in &variants {
+            assert_ne!(key(variant), key(&base), "{:?}", variant);
+        }
+        assert_eq!(key(&base.clone()), key(&base));
+    }
+
+    #[test]
+    fn a_file_reached_twice_is_chunked_once() {
+        let repo = temp_dir();
+        std::fs::create_dir_all(repo.join("src")).unwrap();
+        std::fs::write(repo.join("src/a.rs"), "fn a() {}\n").unwrap();
+        std::fs::write(repo.join("src/b.rs"), "fn b() {}\n\nfn c() {}\n").unwrap();
+        let root = repo.to_string_lossy().to_string();
+
+        let (tx, rx) = crossbeam_channel::unbounded();
+        scan_repository(&root, tx, Vec::new(), false, 1, SearchOptions::default().file_filter());
+        let scanned: Vec<FileEntry> = rx.iter().collect();
+        assert_eq!(scanned.len(), 2);
+        // As if overlapping overrides had walked the tree twice
+        let entries = unique_entries(scanned.iter().chain(&scanned).cloned());
+        assert_eq!(entries.len(), 2);
+
+        let chunks: Vec<FileChunk> = entries.iter()
+            .flat_map(|entry| process_file(&repo.join(&entry.path), &root, 40).unwrap())
+            .collect();
+        let mut symbols: Vec<_> = chunks.iter().filter_map(|c| c.symbol_name.as_deref()).collect();
+        symbols.sort_unstable();
+        assert_eq!(symbols, ["a", "b", "c"]);
+        let _ = std::fs::remove_dir_all(&repo);
+    }
+
+    #[test]
+    fn keyword_share_counts_words_apart() {
+        let tokens = |query: &str| tokenize(query).collect::<HashSet<String>>();
+        let parse_json = [tokens("parse json")];
+        assert_eq!(keyword_share(&parse_json, "let v = toml::from_str(s)?; // parse it"), 0.5);
+        assert_eq!(keyword_share(&parse_json, "fn parse(input: &str) -> Json { json::from(input) }"), 1.0);
+        assert_eq!(keyword_share(&parse_json, "fn parse_json() {}"), 1.0);
+        assert_eq!(keyword_share(&parse_json, "fn render() {}"), 0.0);
+        // Case-insensitive, and the best of several queries counts
+        let both = [tokens("parse json"), tokens("Render HTML page")];
+        assert_eq!(keyword_share(&both, "fn render_page(html: &Html) {}"), 1.0);
+    }
+
+    #[test]
+    fn manifests_differ_on_model_and_chunk_size_only() {
+        let manifest = |model: &str, max_lines: usize, tool_version: &str, created_at: u64| IndexManifest {
+            schema_version: INDEX_SCHEMA_VERSION,
+            tool_version: tool_version.to_string(),
+            model: model.to_string(),
+            embedding_dim: 384,
+            docs_model: None,
+            max_lines,
+            created_at,
+        };
+        let base = manifest("all-MiniLM-L6-v2", 40, "0.1.0", 100);
+        assert!(base.describes_same_tables(&manifest("all-MiniLM-L6-v2", 40, "0.2.0", 200)));
+        assert!(!base.describes_same_tables(&manifest("bge-small", 40, "0.1.0", 100)));
+        assert!(!base.describes_same_tables(&manifest("all-MiniLM-L6-v2", 60, "0.1.0", 100)));
+        let with_docs = IndexManifest { docs_model: Some("bge-small".to_string()), ..manifest("all-MiniLM-L6-v2", 40, "0.1.0", 100) };
+        assert!(!base.describes_same_tables(&with_docs));
+    }
+
+    #[test]
+    fn fused_queries_favor_shared_chunks_and_keep_the_limit() {
+        let hit = |file: &str, score: f32| {
+            let mut hit = result(file, "", (1, 5), (1, 5));
+            hit.score = score;
+            hit
+        };
+        let first = vec![hit("a.rs", 0.80), hit("shared.rs", 0.70), hit("b.rs", 0.65)];
+        let second = vec![hit("c.rs", 0.75), hit("shared.rs", 0.72), hit("d.rs", 0.60)];
+        let fused = fuse_queries(vec![first, second], 60.0, 3);
+        let files: Vec<&str> = fused.iter().map(|r| r.file_path.as_str()).collect();
+        assert_eq!(files, ["shared.rs", "a.rs", "c.rs"]);
+        // Its best similarity plus the other query's points at rank 1
+        assert!((fused[0].score - (0.72 + RRF_SCALE / 61.0)).abs() < 1e-6);
+
+        let single = fuse_queries(vec![vec![hit("a.rs", 0.9), hit("b.rs", 0.8)]], 60.0, 1);
+        assert_eq!(single.len(), 1);
+    }
+
+    #[test]
+    fn synonyms_expand_only_plain_words() {
+        assert_eq!(expand_synonyms("delete item"), "(delete OR remove OR drop OR erase OR pop) item");
+        assert_eq!(expand_synonyms("Fetch user"), "(fetch OR get OR retrieve OR load OR read) user");
+        assert_eq!(expand_synonyms("path:delete \"remove it\""), "path:delete \"remove it\"");
+    }
+
+    #[test]
+    fn a_delete_query_finds_remove_chunks_with_synonyms() {
+        let dir = temp_dir();
+        let path = dir.join("text");
+        let path = path.to_str().unwrap();
+        let index = crate::text_index::TextIndex::load_or_create(path).unwrap();
+        let chunk = |file_path: &str, content: &str| FileChunk {
+            file_path: file_path.to_string(),
+            chunk_index: 0,
+            content: content.to_string(),
+            line_start: 1,
+            line_end: 1,
+            mtime: 0,
+            doc_text: String::new(),
+            symbol_start: 1,
+            symbol_end: 1,
+            symbol_name: None,
+            symbol_kind: None,
+            content_hash: 0,
+            imports: String::new(),
+            signature: String::new(),
+        };
+        index.index_chunks(&[
+            chunk("src/list.rs", "fn remove_item(items: &mut Vec<u32>, i: usize) { items.swap_remove(i); }"),
+            chunk("src/log.rs", "fn format_line(line: &str) -> String { line.trim().to_string() }"),
+        ]).unwrap();
+        index.save(path).unwrap();
+
+        let search = |synonyms: bool| {
+            let options = SearchOptions { synonyms, ..SearchOptions::default() };
+            let hits = index.search_chunks(&keyword_query("delete", &options), 5, false);
+            hits.into_iter().map(|hit| hit.file_path).collect::<Vec<_>>()
+        };
+        assert!(search(false).is_empty());
+        assert_eq!(search(true), ["src/list.rs"]);
+        drop(index);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn result(file_path: &str, content: &str, lines: (usize, usize), symbol: (usize, usize)) -> crate::store::SearchResult {
+        crate::store::SearchResult {
+            file_path: file_path.to_string(),
+            chunk_index: 0,
+            content: content.to_string(),
+            line_start: lines.0,
+            line_end: lines.1,
+            symbol_start: symbol.0,
+            symbol_end: symbol.1,
+            symbol_name: None,
+            symbol_kind: None,
+            score: 1.0,
+            distance: None,
+            embedding: None,
+        }
+    }
+
+    #[test]
+    fn notebook_cells_are_not_read_as_file_lines() {
+        let repo = temp_dir();
+        let notebook = r##"{"cells": [
+  {"cell_type": "markdown", "source": ["# Load"]},
+  {"cell_type": "code", "source": ["import pandas as pd\n", "df = pd.read_csv('a.csv')\n", "df.head()"]}
+]}"##;
+        std::fs::write(repo.join("analysis.ipynb"), notebook).unwrap();
+
+        let chunks = process_file(&repo.join("analysis.ipynb"), &repo.to_string_lossy(), 40).unwrap();
+        let cell = &chunks[1];
+        assert_eq!((cell.line_start, cell.symbol_kind.as_deref()), (2, Some(crate::scanner::CELL_KIND)));
+
+        // A stored copy cut to one line comes back whole, matched by cell number
+        let mut truncated = result("analysis.ipynb", "import pandas as pd", (2, 2), (2, 2));
+        truncated.chunk_index = cell.chunk_index;
+        truncated.symbol_kind = cell.symbol_kind.clone();
+        assert!(truncated.lines_are_cells());
+        load_full_content(&repo, std::slice::from_mut(&mut truncated), 1, 40);
+        assert_eq!(truncated.content, cell.content);
+
+        // Line 1 of the JSON is not the cell's first line
+        let mut expanded = truncated.clone();
+        expanded.symbol_start = 1;
+        expand_to_symbol(&repo, &mut expanded);
+        assert_eq!((expanded.content.as_str(), expanded.line_start), (cell.content.as_str(), 2));
+        let _ = std::fs::remove_dir_all(&repo);
+    }
+
+    #[test]
+    fn expand_to_symbol_reads_the_symbol_lines() {
+        let repo = temp_dir();
+        std::fs::write(repo.join("lib.rs"), "fn a() {\n    one();\n    two();\n}\n").unwrap();
+        let mut piece = result("lib.rs", "    two();", (3, 3), (1, 4));
+        expand_to_symbol(&repo, &mut piece);
+        assert_eq!((piece.line_start, piece.line_end), (1, 4));
+        assert_eq!(piece.content, "fn a() {\n    one();\n    two();\n}");
+        let _ = std::fs::remove_dir_all(&repo);
+    }
+
+    #[test]
+    fn import_rejects_vectors_from_another_model() {
+        let repo = temp_dir();
+        let path = repo.to_string_lossy().to_string();
+        let row = |vector: Vec<f32>, model: Option<&str>| {
+            let mut row = serde_json::json!({
+                "file_path": "lib.rs", "line_start": 1, "line_end": 1, "content": "fn a() {}",
+                "mtime": 0, "chunk_index": 0, "symbol_start": 1, "symbol_end": 1,
+                "symbol_name": "a", "symbol_kind": "function", "vector": vector,
+            });
+            if let Some(model) = model {
+                row["model"] = model.into();
+            }
+            row.to_string()
+        };
+        let import = |line: String| {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(import_index(&path, Some(repo.join("index")), None, line.as_bytes(), false))
+        };
+        assert_eq!(configured_model_id(), MODEL_ID);
+
+        let error = import(row(vec![0.5; MODEL_DIM], Some("text-embedding-3-small"))).unwrap_err();
+        assert!(error.to_string().contains("embedded by 'text-embedding-3-small'"), "{}", error);
+        let error = import(row(vec![0.5; 1536], None)).unwrap_err();
+        assert!(error.to_string().contains("1536-dimensional"), "{}", error);
+        // Nothing was written
+        assert!(!repo.join("index").exists());
+        let _ = std::fs::remove_dir_all(&repo);
+    }
 }
@@ -7,31 +7,481 @@ use arrow_array::{
 };
 use arrow_schema::{DataType, Field, Schema};
 use futures::StreamExt;
+use futures::stream::BoxStream;
 use lancedb::{connect, Connection};
 use lancedb::query::{ExecutableQuery, QueryBase, Select}; // Import Select
 use lancedb::arrow::SendableRecordBatchStream; 
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
 use crate::scanner::FileChunk;
+use crate::error::SearchError;
+use crate::scanner::{content_hash, env_flag};
 
-const EMBEDDING_DIM: i32 = 384;
+/// Vector width when neither the caller nor an existing table specifies one
+/// (the bundled model's).
+const DEFAULT_EMBEDDING_DIM: i32 = crate::embeddings::MODEL_DIM as i32;
+
+/// Table of code chunks (and of every chunk when there is a single model).
+pub const CODE_TABLE: &str = "code_chunks";
+/// Table of prose chunks when they are embedded by a separate docs model.
+pub const DOCS_TABLE: &str = "doc_chunks";
+
+/// Column LanceDB adds to vector search results.
+const DISTANCE_COLUMN: &str = "_distance";
+
+/// Reports a missing distance column once per process instead of once per query.
+static MISSING_DISTANCE_WARNING: std::sync::Once = std::sync::Once::new();
+
+/// Warnings already printed by `warn_once`.
+static WARNED: std::sync::Mutex<Option<std::collections::HashSet<String>>> = std::sync::Mutex::new(None);
+
+/// Prints `message` unless this process printed it before: an index is opened
+/// for every query in `repl` and the MCP server, but its state is the same.
+/// Returns whether it was printed.
+fn warn_once(message: String) -> bool {
+    let mut warned = WARNED.lock().unwrap_or_else(|e| e.into_inner());
+    let first = warned.get_or_insert_with(Default::default).insert(message.clone());
+    if first {
+        eprintln!("{}", message);
+    }
+    first
+}
+
+// Schema metadata keys describing how the stored vectors were produced
+const META_MODEL: &str = "code_search.model";
+const META_DIM: &str = "code_search.embedding_dim";
+const META_NORMALIZED: &str = "code_search.normalized";
+const META_MAX_LINES: &str = "code_search.max_lines";
+const META_CONTENT_LINES: &str = "code_search.content_lines";
+const META_INCLUDE_IMPORTS: &str = "code_search.include_imports";
+const META_EMBED_HEADER: &str = "code_search.embed_header";
+// Not META_NORMALIZED, which is about the vectors rather than the embedded text
+const META_NORMALIZE_WHITESPACE: &str = "code_search.normalize_whitespace";
+
+/// Options that change the text embedded for a chunk. Recorded in the table
+/// metadata, since vectors embedded under other settings don't mix.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EmbedSettings {
+    /// `SearchOptions::include_imports`
+    pub include_imports: bool,
+    /// `SearchOptions::embed_header`
+    pub embed_header: bool,
+    /// `SearchOptions::normalize`
+    pub normalize: bool,
+}
+
+impl EmbedSettings {
+    /// The settings a table records; tables created before a key was recorded
+    /// count as having had it off.
+    fn from_metadata(metadata: &HashMap<String, String>) -> Self {
+        let flag = |key| metadata.get(key).is_some_and(|v| v == "true");
+        Self {
+            include_imports: flag(META_INCLUDE_IMPORTS),
+            embed_header: flag(META_EMBED_HEADER),
+            normalize: flag(META_NORMALIZE_WHITESPACE),
+        }
+    }
+
+    fn record(&self, metadata: &mut HashMap<String, String>) {
+        metadata.insert(META_INCLUDE_IMPORTS.to_string(), self.include_imports.to_string());
+        metadata.insert(META_EMBED_HEADER.to_string(), self.embed_header.to_string());
+        metadata.insert(META_NORMALIZE_WHITESPACE.to_string(), self.normalize.to_string());
+    }
+}
+
+impl std::fmt::Display for EmbedSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "include_imports={}, embed_header={}, normalize={}",
+            self.include_imports, self.embed_header, self.normalize
+        )
+    }
+}
+
+/// Table versions written since the last cleanup that make `cleanup_due` true.
+const CLEANUP_EVERY_VERSIONS: u64 = 20;
+/// Age of the last cleanup after which any new version makes `cleanup_due` true.
+const CLEANUP_INTERVAL_SECS: u64 = 60 * 60;
+/// Default age (`CODE_SEARCH_PRUNE_HOURS`) of the versions `cleanup` prunes.
+const DEFAULT_PRUNE_HOURS: f64 = 1.0;
+
+/// Versions younger than this survive `cleanup`, so concurrent readers of an older
+/// snapshot keep working. Fractions are allowed (`0.1` is six minutes).
+fn prune_window() -> chrono::Duration {
+    let hours = std::env::var("CODE_SEARCH_PRUNE_HOURS")
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|h| h.is_finite() && *h >= 0.0)
+        .unwrap_or(DEFAULT_PRUNE_HOURS);
+    chrono::Duration::seconds((hours * 3600.0) as i64)
+}
 
 pub struct VectorStore {
     conn: Connection,
     table_name: String,
+    model_id: String,
+    // Width of the vector column
+    dim: i32,
+    // Lines per chunk the table is built with; set once, then recorded on creation
+    max_lines: std::sync::OnceLock<usize>,
+    // Lines of chunk content kept in the table (unset keeps all); recorded like `max_lines`
+    content_lines: std::sync::OnceLock<usize>,
+    // Git branch whose rows this handle reads and writes (`with_branch`); empty
+    // unless indexing is branch-aware
+    branch: String,
+    // `<table>.files.json` next to the table (`<table>.files.<branch>.json` for a
+    // branch), see `indexed_files`
+    manifest_path: std::path::PathBuf,
+    // `<table>.cleanup.json`, when `cleanup` last ran (see `cleanup_due`)
+    cleanup_path: std::path::PathBuf,
+    // Serializes writers (search-time re-indexing and the MCP watcher).
+    // Readers don't take it: LanceDB snapshots are consistent per query.
+    write_lock: tokio::sync::Mutex<()>,
+    // The existing table's columns don't match `chunk_schema` (older layout);
+    // cleared once `prepare_for_writes` drops it
+    outdated: AtomicBool,
+    // Recorded when the table is created; set by `prepare_for_writes`
+    embed_settings: std::sync::Mutex<EmbedSettings>,
 }
 
 impl VectorStore {
-    pub async fn new(path: &str) -> Result<Self> {
+    /// Opens (or prepares) `table` in the index at `path` for vectors of `dim`
+    /// dimensions from `model_id`. With `dim` None the width of the existing table
+    /// is used, for callers that only read.
+    pub async fn new(path: &str, table: &str, model_id: &str, dim: Option<usize>) -> Result<Self> {
         let conn = connect(path).execute().await?;
-        Ok(Self {
+        let mut store = Self {
             conn,
-            table_name: "code_chunks".to_string(),
+            table_name: table.to_string(),
+            model_id: model_id.to_string(),
+            dim: dim.map_or(DEFAULT_EMBEDDING_DIM, |d| d as i32),
+            max_lines: std::sync::OnceLock::new(),
+            content_lines: std::sync::OnceLock::new(),
+            branch: String::new(),
+            manifest_path: std::path::Path::new(path).join(format!("{}.files.json", table)),
+            cleanup_path: std::path::Path::new(path).join(format!("{}.cleanup.json", table)),
+            write_lock: tokio::sync::Mutex::new(()),
+            outdated: AtomicBool::new(false),
+            embed_settings: std::sync::Mutex::new(EmbedSettings::default()),
+        };
+        if dim.is_none() {
+            if let Some(existing) = store.embedding_dim().await? {
+                store.dim = existing as i32;
+            }
+        }
+        store.check_model_metadata(path).await?;
+        if store.has_outdated_layout().await? {
+            // Settings recorded by the old table don't apply to its rebuild
+            store.outdated.store(true, Ordering::Relaxed);
+            return Ok(store);
+        }
+        if let Some(max_lines) = store.recorded_setting(META_MAX_LINES).await? {
+            let _ = store.max_lines.set(max_lines);
+        }
+        if let Some(content_lines) = store.recorded_setting(META_CONTENT_LINES).await? {
+            let _ = store.content_lines.set(content_lines);
+        }
+        Ok(store)
+    }
+
+    /// Scopes this handle to the rows of `branch`: reads only see them and writes
+    /// only touch them, so the branches of a repository share one table.
+    pub fn with_branch(mut self, branch: &str) -> Self {
+        if !branch.is_empty() {
+            let name = format!("{}.files.{}.json", self.table_name, branch_suffix(branch));
+            self.manifest_path.set_file_name(name);
+        }
+        self.branch = branch.to_string();
+        self
+    }
+
+    /// `branch = '<branch>'`, the filter every read and write of this handle adds.
+    fn branch_predicate(&self) -> String {
+        format!("branch = '{}'", self.branch.replace('\'', "''"))
+    }
+
+    /// Index-time setting stored in an existing table's metadata (None for a new
+    /// table, or one created before the setting was recorded).
+    async fn recorded_setting(&self, key: &str) -> Result<Option<usize>> {
+        let table = match self.conn.open_table(&self.table_name).execute().await {
+            Ok(t) => t,
+            Err(_) => return Ok(None),
+        };
+        let schema = table.schema().await?;
+        Ok(schema.metadata().get(key).and_then(|n| n.parse().ok()))
+    }
+
+    /// Lines per chunk the table was built with, if known.
+    pub fn max_lines(&self) -> Option<usize> {
+        self.max_lines.get().copied()
+    }
+
+    /// Sets the chunk size recorded when the table is created. No-op once known:
+    /// an existing index keeps its size.
+    pub fn set_max_lines(&self, max_lines: usize) {
+        let _ = self.max_lines.set(max_lines);
+    }
+
+    /// Lines of each chunk's content the table keeps, if it truncates them.
+    pub fn content_lines(&self) -> Option<usize> {
+        self.content_lines.get().copied()
+    }
+
+    /// Truncates content stored from now on to `content_lines` lines and records it
+    /// when the table is created. No-op once known, like `set_max_lines`.
+    pub fn set_content_lines(&self, content_lines: usize) {
+        let _ = self.content_lines.set(content_lines);
+    }
+
+    /// Whether the existing table's columns don't match the current schema
+    /// (written by an older version). False when there is no table yet.
+    async fn has_outdated_layout(&self) -> Result<bool> {
+        let table = match self.conn.open_table(&self.table_name).execute().await {
+            Ok(t) => t,
+            Err(_) => return Ok(false),
+        };
+        let schema = table.schema().await?;
+        let current = self.chunk_schema();
+        let names = |s: &Schema| s.fields().iter().map(|f| f.name().clone()).collect::<Vec<_>>();
+        Ok(names(&schema) != names(&current))
+    }
+
+    /// Settings the existing table was embedded with (None before the first index).
+    async fn recorded_embed_settings(&self) -> Result<Option<EmbedSettings>> {
+        let table = match self.conn.open_table(&self.table_name).execute().await {
+            Ok(t) => t,
+            Err(_) => return Ok(None),
+        };
+        let schema = table.schema().await?;
+        Ok(Some(EmbedSettings::from_metadata(schema.metadata())))
+    }
+
+    /// Drops a table with an older layout, or one embedded with other `settings`,
+    /// so indexing rebuilds it instead of failing on insert or mixing vectors.
+    /// Only the indexing path calls this; readers get `ensure_current`'s error and
+    /// leave the table alone. Returns whether the table was dropped.
+    pub async fn prepare_for_writes(&self, path: &str, settings: EmbedSettings) -> Result<bool> {
+        let _guard = self.write_lock.lock().await;
+        *self.embed_settings.lock().unwrap_or_else(|e| e.into_inner()) = settings;
+        // Another handle (of another branch) may have rebuilt the table already
+        let reason = if self.outdated.swap(false, Ordering::Relaxed) && self.has_outdated_layout().await? {
+            Some("uses an older layout".to_string())
+        } else {
+            match self.recorded_embed_settings().await? {
+                Some(recorded) if recorded != settings => Some(format!("was embedded with {}", recorded)),
+                _ => None,
+            }
+        };
+        let Some(reason) = reason else {
+            return Ok(false);
+        };
+        eprintln!("Index at {} {}; rebuilding it", path, reason);
+        self.conn.drop_table(&self.table_name).await?;
+        // A new table restarts at version 1, which a stale manifest (of any
+        // branch) could match
+        self.remove_manifests();
+        let _ = std::fs::remove_file(&self.cleanup_path);
+        Ok(true)
+    }
+
+    /// Fails with `SearchError::IndexIncompatible` if the table has an older
+    /// layout, for read-only callers that must not rebuild it.
+    pub fn ensure_current(&self, path: &str) -> Result<()> {
+        if self.outdated.load(Ordering::Relaxed) {
+            return Err(SearchError::IndexIncompatible(format!(
+                "Index at {} uses an older layout; run a search to rebuild it",
+                path
+            )).into());
+        }
+        Ok(())
+    }
+
+    /// Verifies that an existing table was built by the current model.
+    /// Querying vectors from another model returns nonsense, so a mismatch is an error;
+    /// tables created before metadata was recorded only get a warning.
+    async fn check_model_metadata(&self, path: &str) -> Result<()> {
+        let table = match self.conn.open_table(&self.table_name).execute().await {
+            Ok(t) => t,
+            Err(_) => return Ok(()), // Nothing indexed yet
+        };
+        let schema = table.schema().await?;
+        let metadata = schema.metadata();
+
+        let (Some(model), Some(dim)) = (metadata.get(META_MODEL), metadata.get(META_DIM)) else {
+            warn_once(format!("Warning: index at {} has no model metadata (built by an older version); assuming {}", path, self.model_id));
+            return Ok(());
+        };
+
+        let expected_dim = self.dim.to_string();
+        if *model != self.model_id || *dim != expected_dim {
+            return Err(SearchError::IndexIncompatible(format!(
+                "Index at {} was built with model '{}' (dim {}), but the current model is '{}' (dim {}). \
+                 Delete the index directory to rebuild it.",
+                path, model, dim, self.model_id, expected_dim
+            )).into());
+        }
+        if metadata.get(META_NORMALIZED).map(String::as_str) != Some("true") {
+            warn_once(format!("Warning: index at {} does not record normalized embeddings; scores may be off", path));
+        }
+        Ok(())
+    }
+
+    fn chunk_schema(&self) -> Arc<Schema> {
+        let mut metadata = HashMap::from([
+            (META_MODEL.to_string(), self.model_id.clone()),
+            (META_DIM.to_string(), self.dim.to_string()),
+            // EmbeddingModel L2-normalizes, which the distance -> score mapping relies on
+            (META_NORMALIZED.to_string(), "true".to_string()),
+        ]);
+        if let Some(max_lines) = self.max_lines() {
+            metadata.insert(META_MAX_LINES.to_string(), max_lines.to_string());
+        }
+        if let Some(content_lines) = self.content_lines() {
+            metadata.insert(META_CONTENT_LINES.to_string(), content_lines.to_string());
+        }
+        self.embed_settings.lock().unwrap_or_else(|e| e.into_inner()).record(&mut metadata);
+
+        Arc::new(Schema::new(vec![
+            Field::new("file_path", DataType::Utf8, false),
+            Field::new("chunk_index", DataType::Int32, false),
+            Field::new("content", DataType::Utf8, false),
+            Field::new("line_start", DataType::Int32, false),
+            Field::new("line_end", DataType::Int32, false),
+            Field::new("mtime", DataType::Int64, false),
+            Field::new("symbol_start", DataType::Int32, false),
+            Field::new("symbol_end", DataType::Int32, false),
+            Field::new("symbol_name", DataType::Utf8, true),
+            Field::new("symbol_kind", DataType::Utf8, true),
+            Field::new("content_hash", DataType::Int64, false),
+            Field::new("branch", DataType::Utf8, false),
+            Field::new(
+                "vector",
+                DataType::FixedSizeList(
+                    Arc::new(Field::new("item", DataType::Float32, true)),
+                    self.dim,
+                ),
+                false,
+            ),
+        ]).with_metadata(metadata))
+    }
+
+    /// Table version, bumped by every write, so it identifies the index contents.
+    /// None if nothing is indexed yet.
+    pub async fn version(&self) -> Result<Option<u64>> {
+        match self.conn.open_table(&self.table_name).execute().await {
+            Ok(table) => Ok(Some(table.version().await?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Number of stored chunks of this branch (0 before the first index).
+    pub async fn count_chunks(&self) -> Result<usize> {
+        match self.conn.open_table(&self.table_name).execute().await {
+            Ok(table) => Ok(table.count_rows(Some(self.branch_predicate())).await?),
+            Err(_) => Ok(0),
+        }
+    }
+
+    /// Chunk and file counts plus the newest stored mtime.
+    pub async fn stats(&self) -> Result<IndexStats> {
+        let files = self.indexed_files().await?;
+        Ok(IndexStats {
+            chunks: self.count_chunks().await?,
+            files: files.len(),
+            newest_mtime: files.values().map(|file| file.mtime).max(),
         })
     }
 
+    /// Vector width of the existing table, or None if nothing is indexed yet.
+    pub async fn embedding_dim(&self) -> Result<Option<usize>> {
+        let table = match self.conn.open_table(&self.table_name).execute().await {
+            Ok(t) => t,
+            Err(_) => return Ok(None),
+        };
+        let schema = table.schema().await?;
+        let field = schema.field_with_name("vector")?;
+        match field.data_type() {
+            DataType::FixedSizeList(_, dim) => Ok(Some(*dim as usize)),
+            other => Err(anyhow::anyhow!("Unexpected vector column type: {}", other)),
+        }
+    }
+
+    /// Stored mtime of every indexed file.
     pub async fn get_indexed_metadata(&self) -> Result<HashMap<String, u64>> {
-        let mut map = HashMap::new();
+        let files = self.indexed_files().await?;
+        Ok(files.into_iter().map(|(path, file)| (path, file.mtime)).collect())
+    }
+
+    /// Chunk count and mtime of every indexed file. Served from the manifest while it
+    /// matches the table version (every write bumps it); otherwise recounted from the
+    /// table and saved again, e.g. after another process wrote to the index.
+    pub async fn indexed_files(&self) -> Result<HashMap<String, IndexedFile>> {
+        let Some(version) = self.version().await? else {
+            return Ok(HashMap::new());
+        };
+        if let Some(manifest) = self.read_manifest().filter(|m| m.version == version) {
+            return Ok(manifest.files);
+        }
+        let files = self.count_files().await?;
+        self.write_manifest(&FileManifest { version, files: files.clone() });
+        Ok(files)
+    }
+
+    /// Indexed file paths, sorted, limited to those matching the `filter` glob
+    /// (see `file_glob`) when one is given.
+    pub async fn list_files(&self, filter: Option<&str>) -> Result<Vec<String>> {
+        matching_files(self.indexed_files().await?.into_keys(), filter)
+    }
+
+    /// Deletes the manifests of every branch of this table.
+    fn remove_manifests(&self) {
+        let Some(dir) = self.manifest_path.parent() else { return };
+        let prefix = format!("{}.files.", self.table_name);
+        for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+            if entry.file_name().to_str().is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".json")) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    fn read_manifest(&self) -> Option<FileManifest> {
+        serde_json::from_slice(&std::fs::read(&self.manifest_path).ok()?).ok()
+    }
+
+    /// Best effort: without a manifest, `indexed_files` scans the table.
+    fn write_manifest(&self, manifest: &FileManifest) {
+        let tmp = self.manifest_path.with_extension("json.tmp");
+        let written = serde_json::to_vec(manifest).ok()
+            .is_some_and(|json| std::fs::write(&tmp, json).is_ok());
+        if !written || std::fs::rename(&tmp, &self.manifest_path).is_err() {
+            let _ = std::fs::remove_file(&self.manifest_path);
+        }
+    }
+
+    /// Applies a write to the manifest. `before` is the table version the write
+    /// started from: unless the manifest matched it and the write produced exactly
+    /// the next version (nobody else wrote in between), the manifest is dropped and
+    /// rebuilt on the next read.
+    async fn update_manifest(&self, before: Option<u64>, apply: impl FnOnce(&mut HashMap<String, IndexedFile>)) {
+        let after = self.version().await.ok().flatten();
+        let manifest = self.read_manifest().filter(|m| Some(m.version) == before);
+        match (manifest, before, after) {
+            (Some(mut manifest), Some(before), Some(after)) if after == before + 1 => {
+                apply(&mut manifest.files);
+                manifest.version = after;
+                self.write_manifest(&manifest);
+            }
+            _ => {
+                let _ = std::fs::remove_file(&self.manifest_path);
+            }
+        }
+    }
+
+    /// Counts chunks per file with one pass over the `file_path` and `mtime` columns.
+    async fn count_files(&self) -> Result<HashMap<String, IndexedFile>> {
+        let mut map: HashMap<String, IndexedFile> = HashMap::new();
         
         let table = match self.conn.open_table(&self.table_name).execute().await {
             Ok(t) => t,
@@ -40,7 +490,12 @@ impl VectorStore {
 
         // select needs Select enum
         let selection = Select::Columns(vec!["file_path".to_string(), "mtime".to_string()]);
-        let stream_result = table.query().select(selection).limit(1_000_000).execute().await;
+        let stream_result = table.query()
+            .only_if(self.branch_predicate())
+            .select(selection)
+            .limit(1_000_000)
+            .execute()
+            .await;
         
         let mut stream: SendableRecordBatchStream = match stream_result {
             Ok(s) => s,
@@ -61,9 +516,9 @@ impl VectorStore {
                 .ok_or(anyhow::anyhow!("Invalid mtime type"))?;
 
             for i in 0..batch.num_rows() {
-                let path = file_paths.value(i).to_string();
-                let mtime = mtimes.value(i) as u64;
-                map.insert(path, mtime);
+                let file = map.entry(file_paths.value(i).to_string())
+                    .or_insert(IndexedFile { chunks: 0, mtime: mtimes.value(i) as u64 });
+                file.chunks += 1;
             }
         }
         Ok(map)
@@ -73,24 +528,14 @@ impl VectorStore {
         if chunks.is_empty() {
             return Ok(());
         }
+        // The vector column has a fixed width
+        if let Some(bad) = embeddings.iter().find(|e| e.len() != self.dim as usize) {
+            anyhow::bail!("Embedding has {} dimensions, but the index stores {}", bad.len(), self.dim);
+        }
+        let _write_guard = self.write_lock.lock().await;
         eprintln!("Upserting {} chunks into LanceDB...", chunks.len());
 
-        let schema = Arc::new(Schema::new(vec![
-            Field::new("file_path", DataType::Utf8, false),
-            Field::new("chunk_index", DataType::Int32, false),
-            Field::new("content", DataType::Utf8, false),
-            Field::new("line_start", DataType::Int32, false),
-            Field::new("line_end", DataType::Int32, false),
-            Field::new("mtime", DataType::Int64, false),
-            Field::new(
-                "vector",
-                DataType::FixedSizeList(
-                    Arc::new(Field::new("item", DataType::Float32, true)),
-                    EMBEDDING_DIM,
-                ),
-                false,
-            ),
-        ]));
+        let schema = self.chunk_schema();
 
         let file_paths = StringArray::from(
             chunks.iter().map(|c| c.file_path.clone()).collect::<Vec<_>>()
@@ -99,7 +544,10 @@ impl VectorStore {
             chunks.iter().map(|c| c.chunk_index as i32).collect::<Vec<_>>()
         );
         let contents = StringArray::from(
-            chunks.iter().map(|c| c.content.clone()).collect::<Vec<_>>()
+            chunks.iter().map(|c| match self.content_lines() {
+                Some(n) => c.content.lines().take(n).collect::<Vec<_>>().join("\n"),
+                None => c.content.clone(),
+            }).collect::<Vec<_>>()
         );
         let line_starts = Int32Array::from(
             chunks.iter().map(|c| c.line_start as i32).collect::<Vec<_>>()
@@ -110,10 +558,26 @@ impl VectorStore {
         let mtimes = Int64Array::from(
             chunks.iter().map(|c| c.mtime as i64).collect::<Vec<_>>()
         );
+        let symbol_starts = Int32Array::from(
+            chunks.iter().map(|c| c.symbol_start as i32).collect::<Vec<_>>()
+        );
+        let symbol_ends = Int32Array::from(
+            chunks.iter().map(|c| c.symbol_end as i32).collect::<Vec<_>>()
+        );
+        let symbol_names = StringArray::from(
+            chunks.iter().map(|c| c.symbol_name.clone()).collect::<Vec<_>>()
+        );
+        let symbol_kinds = StringArray::from(
+            chunks.iter().map(|c| c.symbol_kind.clone()).collect::<Vec<_>>()
+        );
+        let content_hashes = Int64Array::from(
+            chunks.iter().map(|c| c.content_hash as i64).collect::<Vec<_>>()
+        );
+        let branches = StringArray::from(vec![self.branch.as_str(); chunks.len()]);
 
         let vectors = FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
             embeddings.iter().map(|e| Some(e.iter().map(|x| Some(*x)))),
-            EMBEDDING_DIM,
+            self.dim,
         );
 
         let batch = RecordBatch::try_new(
@@ -125,11 +589,18 @@ impl VectorStore {
                 Arc::new(line_starts),
                 Arc::new(line_ends),
                 Arc::new(mtimes),
+                Arc::new(symbol_starts),
+                Arc::new(symbol_ends),
+                Arc::new(symbol_names),
+                Arc::new(symbol_kinds),
+                Arc::new(content_hashes),
+                Arc::new(branches),
                 Arc::new(vectors),
             ],
         )?;
 
         let batches = RecordBatchIterator::new(vec![Ok(batch)], schema.clone());
+        let before = self.version().await?;
         
         match self.conn.open_table(&self.table_name).execute().await {
             Ok(table) => {
@@ -138,52 +609,211 @@ impl VectorStore {
                     .collect::<std::collections::HashSet<_>>()
                     .into_iter()
                     .collect();
-                 
-                 if !unique_files.is_empty() {
-                     let filter = unique_files.iter()
-                        .map(|f| format!("'{}'", f))
-                        .collect::<Vec<_>>()
-                        .join(", ");
-                     let predicate = format!("file_path IN ({})", filter);
-                     let _ = table.delete(&predicate).await;
-                 }
-                 table.add(batches).execute().await?;
+
+                 // Single atomic merge: replace matching chunks, insert new ones, and drop
+                 // leftover chunks of these files (when a file shrank) so readers never
+                 // observe a file with no chunks mid-update. Other branches' rows stay.
+                 let mut merge = table.merge_insert(&["branch", "file_path", "chunk_index"]);
+                 merge
+                    .when_matched_update_all(None)
+                    .when_not_matched_insert_all()
+                    .when_not_matched_by_source_delete(Some(format!(
+                        "{} AND {}", self.branch_predicate(), file_path_predicate(&unique_files)
+                    )));
+                 merge.execute(Box::new(batches)).await?;
             },
             Err(_) => {
                 self.conn.create_table(&self.table_name, batches).execute().await?;
             }
         }
+        self.update_manifest(before, |files| {
+            let mut written: HashMap<&str, IndexedFile> = HashMap::new();
+            for chunk in chunks {
+                written.entry(&chunk.file_path)
+                    .or_insert(IndexedFile { chunks: 0, mtime: chunk.mtime })
+                    .chunks += 1;
+            }
+            files.extend(written.into_iter().map(|(path, file)| (path.to_string(), file)));
+        }).await;
         Ok(())
     }
 
     pub async fn delete_files(&self, file_paths: &[String]) -> Result<()> {
         if file_paths.is_empty() { return Ok(()); }
+        let _write_guard = self.write_lock.lock().await;
         
         let table = match self.conn.open_table(&self.table_name).execute().await {
             Ok(t) => t,
             Err(_) => return Ok(()),
         };
         
-        let filter = file_paths.iter()
-            .map(|f| format!("'{}'", f))
-            .collect::<Vec<_>>()
-            .join(", ");
-        let predicate = format!("file_path IN ({})", filter);
-        table.delete(&predicate).await?;
+        let before = Some(table.version().await?);
+        table.delete(&format!("{} AND {}", self.branch_predicate(), file_path_predicate(file_paths))).await?;
+        self.update_manifest(before, |files| {
+            for path in file_paths {
+                files.remove(path);
+            }
+        }).await;
+        Ok(())
+    }
+
+    /// Content hash of each of `file_paths` that is in the index with a known hash.
+    pub async fn file_hashes(&self, file_paths: &[String]) -> Result<HashMap<String, u64>> {
+        let mut map = HashMap::new();
+        if file_paths.is_empty() { return Ok(map); }
+
+        let table = match self.conn.open_table(&self.table_name).execute().await {
+            Ok(t) => t,
+            Err(_) => return Ok(map),
+        };
+
+        let selection = Select::Columns(vec!["file_path".to_string(), "content_hash".to_string()]);
+        let mut stream: SendableRecordBatchStream = table.query()
+            .only_if(format!("{} AND {} AND content_hash != 0", self.branch_predicate(), file_path_predicate(file_paths)))
+            .select(selection)
+            .limit(1_000_000)
+            .execute()
+            .await?;
+
+        while let Some(batch) = stream.next().await {
+            let batch = batch?;
+            let paths = typed_column::<StringArray>(&batch, "file_path")?;
+            let hashes = typed_column::<Int64Array>(&batch, "content_hash")?;
+            for i in 0..batch.num_rows() {
+                map.insert(paths.value(i).to_string(), hashes.value(i) as u64);
+            }
+        }
+        Ok(map)
+    }
+
+    /// Moves the chunks of `old` to `new` in place, keeping their vectors.
+    /// `mtime` is the renamed file's, so the next diff sees it as up to date.
+    pub async fn rename_file(&self, old: &str, new: &str, mtime: u64) -> Result<()> {
+        let _write_guard = self.write_lock.lock().await;
+
+        let table = self.conn.open_table(&self.table_name).execute().await?;
+        let before = Some(table.version().await?);
+        table.update()
+            .only_if(format!("{} AND file_path = '{}'", self.branch_predicate(), old.replace('\'', "''")))
+            .column("file_path", format!("'{}'", new.replace('\'', "''")))
+            .column("mtime", mtime.to_string())
+            .execute()
+            .await?;
+        self.update_manifest(before, |files| {
+            if let Some(file) = files.remove(old) {
+                files.insert(new.to_string(), IndexedFile { mtime, ..file });
+            }
+        }).await;
         Ok(())
     }
 
-    pub async fn search(&self, query_embedding: &[f32], limit: usize) -> Result<Vec<SearchResult>> {
+    /// Copies the rows of files that another branch indexed with the same content
+    /// (`files` maps each path to its current content hash and mtime) into this
+    /// branch, vectors included, so switching branches only embeds what differs.
+    /// Returns the paths that were copied.
+    pub async fn adopt_from_other_branches(&self, files: &HashMap<String, (u64, u64)>) -> Result<Vec<String>> {
+        if files.is_empty() {
+            return Ok(Vec::new());
+        }
+        let table = match self.conn.open_table(&self.table_name).execute().await {
+            Ok(t) => t,
+            Err(_) => return Ok(Vec::new()),
+        };
+        let paths: Vec<String> = files.keys().cloned().collect();
+        let filter = format!("NOT ({}) AND {} AND content_hash != 0", self.branch_predicate(), file_path_predicate(&paths));
+        let rows = table.count_rows(Some(filter.clone())).await?;
+        if rows == 0 {
+            return Ok(Vec::new());
+        }
+        let mut batches: SendableRecordBatchStream = table.query().only_if(filter).limit(rows).execute().await?;
+
+        // Unchanged content chunks the same, so any other branch's copy will do
+        let mut found: HashMap<(String, usize), IndexedChunk> = HashMap::new();
+        while let Some(batch) = batches.next().await {
+            for row in indexed_chunks(&batch?)? {
+                if files.get(&row.file_path).is_some_and(|(hash, _)| *hash == row.content_hash) {
+                    found.entry((row.file_path.clone(), row.chunk_index)).or_insert(row);
+                }
+            }
+        }
+        let mut rows: Vec<IndexedChunk> = found.into_values().collect();
+        rows.sort_by(|a, b| (&a.file_path, a.chunk_index).cmp(&(&b.file_path, b.chunk_index)));
+        let mut adopted: Vec<String> = rows.iter().map(|row| row.file_path.clone()).collect();
+        adopted.dedup();
+
+        let mut embeddings = Vec::with_capacity(rows.len());
+        let chunks: Vec<FileChunk> = rows.into_iter().map(|row| {
+            embeddings.push(row.vector.unwrap_or_default());
+            FileChunk {
+                mtime: files[&row.file_path].1,
+                file_path: row.file_path,
+                chunk_index: row.chunk_index,
+                content: row.content,
+                line_start: row.line_start,
+                line_end: row.line_end,
+                doc_text: String::new(),
+                symbol_start: row.symbol_start,
+                symbol_end: row.symbol_end,
+                symbol_name: row.symbol_name,
+                symbol_kind: row.symbol_kind,
+                content_hash: row.content_hash,
+                imports: String::new(),
+                signature: String::new(),
+            }
+        }).collect();
+        self.upsert(&chunks, &embeddings).await?;
+        Ok(adopted)
+    }
+
+    /// Nearest chunks to `query_embedding`. A non-empty `kinds` restricts the search
+    /// to chunks with one of those symbol kinds, `min_mtime` to files modified at
+    /// or after it, and `file` to that one file (filtered inside LanceDB, so `limit`
+    /// still applies to matching chunks). Only this handle's branch is searched
+    /// unless `all_branches` is set.
+    /// Stored vectors are only read (into `SearchResult::embedding`) with `include_vector`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn search(
+        &self,
+        query_embedding: &[f32],
+        limit: usize,
+        kinds: &[String],
+        min_mtime: Option<u64>,
+        file: Option<&str>,
+        include_vector: bool,
+        all_branches: bool,
+    ) -> Result<Vec<SearchResult>> {
         let table = match self.conn.open_table(&self.table_name).execute().await {
             Ok(t) => t,
             Err(_) => return Ok(vec![]),
         };
         
-        let mut results: SendableRecordBatchStream = table
+        let mut query = table
             .vector_search(query_embedding.to_vec())?
-            .limit(limit)
-            .execute()
-            .await?;
+            .limit(limit);
+        let mut filters = Vec::new();
+        if !all_branches {
+            filters.push(self.branch_predicate());
+        }
+        if !kinds.is_empty() {
+            filters.push(in_predicate("symbol_kind", kinds));
+        }
+        if let Some(min_mtime) = min_mtime {
+            filters.push(format!("mtime >= {}", min_mtime));
+        }
+        if let Some(file) = file {
+            filters.push(format!("file_path = '{}'", file.replace('\'', "''")));
+        }
+        if !filters.is_empty() {
+            query = query.only_if(filters.join(" AND "));
+        }
+        if !include_vector {
+            let columns = self.chunk_schema().fields().iter()
+                .map(|f| f.name().clone())
+                .filter(|name| name != "vector")
+                .collect();
+            query = query.select(Select::Columns(columns));
+        }
+        let mut results: SendableRecordBatchStream = query.execute().await?;
 
         let mut search_results = Vec::new();
 
@@ -215,23 +845,48 @@ impl VectorStore {
             let line_ends: &Int32Array = line_end_col.as_any().downcast_ref::<Int32Array>()
                 .ok_or(anyhow::anyhow!("Invalid line_end"))?;
 
-            let dist_col = batch.column_by_name("_distance");
-            // Handle optional distance column
-            let distances: Option<&Float32Array> = if let Some(col) = dist_col {
-                col.as_any().downcast_ref::<Float32Array>()
-            } else {
-                None
+            let symbol_start_col: &Arc<dyn Array> = batch.column_by_name("symbol_start")
+                .ok_or(anyhow::anyhow!("Missing symbol_start"))?;
+            let symbol_starts: &Int32Array = symbol_start_col.as_any().downcast_ref::<Int32Array>()
+                .ok_or(anyhow::anyhow!("Invalid symbol_start"))?;
+
+            let symbol_end_col: &Arc<dyn Array> = batch.column_by_name("symbol_end")
+                .ok_or(anyhow::anyhow!("Missing symbol_end"))?;
+            let symbol_ends: &Int32Array = symbol_end_col.as_any().downcast_ref::<Int32Array>()
+                .ok_or(anyhow::anyhow!("Invalid symbol_end"))?;
+
+            let symbol_name_col: &Arc<dyn Array> = batch.column_by_name("symbol_name")
+                .ok_or(anyhow::anyhow!("Missing symbol_name"))?;
+            let symbol_names: &StringArray = symbol_name_col.as_any().downcast_ref::<StringArray>()
+                .ok_or(anyhow::anyhow!("Invalid symbol_name"))?;
+
+            let symbol_kind_col: &Arc<dyn Array> = batch.column_by_name("symbol_kind")
+                .ok_or(anyhow::anyhow!("Missing symbol_kind"))?;
+            let symbol_kinds: &StringArray = symbol_kind_col.as_any().downcast_ref::<StringArray>()
+                .ok_or(anyhow::anyhow!("Invalid symbol_kind"))?;
+
+            // The distance column's name is a LanceDB detail; if it ever goes missing,
+            // fall back to scoring by rank instead of dropping every result
+            let distances: Option<&Float32Array> = batch.column_by_name(DISTANCE_COLUMN)
+                .and_then(|col| col.as_any().downcast_ref::<Float32Array>());
+            let vectors = match batch.column_by_name("vector") {
+                Some(_) if include_vector => Some(typed_column::<FixedSizeListArray>(&batch, "vector")?),
+                _ => None,
             };
-            
+            if distances.is_none() {
+                MISSING_DISTANCE_WARNING.call_once(|| {
+                    eprintln!("Warning: vector search returned no {} column; scoring results by rank", DISTANCE_COLUMN);
+                });
+            }
+
             for i in 0..batch.num_rows() {
-                let dist = if let Some(d_arr) = distances {
-                     d_arr.value(i)
-                } else {
-                    0.0
+                let score = match distances {
+                    // Assuming L2 distance on normalized vectors (range 0.0 to 2.0)
+                    // Map to 0.0 - 1.0 similarity score
+                    Some(d_arr) => (1.0 - (d_arr.value(i) / 2.0)).max(0.0),
+                    // 1.0 for the nearest, decreasing linearly; kept above the 0.01 cutoff
+                    None => (1.0 - search_results.len() as f32 / limit.max(1) as f32).max(0.02),
                 };
-                // Assuming L2 distance on normalized vectors (range 0.0 to 2.0)
-                // Map to 0.0 - 1.0 similarity score
-                let score = (1.0 - (dist / 2.0)).max(0.0);
 
                 search_results.push(SearchResult {
                     file_path: file_paths.value(i).to_string(),
@@ -239,7 +894,21 @@ impl VectorStore {
                     content: contents.value(i).to_string(),
                     line_start: line_starts.value(i) as usize,
                     line_end: line_ends.value(i) as usize,
+                    symbol_start: symbol_starts.value(i) as usize,
+                    symbol_end: symbol_ends.value(i) as usize,
+                    symbol_name: symbol_names.is_valid(i).then(|| symbol_names.value(i).to_string()),
+                    symbol_kind: symbol_kinds.is_valid(i).then(|| symbol_kinds.value(i).to_string()),
                     score, 
+                    distance: distances.map(|d_arr| d_arr.value(i)),
+                    embedding: match vectors {
+                        Some(list) => Some(
+                            list.value(i).as_any().downcast_ref::<Float32Array>()
+                                .ok_or_else(|| anyhow::anyhow!("Invalid vector"))?
+                                .values()
+                                .to_vec()
+                        ),
+                        None => None,
+                    },
                 });
             }
         }
@@ -247,21 +916,71 @@ impl VectorStore {
         Ok(search_results)
     }
 
+    /// Streams every stored chunk of this branch in storage order. Vectors make up
+    /// most of the table, so they are only read when `with_vectors` is set.
+    pub async fn scan_all(&self, with_vectors: bool) -> Result<BoxStream<'static, Result<IndexedChunk>>> {
+        let table = match self.conn.open_table(&self.table_name).execute().await {
+            Ok(t) => t,
+            Err(_) => return Ok(futures::stream::empty().boxed()),
+        };
+
+        // Plain queries are capped by a default limit; ask for every row
+        let rows = table.count_rows(Some(self.branch_predicate())).await?;
+        let mut query = table.query().only_if(self.branch_predicate()).limit(rows.max(1));
+        if !with_vectors {
+            let columns = self.chunk_schema().fields().iter()
+                .map(|f| f.name().clone())
+                .filter(|name| name != "vector")
+                .collect();
+            query = query.select(Select::Columns(columns));
+        }
+        let batches: SendableRecordBatchStream = query.execute().await?;
+
+        Ok(batches
+            .map(|batch| -> Result<Vec<IndexedChunk>> { indexed_chunks(&batch?) })
+            .flat_map(|rows| futures::stream::iter(match rows {
+                Ok(rows) => rows.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(e) => vec![Err(e)],
+            }))
+            .boxed())
+    }
+
+    /// Whether automatic cleanup should run: the table changed since the last
+    /// cleanup, and either `CLEANUP_EVERY_VERSIONS` versions piled up or the last
+    /// one is over `CLEANUP_INTERVAL_SECS` old. Read-only use never triggers it.
+    pub async fn cleanup_due(&self) -> Result<bool> {
+        let Some(version) = self.version().await? else {
+            return Ok(false);
+        };
+        let last: Option<LastCleanup> = std::fs::read(&self.cleanup_path).ok()
+            .and_then(|json| serde_json::from_slice(&json).ok());
+        Ok(match last {
+            None => true,
+            Some(last) if version <= last.version => false,
+            Some(last) => version - last.version >= CLEANUP_EVERY_VERSIONS
+                || unix_now().saturating_sub(last.at) >= CLEANUP_INTERVAL_SECS,
+        })
+    }
+
     pub async fn cleanup(&self) -> Result<()> {
          // Cleanup old versions to prevent disk bloat.
          // Lancedb 0.14 uses `optimize` with `OptimizeAction::Prune`.
-         // We keep versions from the last 1 hour.
+         // We keep versions within `prune_window()` (1 hour by default).
          let table = match self.conn.open_table(&self.table_name).execute().await {
             Ok(t) => t,
             Err(_) => return Ok(()),
         };
 
         use lancedb::table::OptimizeAction;
+        // Compaction commits new versions without changing the rows
+        let version = table.version().await.ok();
+        let manifest = self.read_manifest().filter(|m| Some(m.version) == version);
         
         // 1. Prune old versions
         match table.optimize(OptimizeAction::Prune { 
-            older_than: Some(chrono::Duration::hours(1)), 
-            delete_unverified: Some(false),
+            older_than: Some(prune_window()), 
+            // Files without a committed version may belong to a write still in progress
+            delete_unverified: Some(env_flag("CODE_SEARCH_PRUNE_DELETE_UNVERIFIED")),
             error_if_tagged_old_versions: Some(false)
         }).await {
              Ok(_) => {
@@ -285,16 +1004,265 @@ impl VectorStore {
             }
         }
 
+        if let Ok(Some(version)) = self.version().await {
+            if let Some(mut manifest) = manifest {
+                manifest.version = version;
+                self.write_manifest(&manifest);
+            }
+            let last = LastCleanup { version, at: unix_now() };
+            if let Ok(json) = serde_json::to_vec(&last) {
+                let _ = std::fs::write(&self.cleanup_path, json);
+            }
+        }
         Ok(())
     }
 }
 
+/// Looks up a column by name and downcasts it to its array type.
+fn typed_column<'a, T: Array + 'static>(batch: &'a RecordBatch, name: &str) -> Result<&'a T> {
+    batch.column_by_name(name)
+        .ok_or_else(|| anyhow::anyhow!("Missing {}", name))?
+        .as_any()
+        .downcast_ref::<T>()
+        .ok_or_else(|| anyhow::anyhow!("Invalid {}", name))
+}
+
+/// Rows of a full-table batch; `vector` is None when the column wasn't selected.
+fn indexed_chunks(batch: &RecordBatch) -> Result<Vec<IndexedChunk>> {
+    let file_paths = typed_column::<StringArray>(batch, "file_path")?;
+    let chunk_indices = typed_column::<Int32Array>(batch, "chunk_index")?;
+    let contents = typed_column::<StringArray>(batch, "content")?;
+    let line_starts = typed_column::<Int32Array>(batch, "line_start")?;
+    let line_ends = typed_column::<Int32Array>(batch, "line_end")?;
+    let mtimes = typed_column::<Int64Array>(batch, "mtime")?;
+    let symbol_starts = typed_column::<Int32Array>(batch, "symbol_start")?;
+    let symbol_ends = typed_column::<Int32Array>(batch, "symbol_end")?;
+    let symbol_names = typed_column::<StringArray>(batch, "symbol_name")?;
+    let symbol_kinds = typed_column::<StringArray>(batch, "symbol_kind")?;
+    let content_hashes = typed_column::<Int64Array>(batch, "content_hash")?;
+    let vectors = match batch.column_by_name("vector") {
+        Some(_) => Some(typed_column::<FixedSizeListArray>(batch, "vector")?),
+        None => None,
+    };
+
+    let mut rows = Vec::with_capacity(batch.num_rows());
+    for i in 0..batch.num_rows() {
+        let vector = match vectors {
+            Some(list) => Some(
+                list.value(i).as_any().downcast_ref::<Float32Array>()
+                    .ok_or_else(|| anyhow::anyhow!("Invalid vector"))?
+                    .values()
+                    .to_vec()
+            ),
+            None => None,
+        };
+        rows.push(IndexedChunk {
+            file_path: file_paths.value(i).to_string(),
+            line_start: line_starts.value(i) as usize,
+            line_end: line_ends.value(i) as usize,
+            content: contents.value(i).to_string(),
+            mtime: mtimes.value(i) as u64,
+            chunk_index: chunk_indices.value(i) as usize,
+            symbol_start: symbol_starts.value(i) as usize,
+            symbol_end: symbol_ends.value(i) as usize,
+            symbol_name: symbol_names.is_valid(i).then(|| symbol_names.value(i).to_string()),
+            symbol_kind: symbol_kinds.is_valid(i).then(|| symbol_kinds.value(i).to_string()),
+            content_hash: content_hashes.value(i) as u64,
+            vector,
+            model: None,
+        });
+    }
+    Ok(rows)
+}
+
+/// A branch name as part of a file or table name: other characters than letters,
+/// digits, `_`, `-` and `.` become `-`, plus a hash so `a/b` and `a-b` stay apart.
+pub fn branch_suffix(branch: &str) -> String {
+    let safe: String = branch.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') { c } else { '-' })
+        .collect();
+    if safe == branch {
+        safe
+    } else {
+        format!("{}-{:08x}", safe, content_hash(branch.as_bytes()) as u32)
+    }
+}
+
+/// Builds a `file_path IN (...)` filter, escaping quotes in paths.
+fn file_path_predicate(file_paths: &[String]) -> String {
+    in_predicate("file_path", file_paths)
+}
+
+/// Builds a `<column> IN (...)` filter over string values, escaping quotes.
+fn in_predicate(column: &str, values: &[String]) -> String {
+    let list = values.iter()
+        .map(|v| format!("'{}'", v.replace('\'', "''")))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("{} IN ({})", column, list)
+}
+
+#[derive(Clone, Debug)]
 pub struct SearchResult {
     pub file_path: String,
-    #[allow(dead_code)]
     pub chunk_index: usize,
     pub content: String,
     pub line_start: usize,
     pub line_end: usize,
+    /// Line range of the enclosing symbol (see `FileChunk::symbol_start`)
+    pub symbol_start: usize,
+    pub symbol_end: usize,
+    pub symbol_name: Option<String>,
+    pub symbol_kind: Option<String>,
     pub score: f32,
+    /// Raw distance to the query vector as LanceDB reported it, for results of `VectorStore::search`
+    pub distance: Option<f32>,
+    /// Stored vector of the chunk, when requested from `VectorStore::search`
+    pub embedding: Option<Vec<f32>>,
+}
+
+impl SearchResult {
+    /// ` — parse_config (function)` suffix for result headers; empty for non-AST chunks.
+    pub fn symbol_label(&self) -> String {
+        match (&self.symbol_name, &self.symbol_kind) {
+            (Some(name), Some(kind)) => format!(" — {} ({})", name, kind),
+            (None, Some(kind)) => format!(" — ({})", kind),
+            _ => String::new(),
+        }
+    }
+
+    /// Notebook cells carry the cell number in `line_start`/`line_end` rather than
+    /// a line of the file, so nothing may read file lines at them.
+    pub fn lines_are_cells(&self) -> bool {
+        self.symbol_kind.as_deref() == Some(crate::scanner::CELL_KIND)
+    }
+
+    /// Keeps the first `max_lines` lines of `content` for display, followed by a
+    /// `… (N more lines)` marker. `line_start`/`line_end` still cover the whole chunk.
+    pub fn truncate_content(&mut self, max_lines: usize) {
+        let total = self.content.lines().count();
+        if total <= max_lines {
+            return;
+        }
+        let kept: Vec<&str> = self.content.lines().take(max_lines).collect();
+        self.content = format!("{}\n… ({} more lines)", kept.join("\n"), total - max_lines);
+    }
+}
+
+/// Size and freshness of an index, as reported by the MCP `index_status` tool.
+#[derive(serde::Serialize, Debug, Default)]
+pub struct IndexStats {
+    pub chunks: usize,
+    pub files: usize,
+    /// Newest modification time (Unix seconds) among indexed files; None when empty
+    pub newest_mtime: Option<u64>,
+}
+
+/// What the index holds for one file.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy)]
+pub struct IndexedFile {
+    pub chunks: usize,
+    pub mtime: u64,
+}
+
+/// Per-file summary of a table as of `version`, saved as `<table>.files.json`.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct FileManifest {
+    version: u64,
+    files: HashMap<String, IndexedFile>,
+}
+
+/// Compiles a gitignore-style glob over repo-relative paths: `src/**/*.rs` is
+/// anchored at the root, a pattern without a slash like `*.py` matches anywhere.
+pub fn file_glob(pattern: &str) -> Result<ignore::overrides::Override> {
+    let mut builder = ignore::overrides::OverrideBuilder::new("");
+    builder.add(pattern)
+        .map_err(|e| anyhow::anyhow!("Invalid glob '{}': {}", pattern, e))?;
+    Ok(builder.build()?)
+}
+
+/// `files`, sorted, limited to those matching the `filter` glob (`file_glob`).
+fn matching_files(files: impl IntoIterator<Item = String>, filter: Option<&str>) -> Result<Vec<String>> {
+    let glob = filter.map(file_glob).transpose()?;
+    let mut files: Vec<String> = files.into_iter()
+        .filter(|file| glob.as_ref().is_none_or(|glob| glob.matched(file, false).is_whitelist()))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Saved as `<table>.cleanup.json` by `cleanup`.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct LastCleanup {
+    /// Table version right after the cleanup
+    version: u64,
+    /// Unix seconds
+    at: u64,
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// One stored row, as written by `export` and read back by `import` (one JSON object per line).
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct IndexedChunk {
+    pub file_path: String,
+    pub line_start: usize,
+    pub line_end: usize,
+    pub content: String,
+    pub mtime: u64,
+    pub chunk_index: usize,
+    pub symbol_start: usize,
+    pub symbol_end: usize,
+    pub symbol_name: Option<String>,
+    pub symbol_kind: Option<String>,
+    /// Missing from exports made before it was stored
+    #[serde(default)]
+    pub content_hash: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector: Option<Vec<f32>>,
+    /// Model that made `vector`, stamped by `export --with-vectors`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embed_settings_round_trip_through_metadata() {
+        let settings = EmbedSettings { include_imports: true, embed_header: true, normalize: true };
+        let mut metadata = HashMap::new();
+        settings.record(&mut metadata);
+        assert_eq!(EmbedSettings::from_metadata(&metadata), settings);
+    }
+
+    #[test]
+    fn globs_match_repository_relative_paths() {
+        let files = || ["src/main.rs", "src/store/mod.rs", "tests/cli.rs", "scripts/build.py", "README.md"].map(String::from);
+        // Anchored at the root with a slash, at any depth without one
+        assert_eq!(matching_files(files(), Some("src/**/*.rs")).unwrap(), ["src/main.rs", "src/store/mod.rs"]);
+        assert_eq!(matching_files(files(), Some("*.rs")).unwrap(), ["src/main.rs", "src/store/mod.rs", "tests/cli.rs"]);
+        assert_eq!(matching_files(files(), Some("/*.md")).unwrap(), ["README.md"]);
+        assert_eq!(matching_files(files(), None).unwrap().len(), 5);
+        assert!(file_glob("src/[").is_err());
+    }
+
+    #[test]
+    fn warnings_print_once_per_message() {
+        let message = format!("Warning: index at /tmp/{} has no model metadata", uuid::Uuid::new_v4());
+        assert!(warn_once(message.clone()));
+        assert!(!warn_once(message.clone()));
+        assert!(warn_once(format!("{} (other index)", message)));
+    }
+
+    #[test]
+    fn missing_embed_settings_count_as_off() {
+        assert_eq!(EmbedSettings::from_metadata(&HashMap::new()), EmbedSettings::default());
+        assert_ne!(EmbedSettings::default(), EmbedSettings { embed_header: true, ..Default::default() });
+    }
 }
@@ -1,17 +1,55 @@
 use anyhow::Result;
+use crate::error::SearchError;
+use crate::scanner::FileChunk;
+use crate::store::SearchResult;
+use std::collections::HashSet;
 use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
 use tantivy::collector::TopDocs;
 use tantivy::query::QueryParser;
-use tantivy::schema::{Schema, TEXT, STORED, STRING, Field, Value};
+use tantivy::schema::{Schema, TEXT, STORED, STRING, Field, Value, IndexRecordOption, TextFieldIndexing, TextOptions};
+use tantivy::tokenizer::{LowerCaser, RemoveLongFilter, SimpleTokenizer, StopWordFilter, TextAnalyzer};
 use tantivy::{doc, Index, IndexWriter, Term, TantivyDocument};
 use tantivy::directory::MmapDirectory;
 
+/// Weight of comment/docstring matches relative to code matches.
+/// Natural-language queries tend to hit the docs, not the identifiers.
+const DOC_FIELD_BOOST: f32 = 2.0;
+
+/// Default writer buffer in bytes, overridable via `CODE_SEARCH_TANTIVY_BUFFER`.
+const DEFAULT_WRITER_BUFFER: usize = 50_000_000;
+/// Tantivy rejects writer threads with less than ~15MB of arena each.
+const MIN_BUFFER_PER_THREAD: usize = 15_000_000;
+/// Tantivy's own cap on indexing threads.
+const MAX_WRITER_THREADS: usize = 8;
+/// Hits fetched for rank fusion in `search`.
+const FUSION_HITS: usize = 50;
+
+/// Tokenizer for the `content` field: tantivy's default chain plus stopwords.
+/// Registered under this prefix and a hash of the stopword list (`tokenizer_name`).
+const CODE_TOKENIZER: &str = "code";
+/// Keywords that occur in nearly every chunk and only dilute BM25 scores.
+/// Replaced by the file named in `CODE_SEARCH_STOPWORDS`.
+const DEFAULT_STOPWORDS: &[&str] = &[
+    "self", "this", "let", "const", "var", "mut", "pub", "public", "private", "protected",
+    "static", "return", "if", "else", "for", "in", "fn", "def", "function", "new",
+    "true", "false", "null", "none", "nil", "void", "import", "use", "from",
+];
+
 pub struct TextIndex {
     index: Index,
-    writer: Arc<RwLock<IndexWriter>>,
+    // None for an index opened with `open_read_only`
+    writer: Option<Arc<RwLock<IndexWriter>>>,
     path_field: Field,
     content_field: Field,
+    doc_field: Field,
+    // Stored only, so `search_chunks` can return results without the vector store
+    line_start_field: Field,
+    line_end_field: Field,
+    symbol_start_field: Field,
+    symbol_end_field: Field,
+    symbol_name_field: Field,
+    symbol_kind_field: Field,
 }
 
 impl TextIndex {
@@ -21,49 +59,176 @@ impl TextIndex {
              std::fs::create_dir_all(index_path)?;
         }
 
-        let mut schema_builder = Schema::builder();
-        // Use STRING for path (exact match, untokenized)
-        let path_field = schema_builder.add_text_field("path", STRING | STORED);
-        let content_field = schema_builder.add_text_field("content", TEXT);
-        let schema = schema_builder.build();
-
+        let stopwords = stopwords()?;
+        let tokenizer = tokenizer_name(&stopwords);
+        let schema = text_schema(&tokenizer);
         let dir = MmapDirectory::open(index_path)?;
-        let index = Index::open_or_create(dir, schema.clone())?;
-        
-        // 50MB buffer
-        let writer = index.writer(50_000_000)?;
+        let index = match Index::open_or_create(dir, schema.clone()) {
+            Ok(index) => index,
+            Err(tantivy::TantivyError::SchemaError(_)) => {
+                // Built by an older version or with other stopwords; start over
+                eprintln!("Text index schema changed, rebuilding: {}", path_str);
+                std::fs::remove_dir_all(index_path)?;
+                std::fs::create_dir_all(index_path)?;
+                Index::create_in_dir(index_path, schema.clone())?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // Tokenizers aren't persisted with the index, only their name in the schema, so
+        // register on every open; the query parser picks it up too, dropping stopwords from queries
+        index.tokenizers().register(&tokenizer, code_analyzer(stopwords));
+
+        // The buffer is allocated up front and split across the writer threads,
+        // so thread count is bounded by both the cores and the budget.
+        let buffer_size = writer_buffer_size();
+        let num_threads = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(MAX_WRITER_THREADS)
+            .min(buffer_size / MIN_BUFFER_PER_THREAD)
+            .max(1);
+        let writer = index.writer_with_num_threads(num_threads, buffer_size)?;
+        Self::from_index(index, Some(Arc::new(RwLock::new(writer))))
+    }
 
+    /// Opens an existing index for searching only: no writer lock is taken, so an
+    /// indexing process can keep it, and nothing on disk is changed. An index with
+    /// another schema is an error rather than a rebuild.
+    pub fn open_read_only(path_str: &str) -> Result<Self> {
+        let index = Index::open_in_dir(path_str)?;
+        let stopwords = stopwords()?;
+        let tokenizer = tokenizer_name(&stopwords);
+        if index.schema() != text_schema(&tokenizer) {
+            return Err(SearchError::IndexIncompatible(format!(
+                "Text index at {} was built by another version or with other stopwords; \
+                 run a search without --text-only to rebuild it",
+                path_str
+            )).into());
+        }
+        index.tokenizers().register(&tokenizer, code_analyzer(stopwords));
+        Self::from_index(index, None)
+    }
+
+    fn from_index(index: Index, writer: Option<Arc<RwLock<IndexWriter>>>) -> Result<Self> {
+        let schema = index.schema();
         Ok(Self {
+            path_field: schema.get_field("path")?,
+            content_field: schema.get_field("content")?,
+            doc_field: schema.get_field("doc")?,
+            line_start_field: schema.get_field("line_start")?,
+            line_end_field: schema.get_field("line_end")?,
+            symbol_start_field: schema.get_field("symbol_start")?,
+            symbol_end_field: schema.get_field("symbol_end")?,
+            symbol_name_field: schema.get_field("symbol_name")?,
+            symbol_kind_field: schema.get_field("symbol_kind")?,
             index,
-            writer: Arc::new(RwLock::new(writer)),
-            path_field,
-            content_field,
+            writer,
         })
     }
 
+    /// Takes the writer lock, recovering it if a previous holder panicked.
+    /// A panic mid-write cannot leave the `IndexWriter` half-updated (its operations
+    /// are queued until commit), so poisoning would otherwise just wedge the
+    /// long-running MCP server for no benefit.
+    fn lock_writer(&self) -> RwLockWriteGuard<'_, IndexWriter> {
+        self.writer.as_ref()
+            .expect("text index opened read-only")
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
     pub fn save(&self, _path: &str) -> Result<()> {
         // Commit changes. Path arg is ignored as Tantivy manages its own dir.
-        let mut writer = self.writer.write().unwrap();
+        let mut writer = self.lock_writer();
         writer.commit()?;
         Ok(())
     }
 
-    pub fn index_text(&self, file_path: &str, content: &str) -> Result<()> {
-        let writer = self.writer.write().unwrap();
+    /// Number of indexed chunks (committed ones only).
+    pub fn num_docs(&self) -> u64 {
+        self.index.reader().map_or(0, |reader| reader.searcher().num_docs())
+    }
+
+    /// Replaces the indexed chunks of every file in `chunks` with these. A file's
+    /// chunks must all be passed in the same call: the old documents are deleted
+    /// first, which only affects documents added before the delete.
+    pub fn index_chunks(&self, chunks: &[FileChunk]) -> Result<()> {
+        let writer = self.lock_writer();
         
-        // Delete existing document for this path to support updates
-        let term = Term::from_field_text(self.path_field, file_path);
-        writer.delete_term(term);
+        // Delete existing documents for these paths to support updates
+        let paths: HashSet<&str> = chunks.iter().map(|c| c.file_path.as_str()).collect();
+        for path in paths {
+            writer.delete_term(Term::from_field_text(self.path_field, path));
+        }
         
-        writer.add_document(tantivy::doc!(
-            self.path_field => file_path,
-            self.content_field => content,
-        ))?;
+        for chunk in chunks {
+            let mut document = tantivy::doc!(
+                self.path_field => chunk.file_path.as_str(),
+                self.content_field => chunk.content.as_str(),
+                self.doc_field => chunk.doc_text.as_str(),
+                self.line_start_field => chunk.line_start as u64,
+                self.line_end_field => chunk.line_end as u64,
+                self.symbol_start_field => chunk.symbol_start as u64,
+                self.symbol_end_field => chunk.symbol_end as u64,
+            );
+            if let Some(name) = &chunk.symbol_name {
+                document.add_text(self.symbol_name_field, name);
+            }
+            if let Some(kind) = &chunk.symbol_kind {
+                document.add_text(self.symbol_kind_field, kind);
+            }
+            writer.add_document(document)?;
+        }
         
         Ok(())
     }
     
-    pub fn search(&self, query_str: &str) -> Vec<(String, f32)> {
+    /// Drops every chunk of these files.
+    pub fn delete_files(&self, paths: &[String]) {
+        let writer = self.lock_writer();
+        for path in paths {
+            writer.delete_term(Term::from_field_text(self.path_field, path));
+        }
+    }
+
+    /// Paths of the best-matching chunks, best first (a path repeats once per matching chunk).
+    /// With `match_all`, a chunk must contain every query term instead of any of them.
+    pub fn search(&self, query_str: &str, match_all: bool) -> Vec<(String, f32)> {
+        self.top_docs(query_str, FUSION_HITS, match_all)
+            .into_iter()
+            .filter_map(|(score, doc)| Some((self.text(&doc, self.path_field)?, score)))
+            .collect()
+    }
+
+    /// The `limit` best-matching chunks with their stored content, scored by BM25.
+    pub fn search_chunks(&self, query_str: &str, limit: usize, match_all: bool) -> Vec<SearchResult> {
+        self.top_docs(query_str, limit, match_all)
+            .into_iter()
+            .filter_map(|(score, doc)| {
+                let number = |field| doc.get_first(field).and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+                Some(SearchResult {
+                    file_path: self.text(&doc, self.path_field)?,
+                    chunk_index: 0,
+                    content: self.text(&doc, self.content_field)?,
+                    line_start: number(self.line_start_field),
+                    line_end: number(self.line_end_field),
+                    symbol_start: number(self.symbol_start_field),
+                    symbol_end: number(self.symbol_end_field),
+                    symbol_name: self.text(&doc, self.symbol_name_field),
+                    symbol_kind: self.text(&doc, self.symbol_kind_field),
+                    score,
+                    distance: None,
+                    embedding: None,
+                })
+            })
+            .collect()
+    }
+
+    fn text(&self, doc: &TantivyDocument, field: Field) -> Option<String> {
+        doc.get_first(field).and_then(|v| v.as_str()).map(str::to_string)
+    }
+
+    fn top_docs(&self, query_str: &str, limit: usize, match_all: bool) -> Vec<(f32, TantivyDocument)> {
         let reader = match self.index.reader_builder()
             .try_into() {
                 Ok(r) => r,
@@ -74,15 +239,24 @@ impl TextIndex {
             };
             
         let searcher = reader.searcher();
-        let query_parser = QueryParser::for_index(&self.index, vec![self.content_field]);
+        let mut query_parser = QueryParser::for_index(&self.index, vec![self.content_field, self.doc_field]);
+        query_parser.set_field_boost(self.doc_field, DOC_FIELD_BOOST);
+        // Explicit either way: tantivy's default (any term) is easy to mistake for all
+        if match_all {
+            query_parser.set_conjunction_by_default();
+        }
         
         let query = match query_parser.parse_query(query_str) {
             Ok(q) => q,
-            Err(_) => return vec![], // Invalid query
+            // A stray `:` or `(` shouldn't drop the lexical side; the lenient parser
+            // skips the parts it can't make sense of
+            Err(e) => {
+                eprintln!("Warning: keyword query {:?} doesn't parse ({}); searching the parts that do", query_str, e);
+                query_parser.parse_query_lenient(query_str).0
+            }
         };
         
-        // Get top 50 results
-        let top_docs = match searcher.search(&query, &TopDocs::with_limit(50)) {
+        let top_docs = match searcher.search(&query, &TopDocs::with_limit(limit.max(1))) {
             Ok(docs) => docs,
             Err(_) => return vec![],
         };
@@ -93,16 +267,191 @@ impl TextIndex {
                 Ok(doc) => doc,
                 Err(_) => continue,
             };
-            
-            let path_val_opt = retrieved_doc.get_first(self.path_field);
-            if let Some(path_val) = path_val_opt {
-                let path_opt: Option<&str> = path_val.as_str();
-                if let Some(path) = path_opt {
-                     results.push((path.to_string(), score));
-                }
-            }
+            results.push((score, retrieved_doc));
         }
         
         results
     }
 }
+
+fn text_schema(code_tokenizer: &str) -> Schema {
+    let mut schema_builder = Schema::builder();
+    // Use STRING for path (exact match, untokenized)
+    schema_builder.add_text_field("path", STRING | STORED);
+    let code_indexing = TextFieldIndexing::default()
+        .set_tokenizer(code_tokenizer)
+        .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+    schema_builder.add_text_field("content", TextOptions::default().set_indexing_options(code_indexing).set_stored());
+    schema_builder.add_text_field("doc", TEXT);
+    schema_builder.add_u64_field("line_start", STORED);
+    schema_builder.add_u64_field("line_end", STORED);
+    schema_builder.add_u64_field("symbol_start", STORED);
+    schema_builder.add_u64_field("symbol_end", STORED);
+    schema_builder.add_text_field("symbol_name", STORED);
+    schema_builder.add_text_field("symbol_kind", STORED);
+    schema_builder.build()
+}
+
+/// `code-<hash of the stopwords>`: the schema records the name, so an index built
+/// with another list no longer matches it and is rebuilt.
+fn tokenizer_name(stopwords: &[String]) -> String {
+    format!("{}-{:016x}", CODE_TOKENIZER, crate::scanner::content_hash(stopwords.join("\n").as_bytes()))
+}
+
+fn code_analyzer(stopwords: Vec<String>) -> TextAnalyzer {
+    TextAnalyzer::builder(SimpleTokenizer::default())
+        .filter(RemoveLongFilter::limit(40))
+        .filter(LowerCaser)
+        .filter(StopWordFilter::remove(stopwords))
+        .build()
+}
+
+/// Reads the stopword list: one word per line in the `CODE_SEARCH_STOPWORDS` file
+/// (blank lines and `#` comments skipped, an empty file disables filtering),
+/// or the built-in list when unset.
+fn stopwords() -> Result<Vec<String>> {
+    let Ok(path) = std::env::var("CODE_SEARCH_STOPWORDS") else {
+        return Ok(DEFAULT_STOPWORDS.iter().map(|w| w.to_string()).collect());
+    };
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| anyhow::anyhow!("Cannot read CODE_SEARCH_STOPWORDS file {}: {}", path, e))?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_lowercase)
+        .collect())
+}
+
+fn writer_buffer_size() -> usize {
+    std::env::var("CODE_SEARCH_TANTIVY_BUFFER")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_WRITER_BUFFER)
+        .max(MIN_BUFFER_PER_THREAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("code-search-test-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn chunk(path: &str, content: &str) -> FileChunk {
+        FileChunk {
+            file_path: path.to_string(),
+            chunk_index: 0,
+            content: content.to_string(),
+            line_start: 1,
+            line_end: content.lines().count(),
+            mtime: 0,
+            doc_text: String::new(),
+            symbol_start: 1,
+            symbol_end: content.lines().count(),
+            symbol_name: None,
+            symbol_kind: None,
+            content_hash: 0,
+            imports: String::new(),
+            signature: String::new(),
+        }
+    }
+
+    #[test]
+    fn a_poisoned_writer_lock_is_recovered() {
+        let dir = temp_dir();
+        let path = dir.to_str().unwrap();
+        let index = Arc::new(TextIndex::load_or_create(path).unwrap());
+        let holder = index.clone();
+        let panicked = std::thread::spawn(move || {
+            let _writer = holder.lock_writer();
+            panic!("indexing failed while holding the writer");
+        }).join();
+        assert!(panicked.is_err());
+        assert!(index.writer.as_ref().unwrap().is_poisoned());
+
+        index.index_chunks(&[chunk("src/ok.rs", "fn still_works() {}")]).unwrap();
+        index.save(path).unwrap();
+        assert_eq!(index.search_chunks("still_works", 5, false).len(), 1);
+        drop(index);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn read_only_open_searches_while_a_writer_holds_the_lock() {
+        let dir = temp_dir();
+        let path = dir.to_str().unwrap();
+        let writable = TextIndex::load_or_create(path).unwrap();
+        writable.index_chunks(&[chunk("src/parse.rs", "fn parse_config() {}")]).unwrap();
+        writable.save(path).unwrap();
+
+        let read_only = TextIndex::open_read_only(path).unwrap();
+        let hits = read_only.search_chunks("parse_config", 5, false);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].file_path, "src/parse.rs");
+        drop(writable);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn read_only_open_rejects_another_schema_and_keeps_it() {
+        let dir = temp_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut builder = Schema::builder();
+        builder.add_text_field("path", STRING | STORED);
+        Index::create_in_dir(&dir, builder.build()).unwrap();
+
+        let err = TextIndex::open_read_only(dir.to_str().unwrap()).err().unwrap();
+        assert!(matches!(err.downcast_ref::<SearchError>(), Some(SearchError::IndexIncompatible(_))));
+        assert!(Index::open_in_dir(&dir).is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keywords_do_not_drown_out_a_rare_identifier() {
+        let dir = temp_dir();
+        let path = dir.to_str().unwrap();
+        let index = TextIndex::load_or_create(path).unwrap();
+        let mut chunks: Vec<FileChunk> = (0..20)
+            .map(|i| chunk(&format!("src/m{}.rs", i), "pub fn get(&self) -> u32 {\n    let x = self.x;\n    if x > 0 { return self.y; }\n    return self.z;\n}"))
+            .collect();
+        chunks.push(chunk("src/retry.rs", "fn budget_left(state: &State) -> u32 {\n    state.retry_budget\n}"));
+        index.index_chunks(&chunks).unwrap();
+        index.save(path).unwrap();
+
+        let hits = index.search_chunks("return self retry_budget", 5, false);
+        assert_eq!(hits.len(), 1, "only the identifier matches");
+        assert_eq!(hits[0].file_path, "src/retry.rs");
+        assert!(index.search_chunks("return self", 5, false).is_empty());
+        drop(index);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn another_stopword_list_rebuilds_the_index() {
+        let defaults: Vec<String> = DEFAULT_STOPWORDS.iter().map(|w| w.to_string()).collect();
+        let custom = vec!["self".to_string()];
+        assert_ne!(tokenizer_name(&defaults), tokenizer_name(&custom));
+        assert_eq!(tokenizer_name(&defaults), tokenizer_name(&defaults.clone()));
+
+        // An index whose schema names another list's tokenizer
+        let dir = temp_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let other = tokenizer_name(&custom);
+        let old = Index::create_in_dir(&dir, text_schema(&other)).unwrap();
+        old.tokenizers().register(&other, code_analyzer(custom));
+        let mut writer: IndexWriter = old.writer(MIN_BUFFER_PER_THREAD).unwrap();
+        writer.add_document(doc!(old.schema().get_field("content").unwrap() => "let stale = 1;")).unwrap();
+        writer.commit().unwrap();
+        drop(writer);
+
+        let path = dir.to_str().unwrap();
+        let err = TextIndex::open_read_only(path).err().unwrap();
+        assert!(matches!(err.downcast_ref::<SearchError>(), Some(SearchError::IndexIncompatible(_))));
+        let index = TextIndex::load_or_create(path).unwrap();
+        assert_eq!(index.num_docs(), 0, "rebuilt empty, to be backfilled");
+        drop(index);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
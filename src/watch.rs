@@ -0,0 +1,173 @@
+use anyhow::Result;
+use crate::scanner::FileFilter;
+use crate::search::{SearchOptions, Searcher};
+use notify::{Watcher, RecursiveMode, EventKind};
+use notify::event::ModifyKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Re-indexes files under `root` as they change, until the watcher stops.
+/// `searcher` is created on the first event if the caller hasn't done so yet
+/// (the MCP server loads it lazily); the `watch` command passes a ready one.
+pub async fn run(searcher: Arc<Mutex<Option<Searcher>>>, root: PathBuf, options: SearchOptions) -> Result<()> {
+    // notify reports absolute paths; an absolute root keeps them relative to the repository
+    let root = std::fs::canonicalize(&root).unwrap_or(root);
+    let root_str = root.to_string_lossy().to_string();
+
+    // Channel for file events
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+    let watch_root = root.clone();
+    std::thread::spawn(move || {
+        let (wt_tx, wt_rx) = std::sync::mpsc::channel();
+        let watcher = notify::recommended_watcher(wt_tx);
+
+        match watcher {
+            Ok(mut w) => {
+                if let Err(e) = w.watch(&watch_root, RecursiveMode::Recursive) {
+                    eprintln!("Failed to start watcher: {}", e);
+                    return;
+                }
+
+                // Keep watcher alive
+                for res in wt_rx {
+                    match res {
+                        Ok(event) => {
+                            if tx.send(event).is_err() {
+                                break;
+                            }
+                        },
+                        Err(e) => eprintln!("Watch error: {:?}", e),
+                    }
+                }
+            },
+            Err(e) => eprintln!("Failed to create watcher: {}", e),
+        }
+    });
+
+    // Processor loop; serial, which is safer for the DB
+    let filter = options.file_filter();
+    while let Some(event) = rx.recv().await {
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+            continue;
+        }
+        // Renames report the old path, the new one, or both (depending on the platform),
+        // and may move a whole directory
+        let renamed = matches!(event.kind, EventKind::Modify(ModifyKind::Name(_)));
+        for path in event.paths {
+            // Same noise directories as the scan
+            if filter.in_skipped_dir(&path, &root) {
+                continue;
+            }
+
+            // Index changes made before the first search too
+            let mut searcher_guard = searcher.lock().await;
+            if searcher_guard.is_none() {
+                eprintln!("Initializing searcher for watch mode...");
+                match Searcher::new() {
+                    Ok(s) => *searcher_guard = Some(s),
+                    Err(e) => {
+                        eprintln!("Failed to init searcher: {}", e);
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(searcher) = searcher_guard.as_ref() {
+                // Our own writes to the index would otherwise be indexed in turn
+                if path.starts_with(searcher.index_path(&root)) {
+                    continue;
+                }
+                match change_for(&path, renamed) {
+                    Change::IndexDir => {
+                        for file in files_under(&path, &root, &filter) {
+                            let _ = searcher.index_file(&file, &root_str, &options).await;
+                        }
+                    }
+                    Change::Forget => {
+                        if let Err(e) = searcher.forget_path(&path, &root_str, &options).await {
+                            eprintln!("Failed to remove {:?} from the index: {:#}", path, e);
+                        }
+                    }
+                    Change::Index => {
+                        let _ = searcher.index_file(&path, &root_str, &options).await;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What the index needs for one path of a watch event.
+#[derive(Debug, PartialEq)]
+enum Change {
+    /// (Re-)index the file; `index_file` also drops a file that no longer exists
+    Index,
+    /// A directory was moved into place: index every file under it
+    IndexDir,
+    /// The path was renamed away: drop it, and anything indexed under it
+    Forget,
+}
+
+fn change_for(path: &Path, renamed: bool) -> Change {
+    if renamed && path.is_dir() {
+        Change::IndexDir
+    } else if renamed && !path.exists() {
+        Change::Forget
+    } else {
+        Change::Index
+    }
+}
+
+/// Indexable files under a directory that was moved into place, honoring ignore files like the scan.
+fn files_under(dir: &Path, root: &Path, filter: &FileFilter) -> Vec<PathBuf> {
+    ignore::WalkBuilder::new(dir)
+        .hidden(false)
+        .add_custom_ignore_filename(".codesearchignore")
+        .build()
+        .flatten()
+        .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+        .map(|entry| entry.into_path())
+        .filter(|path| !filter.in_skipped_dir(path, root) && filter.should_process_file(path))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn renames_forget_the_old_path_and_index_the_new_one() {
+        let root = std::env::temp_dir().join(format!("code-search-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("src/old.rs"), "fn moved() {}\n").unwrap();
+
+        // A file
+        std::fs::rename(root.join("src/old.rs"), root.join("src/new.rs")).unwrap();
+        assert_eq!(change_for(&root.join("src/old.rs"), true), Change::Forget);
+        assert_eq!(change_for(&root.join("src/new.rs"), true), Change::Index);
+
+        // A directory, whose new location is walked like the scan would
+        std::fs::create_dir_all(root.join("lib/target")).unwrap();
+        std::fs::write(root.join("lib/util.rs"), "fn util() {}\n").unwrap();
+        std::fs::write(root.join("lib/target/out.rs"), "fn generated() {}\n").unwrap();
+        std::fs::write(root.join("lib/notes.bin"), "\0").unwrap();
+        std::fs::rename(root.join("lib"), root.join("pkg")).unwrap();
+        assert_eq!(change_for(&root.join("lib"), true), Change::Forget);
+        assert_eq!(change_for(&root.join("pkg"), true), Change::IndexDir);
+        let filter = FileFilter {
+            extensions: HashSet::from(["rs".to_string()]),
+            index_generated: false,
+            skip_dirs: HashSet::from(["target".to_string()]),
+        };
+        assert_eq!(files_under(&root.join("pkg"), &root, &filter), vec![root.join("pkg/util.rs")]);
+
+        // Plain edits and removals go through `index_file`
+        assert_eq!(change_for(&root.join("src/new.rs"), false), Change::Index);
+        assert_eq!(change_for(&root.join("src/gone.rs"), false), Change::Index);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}